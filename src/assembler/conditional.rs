@@ -0,0 +1,226 @@
+use super::diagnostic::Error;
+use super::parser::{Operand, Statement};
+use super::{expect_operands, symbol_name, Assembler};
+
+// an .if, .ifdef or .ifndef block that is not closed yet
+struct Condition {
+    header: Statement,
+    // false inside a block that is being skipped, its own value does not matter then
+    enclosing: bool,
+    value: bool,
+    seen_else: bool,
+}
+
+#[derive(Default)]
+pub struct Conditions {
+    stack: Vec<Condition>,
+    // the values of the first pass, the layout depends on them
+    values: Vec<bool>,
+    index: usize,
+}
+
+impl Conditions {
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+    // starts a pass, the values of the first one are kept
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.index = 0;
+    }
+    fn active(&self) -> bool {
+        self.stack
+            .last()
+            .is_none_or(|condition| condition.enclosing && condition.value)
+    }
+}
+
+pub fn is_conditional(mnemonic: &str) -> bool {
+    matches!(mnemonic, ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif")
+}
+
+impl Assembler<'_> {
+    // false while the statement is inside a block whose condition does not hold
+    pub fn active(&self) -> bool {
+        self.conditions.active()
+    }
+    pub fn conditional(&mut self, name: &str, statement: &Statement) -> Result<(), Error> {
+        let operands = &statement.operands;
+        match name {
+            ".else" | ".endif" => {
+                expect_operands(name, operands, "")?;
+                let condition = self
+                    .conditions
+                    .stack
+                    .last_mut()
+                    .ok_or_else(|| Error::at(name, format!("{} without an .if", name)))?;
+                if name == ".endif" {
+                    self.conditions.stack.pop();
+                } else if condition.seen_else {
+                    return Err(Error::at(name, "Only one .else per .if".to_string()));
+                } else {
+                    condition.seen_else = true;
+                    condition.value = !condition.value;
+                }
+                Ok(())
+            }
+            _ => {
+                let enclosing = self.active();
+                let mut condition = Condition {
+                    header: statement.clone(),
+                    enclosing,
+                    value: false,
+                    seen_else: false,
+                };
+                // the block is pushed even when the condition fails, .endif still closes it
+                let value = if enclosing {
+                    self.evaluate_condition(name, operands)
+                } else {
+                    Ok(false)
+                };
+                condition.value = *value.as_ref().unwrap_or(&false);
+                self.conditions.stack.push(condition);
+                value.map(|_| ())
+            }
+        }
+    }
+    fn evaluate_condition(&mut self, name: &str, operands: &[Operand]) -> Result<bool, Error> {
+        let value = if name == ".if" {
+            expect_operands(name, operands, "expression")?;
+            self.absolute(&operands[0]).map(|value| value != 0)
+        } else {
+            expect_operands(name, operands, "symbol")?;
+            let symbol = symbol_name(&operands[0])?;
+            let defined = self.constants.contains_key(symbol) || self.defined.contains(symbol);
+            Ok(defined == (name == ".ifdef"))
+        };
+        // both passes have to see the same code, labels are only placed in the second
+        let index = self.conditions.index;
+        self.conditions.index += 1;
+        if !self.resolved {
+            let first = *value.as_ref().unwrap_or(&false);
+            self.conditions.values.push(first);
+            return Ok(first);
+        }
+        let first = self.conditions.values.get(index).copied().unwrap_or(false);
+        match value? {
+            value if value != first => Err(Error::at(
+                &operands[0].word.text,
+                format!(
+                    "Condition changes once labels are placed: {}",
+                    operands[0].word.text
+                ),
+            )),
+            _ => Ok(first),
+        }
+    }
+    // conditions left open at the end of a file or of the source
+    pub fn unclosed_conditions(&mut self, depth: usize) {
+        while self.conditions.stack.len() > depth {
+            let condition = self.conditions.stack.pop().unwrap();
+            let name = condition
+                .header
+                .mnemonic
+                .as_ref()
+                .map_or(String::new(), |mnemonic| mnemonic.text.clone());
+            let error = Error::at(&name, format!("Missing .endif for {}", name));
+            self.report(&condition.header, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{assemble, AssemblerOptions};
+
+    // the assembled bytes, or the line and message of every error
+    fn assemble_with(source: &str, defines: &[&str]) -> Result<Vec<u8>, Vec<(usize, String)>> {
+        let mut options = AssemblerOptions::new();
+        options.defines = defines.iter().map(|name| (name.to_string(), 1)).collect();
+        match assemble(source, "test.s", &options) {
+            Ok(assembly) => Ok(assembly.image),
+            Err(diagnostics) => Err(diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.line, diagnostic.message))
+                .collect()),
+        }
+    }
+
+    #[test]
+    fn picks_the_branch_whose_condition_holds() {
+        let source = ".equ SIZE, 4
+.if SIZE == 4
+    .byte 1
+.else
+    .byte 2
+.endif
+.if SIZE > 4
+    .byte 3
+    // a skipped block does not evaluate its own conditions
+    .if undefined
+    .endif
+.else
+    .byte 4
+.endif
+";
+        // the sections after .text pad the image to 4 bytes
+        assert_eq!(assemble_with(source, &[]), Ok(vec![1, 4, 0, 0]));
+    }
+
+    #[test]
+    fn ifdef_sees_defines_and_earlier_labels() {
+        let source = ".ifdef DEBUG
+    .byte 1
+.endif
+.ifndef DEBUG
+    .byte 2
+.endif
+.ifdef later
+    .byte 3
+.endif
+later:
+.ifdef later
+    .byte 4
+.endif
+";
+        assert_eq!(assemble_with(source, &["DEBUG"]), Ok(vec![1, 4, 0, 0]));
+        assert_eq!(assemble_with(source, &[]), Ok(vec![2, 4, 0, 0]));
+    }
+
+    #[test]
+    fn keeps_the_value_of_the_first_pass() {
+        // labels have no final address in the first pass, the block would appear late
+        let source = "start:
+    nop
+end:
+.if end - start == 4
+    nop
+.endif
+";
+        assert_eq!(
+            assemble_with(source, &[]),
+            Err(vec![(
+                4,
+                "Condition changes once labels are placed: end - start == 4".to_string()
+            )])
+        );
+    }
+
+    #[test]
+    fn reports_unbalanced_blocks() {
+        assert_eq!(
+            assemble_with(".else\n.endif\n", &[]),
+            Err(vec![
+                (1, ".else without an .if".to_string()),
+                (2, ".endif without an .if".to_string()),
+            ])
+        );
+        assert_eq!(
+            assemble_with(".if 1\n.else\n.else\n.endif\n.if 1\n", &[]),
+            Err(vec![
+                (3, "Only one .else per .if".to_string()),
+                (5, "Missing .endif for .if".to_string()),
+            ])
+        );
+    }
+}
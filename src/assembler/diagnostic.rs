@@ -0,0 +1,119 @@
+use std::fmt;
+
+use super::lexer::is_symbol_char;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// columns and lengths count characters, columns start at 1
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Span {
+    // index into the assembler's source files
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub message: String,
+    pub snippet: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let gutter = " ".repeat(self.line.to_string().len());
+        // keeps tabs so the caret lines up with the snippet
+        let indent = self
+            .snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        writeln!(f, "{}: {}", severity, self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            indent,
+            "^".repeat(self.length.max(1))
+        )
+    }
+}
+
+// a problem with one statement, the token is used to point at the column
+pub struct Error {
+    pub severity: Severity,
+    pub message: String,
+    pub token: Option<String>,
+}
+
+impl Error {
+    pub fn statement(message: String) -> Error {
+        Error {
+            severity: Severity::Error,
+            message,
+            token: None,
+        }
+    }
+    pub fn at(token: &str, message: String) -> Error {
+        Error {
+            severity: Severity::Error,
+            message,
+            token: Some(token.to_string()),
+        }
+    }
+    pub fn warning(token: &str, message: String) -> Error {
+        Error {
+            severity: Severity::Warning,
+            ..Error::at(token, message)
+        }
+    }
+}
+
+// the first occurrence of token that is not part of a longer symbol, counted in characters
+pub fn find_token(text: &str, token: &str) -> Option<usize> {
+    let before = |i: usize| text[..i].chars().next_back();
+    let after = |i: usize| text[i + token.len()..].chars().next();
+    text.match_indices(token)
+        .map(|(i, _)| i)
+        .find(|&i| !before(i).is_some_and(is_symbol_char) && !after(i).is_some_and(is_symbol_char))
+        .map(|i| text[..i].chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_whole_symbols_only() {
+        assert_eq!(find_token("a0+label", "label"), Some(3));
+        assert_eq!(find_token("labels+label", "label"), Some(7));
+        assert_eq!(find_token("_label", "label"), None);
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        assert_eq!(find_token("'é'+missing", "missing"), Some(4));
+        assert_eq!(find_token("\"→→\"+bad", "bad"), Some(5));
+    }
+}
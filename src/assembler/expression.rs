@@ -0,0 +1,349 @@
+use super::lexer::{parse_char, Token, TokenKind};
+use super::Assembler;
+
+#[derive(Clone, Copy)]
+pub struct Value {
+    pub number: i64,
+    // how many label addresses are summed in, 0 for constants and label differences
+    pub labels: i32,
+    // false while a symbol is not defined yet, in the first pass
+    pub known: bool,
+}
+
+impl Value {
+    pub fn constant(number: i64) -> Value {
+        Value {
+            number,
+            labels: 0,
+            known: true,
+        }
+    }
+}
+
+// an expression as parsed, symbols are only looked up when it is evaluated
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // %hi(x) and the like
+    Relocation(String, Box<Expr>),
+}
+
+// the two character operators come first so they are matched whole
+pub const OPERATORS: &[&str] = &[
+    "<<", ">>", "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "&", "|",
+    "^", "~", "!", "(", ")",
+];
+
+// operators from the loosest to the tightest binding, like C
+const PRECEDENCE: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+fn number(literal: &str) -> Result<Expr, String> {
+    // 1b and 1f refer to numeric labels
+    if literal.len() > 1
+        && literal.ends_with(['b', 'f'])
+        && literal[..literal.len() - 1]
+            .bytes()
+            .all(|byte| byte.is_ascii_digit())
+    {
+        return Ok(Expr::Symbol(literal.to_string()));
+    }
+    let lower = literal.to_ascii_lowercase();
+    let number = if let Some(digits) = lower.strip_prefix("0x") {
+        i64::from_str_radix(digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        i64::from_str_radix(digits, 2)
+    } else {
+        lower.parse::<i64>()
+    };
+    number
+        .map(Expr::Number)
+        .map_err(|_| format!("Invalid number: {}", literal))
+}
+
+fn character(literal: &str, text: &str) -> Result<Expr, String> {
+    let chars = literal.chars().collect::<Vec<char>>();
+    let mut position = 1;
+    match parse_char(&chars, &mut position) {
+        Some(c) if position + 1 == chars.len() && chars[position] == '\'' => {
+            Ok(Expr::Number(c as i64))
+        }
+        _ => Err(format!("Invalid character literal in: {}", text)),
+    }
+}
+
+struct ExpressionParser<'t> {
+    // the operand, for messages
+    text: &'t str,
+    tokens: &'t [Token],
+    position: usize,
+}
+
+impl ExpressionParser<'_> {
+    fn next_is(&self, operator: &str) -> bool {
+        matches!(self.tokens.get(self.position),
+            Some(token) if token.kind == TokenKind::Operator && token.text == operator)
+    }
+    fn expect(&mut self, operator: &str) -> Result<(), String> {
+        if !self.next_is(operator) {
+            return Err(format!(
+                "Expected '{}' in expression: {}",
+                operator, self.text
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&operator) = PRECEDENCE[level]
+            .iter()
+            .find(|operator| self.next_is(operator))
+        {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+    fn prefix(&mut self, operator: &'static str) -> Result<Expr, String> {
+        Ok(Expr::Unary(operator, Box::new(self.unary()?)))
+    }
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .ok_or_else(|| format!("Unexpected end of expression: {}", self.text))?;
+        self.position += 1;
+        match (token.kind, token.text.as_str()) {
+            (TokenKind::Number, literal) => number(literal),
+            (TokenKind::Char, literal) => character(literal, self.text),
+            (TokenKind::Identifier, name) => Ok(Expr::Symbol(name.to_string())),
+            (TokenKind::Operator, "(") => {
+                let expression = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            (TokenKind::Operator, "+") => self.unary(),
+            (TokenKind::Operator, "-") => self.prefix("-"),
+            (TokenKind::Operator, "~") => self.prefix("~"),
+            (TokenKind::Operator, "!") => self.prefix("!"),
+            // a relocation name follows the % directly, like %hi(x)
+            (TokenKind::Operator, "%")
+                if self
+                    .tokens
+                    .get(self.position)
+                    .is_some_and(|name| name.kind == TokenKind::Identifier && !name.spaced) =>
+            {
+                let name = self.tokens[self.position].text.clone();
+                self.position += 1;
+                self.expect("(")?;
+                let expression = self.binary(0)?;
+                self.expect(")")?;
+                Ok(Expr::Relocation(name, Box::new(expression)))
+            }
+            (_, unexpected) => Err(format!(
+                "Unexpected '{}' in expression: {}",
+                unexpected, self.text
+            )),
+        }
+    }
+}
+
+// reads the tokens of an operand, text is the operand as written for messages
+pub fn parse(tokens: &[Token], text: &str) -> Result<Expr, String> {
+    let mut parser = ExpressionParser {
+        text,
+        tokens,
+        position: 0,
+    };
+    let expression = parser.binary(0)?;
+    match parser.tokens.get(parser.position) {
+        Some(_) => Err(format!("Unexpected text after expression: {}", text)),
+        None => Ok(expression),
+    }
+}
+
+fn combine(operator: &str, left: Value, right: Value, text: &str) -> Result<Value, String> {
+    let known = left.known && right.known;
+    let (number, labels) = match operator {
+        "+" => (
+            left.number.wrapping_add(right.number),
+            left.labels + right.labels,
+        ),
+        "-" => (
+            left.number.wrapping_sub(right.number),
+            left.labels - right.labels,
+        ),
+        // symbols that are not defined yet read as 0 in the first pass
+        _ if !known => (0, 0),
+        _ if left.labels != 0 || right.labels != 0 => {
+            return Err(format!(
+                "Only + and - can be used with label addresses in expression: {}",
+                text
+            ))
+        }
+        "/" | "%" if right.number == 0 => {
+            return Err(format!("Division by zero in expression: {}", text))
+        }
+        "/" => (left.number.wrapping_div(right.number), 0),
+        "%" => (left.number.wrapping_rem(right.number), 0),
+        "*" => (left.number.wrapping_mul(right.number), 0),
+        "<<" => (left.number.wrapping_shl(right.number as u32), 0),
+        ">>" => (left.number.wrapping_shr(right.number as u32), 0),
+        "&" => (left.number & right.number, 0),
+        "|" => (left.number | right.number, 0),
+        // comparisons and logical operators give 1 or 0, like C
+        "==" => ((left.number == right.number) as i64, 0),
+        "!=" => ((left.number != right.number) as i64, 0),
+        "<" => ((left.number < right.number) as i64, 0),
+        "<=" => ((left.number <= right.number) as i64, 0),
+        ">" => ((left.number > right.number) as i64, 0),
+        ">=" => ((left.number >= right.number) as i64, 0),
+        "&&" => ((left.number != 0 && right.number != 0) as i64, 0),
+        "||" => ((left.number != 0 || right.number != 0) as i64, 0),
+        _ => (left.number ^ right.number, 0),
+    };
+    Ok(Value {
+        number,
+        labels,
+        known,
+    })
+}
+
+// evaluates a parsed operand, symbols are looked up in the assembler
+pub fn evaluate(assembler: &Assembler, expression: &Expr, text: &str) -> Result<Value, String> {
+    match expression {
+        Expr::Number(number) => Ok(Value::constant(*number)),
+        Expr::Symbol(name) => assembler.symbol(name),
+        Expr::Unary(operator, operand) => {
+            let value = evaluate(assembler, operand, text)?;
+            let number = match *operator {
+                "-" => {
+                    return Ok(Value {
+                        number: value.number.wrapping_neg(),
+                        labels: -value.labels,
+                        ..value
+                    })
+                }
+                _ if value.labels != 0 => {
+                    return Err(format!(
+                        "Only + and - can be used with label addresses in expression: {}",
+                        text
+                    ))
+                }
+                "~" => !value.number,
+                _ => (value.number == 0) as i64,
+            };
+            Ok(Value { number, ..value })
+        }
+        Expr::Binary(operator, left, right) => {
+            let left = evaluate(assembler, left, text)?;
+            let right = evaluate(assembler, right, text)?;
+            combine(operator, left, right, text)
+        }
+        Expr::Relocation(name, operand) => {
+            let value = evaluate(assembler, operand, text)?;
+            assembler.relocation(name, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::lex;
+    use super::super::{AssemblerOptions, Source};
+    use super::*;
+    use std::path::PathBuf;
+
+    // evaluates text once source is assembled, with its labels and constants defined
+    fn evaluate_in(source: &str, text: &str) -> Result<(i64, i32), String> {
+        let options = AssemblerOptions::new();
+        let source = Source::new(PathBuf::from("test.s"), source, 0);
+        let mut assembler = Assembler::new(source, &options);
+        assembler.pass();
+        assembler.layout();
+        assembler.pass();
+        let (tokens, _) = lex(text, 0);
+        let expression = parse(&tokens, text)?;
+        evaluate(&assembler, &expression, text).map(|value| (value.number, value.labels))
+    }
+
+    fn constant(text: &str) -> Result<i64, String> {
+        evaluate_in("", text).map(|(number, _)| number)
+    }
+
+    #[test]
+    fn follows_c_precedence() {
+        assert_eq!(constant("1+2*3"), Ok(7));
+        assert_eq!(constant("(1+2)*3"), Ok(9));
+        assert_eq!(constant("1<<2+1"), Ok(8));
+        assert_eq!(constant("1|2&3"), Ok(3));
+        assert_eq!(constant("-2*-3"), Ok(6));
+        assert_eq!(constant("1+2==3&&4>3"), Ok(1));
+        assert_eq!(constant("~0x0f&0xff"), Ok(0xf0));
+    }
+
+    #[test]
+    fn label_differences_are_constants() {
+        let source = "start:\n    nop\n    nop\nend:\n";
+        assert_eq!(evaluate_in(source, "end-start"), Ok((8, 0)));
+        assert_eq!(evaluate_in(source, "end+4"), Ok((12, 1)));
+        assert_eq!(evaluate_in(source, "(end-start)/4"), Ok((2, 0)));
+    }
+
+    #[test]
+    fn rejects_arithmetic_on_label_addresses() {
+        let source = "start:\n    nop\n";
+        for text in ["start*2", "start/2", "start<<1", "~start"] {
+            let message = evaluate_in(source, text).unwrap_err();
+            assert!(message.starts_with("Only + and - can be used"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_division_by_zero() {
+        for text in ["1/0", "1%0", "4/(2-2)"] {
+            assert_eq!(
+                constant(text),
+                Err(format!("Division by zero in expression: {}", text))
+            );
+        }
+    }
+
+    #[test]
+    fn hi_rounds_up_when_lo_is_negative() {
+        assert_eq!(constant("%hi(0x12345800)"), Ok(0x12346));
+        assert_eq!(constant("%lo(0x12345800)"), Ok(-0x800));
+        assert_eq!(constant("%hi(0x123457ff)"), Ok(0x12345));
+        assert_eq!(constant("%lo(0x123457ff)"), Ok(0x7ff));
+        assert_eq!(
+            constant("(%hi(0x12345800)<<12)+%lo(0x12345800)"),
+            Ok(0x12345800)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert!(constant("1+").is_err());
+        assert!(constant("(1").is_err());
+        assert!(constant("1 2").is_err());
+        assert!(constant("undefined").is_err());
+    }
+}
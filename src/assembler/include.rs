@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::diagnostic::{Error, Severity, Span};
+use super::lexer;
+use super::parser::{self, Operand, Statement};
+use super::{expect_operands, string, Assembler};
+
+// an include that includes itself would otherwise never finish
+const MAX_DEPTH: usize = 64;
+
+// a file read by the assembler, index 0 is the one given on the command line
+pub struct Source {
+    pub path: PathBuf,
+    pub lines: Vec<String>,
+    pub statements: Vec<Statement>,
+    // lex and parse errors, reported wherever the file is assembled
+    pub errors: Vec<(Span, String)>,
+}
+
+impl Source {
+    pub fn new(path: PathBuf, text: &str, index: usize) -> Source {
+        let (tokens, lex_errors) = lexer::lex(text, index);
+        let (statements, parse_errors) = parser::parse(&tokens);
+        Source {
+            path,
+            lines: text.lines().map(str::to_string).collect(),
+            statements,
+            errors: lex_errors.into_iter().chain(parse_errors).collect(),
+        }
+    }
+}
+
+fn file_name(operand: &Operand) -> Result<String, Error> {
+    Ok(String::from_utf8_lossy(string(operand)?).into_owned())
+}
+
+impl Assembler<'_> {
+    // relative names are looked up next to the including file, then in the include paths
+    fn resolve(&self, name: &str, file: usize) -> Result<PathBuf, Error> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return Ok(path.to_path_buf());
+        }
+        let directory = self.sources[file]
+            .path
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        std::iter::once(directory)
+            .chain(self.options.include_paths.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(path))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| Error::statement(format!("Could not find file: {}", name)))
+    }
+    // reads and parses a file the first time it is included
+    fn load(&mut self, path: PathBuf) -> Result<usize, Error> {
+        if let Some(index) = self.sources.iter().position(|source| source.path == path) {
+            return Ok(index);
+        }
+        let text = fs::read_to_string(&path).map_err(|error| {
+            Error::statement(format!("Could not read {}: {}", path.display(), error))
+        })?;
+        let index = self.sources.len();
+        self.sources.push(Source::new(path, &text, index));
+        Ok(index)
+    }
+    // .include "file" and .incbin "file"[, skip[, count]]
+    pub fn include(&mut self, name: &str, operands: &[Operand], file: usize) -> Result<(), Error> {
+        if name == ".incbin" {
+            let (operand, skip, count) = match operands {
+                [operand] => (operand, None, None),
+                [operand, skip] => (operand, Some(skip), None),
+                [operand, skip, count] => (operand, Some(skip), Some(count)),
+                _ => {
+                    return Err(Error::statement(
+                        ".incbin needs a file and an optional skip and count".to_string(),
+                    ))
+                }
+            };
+            let path = self.resolve(&file_name(operand)?, file)?;
+            let bytes = fs::read(&path).map_err(|error| {
+                Error::statement(format!("Could not read {}: {}", path.display(), error))
+            })?;
+            let skip = match skip {
+                Some(skip) => self.absolute(skip)? as usize,
+                None => 0,
+            };
+            let count = match count {
+                Some(count) => self.absolute(count)? as usize,
+                None => bytes.len().saturating_sub(skip),
+            };
+            let bytes = bytes.get(skip..skip + count).ok_or_else(|| {
+                Error::statement(format!(
+                    "{} has {} bytes, cannot take {} after skipping {}",
+                    path.display(),
+                    bytes.len(),
+                    count,
+                    skip
+                ))
+            })?;
+            return self.emit(bytes);
+        }
+        expect_operands(name, operands, "\"file\"")?;
+        if self.include_depth >= MAX_DEPTH {
+            return Err(Error::statement(format!(
+                "Includes are nested more than {} deep",
+                MAX_DEPTH
+            )));
+        }
+        let path = self.resolve(&file_name(&operands[0])?, file)?;
+        let index = self.load(path)?;
+        let errors = self.sources[index]
+            .errors
+            .iter()
+            .map(|(span, message)| self.diagnostic(Severity::Error, *span, message.clone()))
+            .collect::<Vec<_>>();
+        self.diagnostics.extend(errors);
+        let statements = self.sources[index].statements.clone();
+        let conditions = self.conditions.depth();
+        self.include_depth += 1;
+        for statement in &statements {
+            self.process(statement);
+        }
+        self.include_depth -= 1;
+        // blocks do not continue past the end of the file they are opened in
+        self.unclosed_block();
+        self.unclosed_conditions(conditions);
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use super::diagnostic::Span;
+use super::expression::OPERATORS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    Identifier,
+    Number,
+    String,
+    Char,
+    Operator,
+    Comma,
+    Colon,
+    // a newline or ;
+    Separator,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    // literals keep their quotes and escapes
+    pub text: String,
+    pub span: Span,
+    // whitespace or a comment comes before it
+    pub spaced: bool,
+}
+
+// reads one character of a literal, handling the escapes strings accept too
+pub fn parse_char(chars: &[char], position: &mut usize) -> Option<char> {
+    let c = *chars.get(*position)?;
+    *position += 1;
+    if c != '\\' {
+        return Some(c);
+    }
+    let escaped = *chars.get(*position)?;
+    *position += 1;
+    match escaped {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(escaped),
+        _ => None,
+    }
+}
+
+// the bytes of a string literal, None when it has an escape that is not understood
+pub fn parse_string(literal: &str) -> Option<Vec<u8>> {
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let chars = inner.chars().collect::<Vec<char>>();
+    let mut bytes = Vec::new();
+    let mut position = 0;
+    while position < chars.len() {
+        let c = parse_char(&chars, &mut position)?;
+        let mut buffer = [0u8; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Some(bytes)
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+// @ starts section types like @progbits, \ starts macro arguments
+fn is_symbol_start(c: char) -> bool {
+    (is_symbol_char(c) && !c.is_ascii_digit()) || c == '@' || c == '\\'
+}
+
+struct Lexer {
+    file: usize,
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+    column: usize,
+    tokens: Vec<Token>,
+    errors: Vec<(Span, String)>,
+}
+
+impl Lexer {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    fn take_while(&mut self, condition: fn(char) -> bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek(0).filter(|&c| condition(c)) {
+            text.push(c);
+            self.advance();
+        }
+        text
+    }
+    // macro bodies use \name for arguments, \@ for the expansion count and \() to end a name
+    fn identifier(&mut self) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek(0) {
+            if c == '\\' {
+                text.extend(self.advance());
+                match (self.peek(0), self.peek(1)) {
+                    (Some('@'), _) => text.extend(self.advance()),
+                    (Some('('), Some(')')) => {
+                        text.extend(self.advance());
+                        text.extend(self.advance());
+                    }
+                    _ => {}
+                }
+            } else if is_symbol_char(c) || (text.is_empty() && c == '@') {
+                text.extend(self.advance());
+            } else {
+                break;
+            }
+        }
+        text
+    }
+    fn span(&self, start: Span) -> Span {
+        Span {
+            length: self.column - start.column,
+            ..start
+        }
+    }
+    fn skip_line_comment(&mut self) {
+        while self.peek(0).is_some_and(|c| c != '\n') {
+            self.advance();
+        }
+    }
+    fn skip_block_comment(&mut self, start: Span) {
+        self.advance();
+        self.advance();
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (Some('*'), Some('/')) => {
+                    self.advance();
+                    self.advance();
+                    return;
+                }
+                (Some(_), _) => {
+                    self.advance();
+                }
+                (None, _) => {
+                    let span = Span { length: 2, ..start };
+                    self.errors
+                        .push((span, "Unterminated block comment".to_string()));
+                    return;
+                }
+            }
+        }
+    }
+    // returns the literal with its quotes, or None when it is not closed on the line
+    fn quoted(&mut self, quote: char) -> Option<String> {
+        let mut text = String::new();
+        text.extend(self.advance());
+        loop {
+            match self.peek(0) {
+                None | Some('\n') => return None,
+                Some('\\') => {
+                    text.extend(self.advance());
+                    if self.peek(0).is_some_and(|c| c != '\n') {
+                        text.extend(self.advance());
+                    }
+                }
+                Some(c) => {
+                    text.extend(self.advance());
+                    if c == quote {
+                        return Some(text);
+                    }
+                }
+            }
+        }
+    }
+    fn operator(&self) -> Option<&'static str> {
+        let c = self.peek(0)?;
+        let next = self.peek(1).unwrap_or(' ');
+        OPERATORS.iter().copied().find(|operator| {
+            operator.starts_with(c) && (operator.len() == 1 || operator.ends_with(next))
+        })
+    }
+    fn run(&mut self) {
+        let mut spaced = false;
+        while let Some(c) = self.peek(0) {
+            let start = Span {
+                file: self.file,
+                line: self.line,
+                column: self.column,
+                length: 0,
+            };
+            let (kind, text) = match c {
+                '#' => {
+                    self.skip_line_comment();
+                    spaced = true;
+                    continue;
+                }
+                '/' if self.peek(1) == Some('/') => {
+                    self.skip_line_comment();
+                    spaced = true;
+                    continue;
+                }
+                '/' if self.peek(1) == Some('*') => {
+                    self.skip_block_comment(start);
+                    spaced = true;
+                    continue;
+                }
+                '\n' | ';' => {
+                    self.advance();
+                    (TokenKind::Separator, c.to_string())
+                }
+                c if c.is_whitespace() => {
+                    self.advance();
+                    spaced = true;
+                    continue;
+                }
+                '"' | '\'' => {
+                    let kind = if c == '"' {
+                        TokenKind::String
+                    } else {
+                        TokenKind::Char
+                    };
+                    match self.quoted(c) {
+                        Some(text) => (kind, text),
+                        None => {
+                            let message = if c == '"' {
+                                "Unterminated string"
+                            } else {
+                                "Unterminated character literal"
+                            };
+                            self.errors.push((self.span(start), message.to_string()));
+                            continue;
+                        }
+                    }
+                }
+                c if c.is_ascii_digit() => (
+                    TokenKind::Number,
+                    self.take_while(|c| c.is_ascii_alphanumeric()),
+                ),
+                c if is_symbol_start(c) => (TokenKind::Identifier, self.identifier()),
+                ',' | ':' => {
+                    self.advance();
+                    let kind = if c == ',' {
+                        TokenKind::Comma
+                    } else {
+                        TokenKind::Colon
+                    };
+                    (kind, c.to_string())
+                }
+                // only used for macro parameter defaults, == is a comparison
+                '=' if self.peek(1) != Some('=') => {
+                    self.advance();
+                    (TokenKind::Operator, c.to_string())
+                }
+                _ => match self.operator() {
+                    Some(operator) => {
+                        operator.chars().for_each(|_| {
+                            self.advance();
+                        });
+                        (TokenKind::Operator, operator.to_string())
+                    }
+                    None => {
+                        self.advance();
+                        self.errors
+                            .push((self.span(start), format!("Unexpected character '{}'", c)));
+                        continue;
+                    }
+                },
+            };
+            // a separator token spans the newline, which has no width on its line
+            let span = if kind == TokenKind::Separator {
+                Span { length: 1, ..start }
+            } else {
+                self.span(start)
+            };
+            self.tokens.push(Token {
+                kind,
+                text,
+                span,
+                spaced,
+            });
+            spaced = false;
+        }
+    }
+}
+
+// splits source into tokens, unreadable characters are reported and skipped
+pub fn lex(source: &str, file: usize) -> (Vec<Token>, Vec<(Span, String)>) {
+    let mut lexer = Lexer {
+        file,
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+        column: 1,
+        tokens: Vec::new(),
+        errors: Vec::new(),
+    };
+    lexer.run();
+    (lexer.tokens, lexer.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<(TokenKind, String)> {
+        let (tokens, errors) = lex(source, 0);
+        assert!(errors.is_empty());
+        tokens
+            .into_iter()
+            .map(|token| (token.kind, token.text))
+            .collect()
+    }
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokens(source).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn splits_statements_at_newlines_and_semicolons() {
+        use self::TokenKind::*;
+        assert_eq!(
+            kinds("nop; li a0, 1\nret"),
+            [Identifier, Separator, Identifier, Identifier, Comma, Number, Separator, Identifier]
+        );
+    }
+
+    #[test]
+    fn keeps_strings_and_characters_whole() {
+        assert_eq!(
+            tokens(r#".ascii "a;b # c, \"d\"" , ';'"#),
+            [
+                (TokenKind::Identifier, ".ascii".to_string()),
+                (TokenKind::String, r#""a;b # c, \"d\"""#.to_string()),
+                (TokenKind::Comma, ",".to_string()),
+                (TokenKind::Char, "';'".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments() {
+        use self::TokenKind::*;
+        assert_eq!(
+            kinds("nop # a; b\nnop // c"),
+            [Identifier, Separator, Identifier]
+        );
+        // a block comment over several lines is not a separator
+        let (tokens, _) = lex("addi /* x\ny */ a0, a0, 1\nnop", 0);
+        assert_eq!(tokens[1].text, "a0");
+        assert!(tokens[1].spaced);
+        assert_eq!((tokens[1].span.line, tokens[1].span.column), (2, 6));
+        assert_eq!(tokens[7].span.line, 3);
+    }
+
+    #[test]
+    fn reports_unterminated_literals_and_comments() {
+        let messages = |source: &str| {
+            lex(source, 0)
+                .1
+                .into_iter()
+                .map(|(_, message)| message)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(messages(".ascii \"abc\nnop"), ["Unterminated string"]);
+        assert_eq!(messages("li a0, 'a"), ["Unterminated character literal"]);
+        assert_eq!(
+            messages("nop /* never closed"),
+            ["Unterminated block comment"]
+        );
+    }
+}
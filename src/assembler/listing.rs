@@ -0,0 +1,258 @@
+use std::fmt::Write;
+
+use super::parser::{Operand, Statement};
+use super::{is_pseudo, texts, Assembler, Section, KEYWORDS, SECTIONS};
+
+// data past this many rows is summed up in one line
+const MAX_DATA_ROWS: usize = 8;
+
+struct Row {
+    file: usize,
+    line: usize,
+    // the row comes from a macro body or is an instruction a pseudo-instruction expands to
+    expanded: bool,
+    address: u32,
+    code: String,
+    text: String,
+}
+
+#[derive(Default)]
+pub struct Listing {
+    rows: Vec<Row>,
+    // instructions emitted by the statement being listed, with their addresses
+    instructions: Vec<(u32, u32, String)>,
+}
+
+impl Listing {
+    // where the rows of the next statement start
+    pub fn position(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+fn section_name(section: Section) -> &'static str {
+    match section {
+        Section::Text => ".text",
+        Section::Rodata => ".rodata",
+        Section::Data => ".data",
+        Section::Bss => ".bss",
+    }
+}
+
+impl Assembler<'_> {
+    fn listing_enabled(&self) -> bool {
+        self.options.listing && self.resolved
+    }
+    pub fn list_instruction(&mut self, opcode: usize, operands: &[Operand], word: u32) {
+        if self.listing_enabled() {
+            let text = format!("{} {}", KEYWORDS[opcode], texts(operands));
+            let address = self.address();
+            self.listing
+                .instructions
+                .push((address, word, text.trim_end().to_string()));
+        }
+    }
+    // adds the rows of a statement that was just assembled, start is where its bytes begin
+    pub fn list(&mut self, statement: &Statement, start: (Section, usize, u32), rows: usize) {
+        if !self.listing_enabled() {
+            return;
+        }
+        let instructions = std::mem::take(&mut self.listing.instructions);
+        // statements from a macro body are listed at the line that expanded it
+        let (site, expanded) = match &self.macros.site {
+            Some((site, _)) => (site.span, true),
+            None => (statement.span, false),
+        };
+        let row = |address: u32, code: String, text: String, expanded: bool| Row {
+            file: site.file,
+            line: site.line,
+            expanded,
+            address,
+            code,
+            text,
+        };
+        let text = statement.text();
+        let (section, offset, address) = start;
+        // a macro invocation or an include, the statements it stands for are listed already
+        if self.listing.rows.len() > rows {
+            let header = row(address, String::new(), text, expanded);
+            self.listing.rows.insert(rows, header);
+            return;
+        }
+        let mnemonic = statement
+            .mnemonic
+            .as_ref()
+            .map_or("", |mnemonic| mnemonic.text.as_str());
+        if !instructions.is_empty() && !is_pseudo(mnemonic, &statement.operands) {
+            let (address, word, _) = instructions[0];
+            let code = format!("{:08x}", word);
+            self.listing.rows.push(row(address, code, text, expanded));
+            return;
+        }
+        if !instructions.is_empty() {
+            self.listing
+                .rows
+                .push(row(address, String::new(), text, expanded));
+            for (address, word, text) in instructions {
+                let code = format!("{:08x}", word);
+                self.listing.rows.push(row(address, code, text, true));
+            }
+            return;
+        }
+        let bytes = match self.sections[section as usize].get(offset..) {
+            Some(bytes) if section == self.section && !bytes.is_empty() => bytes.to_vec(),
+            // nothing was emitted, a label or a directive like .text
+            _ => {
+                let address = self.address();
+                self.listing
+                    .rows
+                    .push(row(address, String::new(), text, expanded));
+                return;
+            }
+        };
+        let chunks = bytes.chunks(4).collect::<Vec<&[u8]>>();
+        for (index, chunk) in chunks.iter().enumerate().take(MAX_DATA_ROWS) {
+            let code = chunk
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let text = if index == 0 {
+                text.clone()
+            } else {
+                String::new()
+            };
+            let address = address + 4 * index as u32;
+            self.listing.rows.push(row(address, code, text, expanded));
+        }
+        if chunks.len() > MAX_DATA_ROWS {
+            let skipped = bytes.len() - 4 * MAX_DATA_ROWS;
+            let address = address + 4 * MAX_DATA_ROWS as u32;
+            let text = format!("... {} more bytes", skipped);
+            self.listing
+                .rows
+                .push(row(address, String::new(), text, expanded));
+        }
+    }
+    // every listed statement with its address and code, then the symbol table
+    pub fn listing(&self) -> String {
+        let mut text = String::new();
+        let mut file = None;
+        for row in &self.listing.rows {
+            if file != Some(row.file) {
+                file = Some(row.file);
+                let path = self.sources[row.file].path.display();
+                let _ = writeln!(text, "{}:", path);
+            }
+            // continued data rows have no text and no line number
+            let line = if row.text.is_empty() {
+                String::new()
+            } else {
+                row.line.to_string()
+            };
+            let marker = if row.expanded { '+' } else { ' ' };
+            let columns = format!(
+                "{:>5}{} {:08x}  {:<11}  {}",
+                line, marker, row.address, row.code, row.text
+            );
+            let _ = writeln!(text, "{}", columns.trim_end());
+        }
+
+        let mut symbols = self
+            .labels
+            .iter()
+            // numeric labels are only known by their number and direction
+            .filter(|(name, _)| !name.contains('\u{2}'))
+            .map(|(name, &(section, offset))| {
+                let address = self.bases[section as usize] + offset;
+                (address, section, name)
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by(|a, b| (a.0, a.2).cmp(&(b.0, b.2)));
+        let _ = writeln!(text, "\nSymbols:");
+        for section in SECTIONS {
+            for (address, _, name) in symbols.iter().filter(|symbol| symbol.1 == section) {
+                let _ = writeln!(
+                    text,
+                    "  {:08x}  {:<8} {}",
+                    address,
+                    section_name(section),
+                    name
+                );
+            }
+        }
+        let mut constants = self.constants.iter().collect::<Vec<_>>();
+        constants.sort_by_key(|(name, _)| name.as_str());
+        if !constants.is_empty() {
+            let _ = writeln!(text, "\nConstants:");
+            for (name, value) in constants {
+                let _ = writeln!(text, "  {:08x}  {}", value.number as u32, name);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{assemble, AssemblerOptions};
+
+    #[test]
+    fn lists_expansions_data_and_symbols() {
+        let source = ".equ COUNT, 3
+.macro twice value
+    .byte \\value, \\value
+.endm
+_start:
+    li a0, COUNT
+    la a1, table
+    add a0, a0, a1
+1:  j 1b
+.data
+table:
+    twice 7
+    .space 40
+";
+        let mut options = AssemblerOptions::new();
+        options.listing = true;
+        let listing = match assemble(source, "test.s", &options) {
+            Ok(assembly) => assembly.listing.unwrap(),
+            Err(diagnostics) => panic!("{}", diagnostics[0]),
+        };
+        assert_eq!(
+            listing,
+            "test.s:
+    1  00000000               .equ COUNT, 3
+    5  00000000               _start:
+    6  00000000               li a0, COUNT
+    6+ 00000000  00300513     addi a0, zero, 3
+    7  00000004               la a1, table
+    7+ 00000004  00000597     auipc a1, 0
+    7+ 00000008  01058593     addi a1, a1, 16
+    8  0000000c  00b50533     add a0, a0, a1
+    9  00000010               1: j 1b
+    9+ 00000010  0000006f     jal zero, 1b
+   10  00000014               .data
+   11  00000014               table:
+   12  00000014               twice 7
+   12+ 00000014  07 07        .byte 7, 7
+   13  00000016  00 00 00 00  .space 40
+       0000001a  00 00 00 00
+       0000001e  00 00 00 00
+       00000022  00 00 00 00
+       00000026  00 00 00 00
+       0000002a  00 00 00 00
+       0000002e  00 00 00 00
+       00000032  00 00 00 00
+   13  00000036               ... 8 more bytes
+
+Symbols:
+  00000000  .text    _start
+  00000014  .data    table
+
+Constants:
+  00000003  COUNT
+"
+        );
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+
+use super::diagnostic::Error;
+use super::lexer::{self, is_symbol_char, Token, TokenKind};
+use super::parser::{self, text_of, Operand, Statement};
+use super::{symbol_name, Assembler};
+
+// deeper nesting is taken to be a macro that expands itself forever
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone)]
+struct Parameter {
+    name: String,
+    default: Option<Vec<Token>>,
+    required: bool,
+    // takes the remaining arguments, comma separated
+    vararg: bool,
+}
+
+#[derive(Clone)]
+struct Macro {
+    parameters: Vec<Parameter>,
+    // the tokens of each statement, arguments are substituted into them
+    body: Vec<Vec<Token>>,
+}
+
+// a .macro, .rept, .irp or .irpc block whose body is being collected
+struct Recording {
+    header: Statement,
+    body: Vec<Vec<Token>>,
+    // blocks opened inside the body that are not closed yet
+    depth: usize,
+}
+
+// each parameter with the tokens of the operand given for it
+type Arguments = [(String, Vec<Token>)];
+
+#[derive(Default)]
+pub struct Macros {
+    definitions: HashMap<String, Macro>,
+    recording: Option<Recording>,
+    // expansions so far, \@ in a body is replaced by it
+    count: usize,
+    depth: usize,
+    // the outermost statement being expanded and the name of what it expands
+    pub site: Option<(Statement, String)>,
+}
+
+impl Macros {
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+}
+
+fn mnemonic(statement: &Statement) -> &str {
+    statement
+        .mnemonic
+        .as_ref()
+        .map_or("", |mnemonic| mnemonic.text.as_str())
+}
+
+fn is_block_start(mnemonic: &str) -> bool {
+    matches!(mnemonic, ".macro" | ".rept" | ".irp" | ".irpc")
+}
+
+fn is_block_end(mnemonic: &str) -> bool {
+    matches!(mnemonic, ".endm" | ".endr")
+}
+
+fn is_token(token: Option<&Token>, kind: TokenKind, text: &str) -> bool {
+    token.is_some_and(|token| token.kind == kind && token.text == text)
+}
+
+// .macro name a, b=1, c:req, d:vararg, parameters can also be separated by spaces
+fn parse_macro(operands: &[Operand]) -> Result<(String, Macro), Error> {
+    let (name, first) = match operands
+        .first()
+        .map(|operand| operand.word.tokens.as_slice())
+    {
+        Some([name, rest @ ..]) if name.kind == TokenKind::Identifier => (name, rest),
+        _ => return Err(Error::statement(".macro needs a name".to_string())),
+    };
+    let lists = std::iter::once(first).chain(
+        operands[1..]
+            .iter()
+            .map(|operand| operand.word.tokens.as_slice()),
+    );
+    let mut parameters = Vec::<Parameter>::new();
+    for tokens in lists {
+        let mut position = 0;
+        while let Some(token) = tokens.get(position) {
+            let parameter = token.text.as_str();
+            if token.kind != TokenKind::Identifier {
+                return Err(Error::at(
+                    parameter,
+                    format!("Invalid macro parameter: {}", parameter),
+                ));
+            }
+            position += 1;
+            let mut qualifier = "";
+            if is_token(tokens.get(position), TokenKind::Colon, ":") {
+                qualifier = tokens
+                    .get(position + 1)
+                    .map_or("", |token| token.text.as_str());
+                position += 2;
+            }
+            let mut default = None;
+            if is_token(tokens.get(position), TokenKind::Operator, "=") {
+                // the default runs up to the next space, b = 1 c has the default 1
+                let start = position + 1;
+                position = start + 1;
+                while tokens.get(position).is_some_and(|token| !token.spaced) {
+                    position += 1;
+                }
+                position = position.min(tokens.len());
+                default = Some(tokens[start.min(position)..position].to_vec());
+            }
+            if !matches!(qualifier, "" | "req" | "vararg") {
+                return Err(Error::at(
+                    qualifier,
+                    format!("Unknown macro parameter qualifier: {}", qualifier),
+                ));
+            }
+            if parameters.iter().any(|other| other.name == parameter) {
+                return Err(Error::at(
+                    parameter,
+                    format!("Duplicate macro parameter: {}", parameter),
+                ));
+            }
+            parameters.push(Parameter {
+                name: parameter.to_string(),
+                default,
+                required: qualifier == "req",
+                vararg: qualifier == "vararg",
+            });
+        }
+    }
+    Ok((
+        name.text.clone(),
+        Macro {
+            parameters,
+            body: Vec::new(),
+        },
+    ))
+}
+
+// replaces \name inside a name or literal, \@ with the expansion count and drops \()
+fn substitute_text(text: &str, arguments: &Arguments, count: usize) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut substituted = String::new();
+    let mut position = 0;
+    while position < chars.len() {
+        let c = chars[position];
+        position += 1;
+        if c != '\\' {
+            substituted.push(c);
+            continue;
+        }
+        match (chars.get(position), chars.get(position + 1)) {
+            (Some('@'), _) => {
+                substituted.push_str(&count.to_string());
+                position += 1;
+            }
+            (Some('('), Some(')')) => position += 2,
+            _ => {
+                let start = position;
+                while chars.get(position).is_some_and(|&c| is_symbol_char(c)) {
+                    position += 1;
+                }
+                let name = chars[start..position].iter().collect::<String>();
+                match arguments.iter().find(|(parameter, _)| *parameter == name) {
+                    Some((_, value)) => substituted.push_str(&text_of(value)),
+                    None => {
+                        substituted.push('\\');
+                        substituted.push_str(&name);
+                    }
+                }
+            }
+        }
+    }
+    substituted
+}
+
+// an argument on its own is replaced by its tokens, so 8(sp) is still a memory operand
+fn substitute(tokens: &[Token], arguments: &Arguments, count: usize) -> Vec<Token> {
+    let mut substituted = Vec::new();
+    for token in tokens {
+        if !token.text.contains('\\') {
+            substituted.push(token.clone());
+            continue;
+        }
+        let argument = token
+            .text
+            .strip_prefix('\\')
+            .and_then(|name| arguments.iter().find(|(parameter, _)| parameter == name));
+        match (token.kind, argument) {
+            // the tokens take the place of the parameter, their spans would point elsewhere
+            (TokenKind::Identifier, Some((_, value))) => {
+                substituted.extend(value.iter().enumerate().map(|(i, argument)| Token {
+                    span: token.span,
+                    spaced: if i == 0 {
+                        token.spaced
+                    } else {
+                        argument.spaced
+                    },
+                    ..argument.clone()
+                }))
+            }
+            // pasted into a name or a string, like label_\@ or "\name"
+            (kind, _) => {
+                let text = substitute_text(&token.text, arguments, count);
+                let kind = match kind {
+                    TokenKind::Identifier if text.starts_with(|c: char| c.is_ascii_digit()) => {
+                        TokenKind::Number
+                    }
+                    _ => kind,
+                };
+                if !text.is_empty() {
+                    substituted.push(Token {
+                        kind,
+                        text,
+                        ..token.clone()
+                    });
+                }
+            }
+        }
+    }
+    substituted
+}
+
+impl Assembler<'_> {
+    // collects the statement into the block being recorded, false when there is none
+    pub fn record(&mut self, statement: &Statement) -> bool {
+        let mnemonic = mnemonic(statement);
+        let recording = match &mut self.macros.recording {
+            Some(recording) => recording,
+            None if is_block_start(mnemonic) => {
+                self.macros.recording = Some(Recording {
+                    header: statement.clone(),
+                    body: Vec::new(),
+                    depth: 0,
+                });
+                return true;
+            }
+            None => return false,
+        };
+        if is_block_start(mnemonic) {
+            recording.depth += 1;
+        } else if is_block_end(mnemonic) && recording.depth == 0 {
+            let recording = self.macros.recording.take().unwrap();
+            if let Err(error) = self.finish(&recording, mnemonic) {
+                self.report(&recording.header, error);
+            }
+            return true;
+        } else if is_block_end(mnemonic) {
+            recording.depth -= 1;
+        }
+        recording.body.push(statement.tokens.clone());
+        true
+    }
+    // a block left open at the end of the source or of a macro body
+    pub fn unclosed_block(&mut self) {
+        if let Some(recording) = self.macros.recording.take() {
+            let directive = mnemonic(&recording.header);
+            let end = if directive == ".macro" {
+                ".endm"
+            } else {
+                ".endr"
+            };
+            let error = Error::at(directive, format!("Missing {} for {}", end, directive));
+            self.report(&recording.header, error);
+        }
+    }
+    fn finish(&mut self, recording: &Recording, end: &str) -> Result<(), Error> {
+        let header = &recording.header;
+        let directive = mnemonic(header);
+        let operands = header.operands.as_slice();
+        let expected = if directive == ".macro" {
+            ".endm"
+        } else {
+            ".endr"
+        };
+        if end != expected {
+            return Err(Error::statement(format!(
+                "{} has to be closed by {}, found {}",
+                directive, expected, end
+            )));
+        }
+        let body = &recording.body;
+        match directive {
+            ".macro" => {
+                let (name, mut definition) = parse_macro(operands)?;
+                definition.body = body.clone();
+                self.macros.definitions.insert(name, definition);
+            }
+            ".rept" => {
+                let count = match operands {
+                    [count] => self.absolute(count)?,
+                    _ => return Err(Error::statement(".rept needs a count".to_string())),
+                };
+                for _ in 0..count {
+                    self.expand(".rept", body, &[], header)?;
+                }
+            }
+            // .irp name, values... and .irpc name, characters
+            _ => {
+                let (name, values) = match operands.split_first() {
+                    Some((name, values)) => (symbol_name(name)?, values),
+                    None => {
+                        return Err(Error::statement(format!(
+                            "{} needs a parameter name",
+                            directive
+                        )))
+                    }
+                };
+                let values: Vec<Vec<Token>> = if directive == ".irp" {
+                    values
+                        .iter()
+                        .map(|value| value.word.tokens.clone())
+                        .collect()
+                } else {
+                    let characters = values
+                        .iter()
+                        .map(|value| value.word.text.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(",");
+                    let characters = characters.trim_matches('"');
+                    characters
+                        .chars()
+                        .map(|c| lexer::lex(&c.to_string(), header.span.file).0)
+                        .collect()
+                };
+                // no values still expands the body once, with an empty argument
+                let values = if values.is_empty() {
+                    vec![Vec::new()]
+                } else {
+                    values
+                };
+                for value in values {
+                    let arguments = [(name.to_string(), value)];
+                    self.expand(directive, body, &arguments, header)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn is_macro(&self, name: &str) -> bool {
+        self.macros.definitions.contains_key(name)
+    }
+    pub fn purge_macro(&mut self, name: &str) -> Result<(), Error> {
+        match self.macros.definitions.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Error::at(name, format!("Unknown macro: {}", name))),
+        }
+    }
+    // binds positional and name=value arguments, then expands the body
+    pub fn invoke(&mut self, name: &str, statement: &Statement) -> Result<(), Error> {
+        let definition = self.macros.definitions[name].clone();
+        let mut values: Vec<Option<Vec<Token>>> = vec![None; definition.parameters.len()];
+        let mut next = 0;
+        for operand in &statement.operands {
+            let text = operand.word.text.as_str();
+            let tokens = operand.word.tokens.as_slice();
+            let keyword = match tokens {
+                [parameter, equals, value @ ..]
+                    if is_token(Some(equals), TokenKind::Operator, "=") =>
+                {
+                    definition
+                        .parameters
+                        .iter()
+                        .position(|other| other.name == parameter.text)
+                        .map(|index| (index, value))
+                }
+                _ => None,
+            };
+            let (index, value) = keyword.unwrap_or((next, tokens));
+            let parameter = definition.parameters.get(index).ok_or_else(|| {
+                Error::at(
+                    text,
+                    format!(
+                        "Macro {} takes {} arguments",
+                        name,
+                        definition.parameters.len()
+                    ),
+                )
+            })?;
+            values[index] = match (values[index].take(), parameter.vararg) {
+                (Some(mut previous), true) => {
+                    previous.push(Token {
+                        kind: TokenKind::Comma,
+                        text: ",".to_string(),
+                        span: operand.word.span,
+                        spaced: false,
+                    });
+                    previous.extend(value.iter().enumerate().map(|(i, token)| Token {
+                        spaced: i == 0 || token.spaced,
+                        ..token.clone()
+                    }));
+                    Some(previous)
+                }
+                _ => Some(value.to_vec()),
+            };
+            next = if parameter.vararg { index } else { index + 1 };
+        }
+        let mut arguments = Vec::new();
+        for (parameter, value) in definition.parameters.iter().zip(values) {
+            let value = match (value, &parameter.default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => default.clone(),
+                (None, None) if parameter.required => {
+                    return Err(Error::statement(format!(
+                        "Macro {} needs an argument for {}",
+                        name, parameter.name
+                    )))
+                }
+                (None, None) => Vec::new(),
+            };
+            arguments.push((parameter.name.clone(), value));
+        }
+        self.expand(name, &definition.body, &arguments, statement)
+    }
+    fn expand(
+        &mut self,
+        name: &str,
+        body: &[Vec<Token>],
+        arguments: &Arguments,
+        site: &Statement,
+    ) -> Result<(), Error> {
+        if self.macros.depth >= MAX_DEPTH {
+            return Err(Error::statement(format!(
+                "Macro expansion is nested more than {} deep",
+                MAX_DEPTH
+            )));
+        }
+        let count = self.macros.count;
+        self.macros.count += 1;
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        for tokens in body {
+            let (parsed, parse_errors) = parser::parse(&substitute(tokens, arguments, count));
+            statements.extend(parsed);
+            errors.extend(parse_errors);
+        }
+
+        let outermost = self.macros.site.is_none();
+        if outermost {
+            self.macros.site = Some((site.clone(), name.to_string()));
+        }
+        for (_, message) in errors {
+            self.report(site, Error::statement(message));
+        }
+        let conditions = self.conditions.depth();
+        self.macros.depth += 1;
+        for statement in &statements {
+            self.process(statement);
+        }
+        self.unclosed_block();
+        self.unclosed_conditions(conditions);
+        self.macros.depth -= 1;
+        if outermost {
+            self.macros.site = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{assemble, AssemblerOptions};
+
+    // the assembled words, or the line and message of every error
+    fn assemble_words(source: &str) -> Result<Vec<u32>, Vec<(usize, String)>> {
+        match assemble(source, "test.s", &AssemblerOptions::new()) {
+            Ok(assembly) => Ok(assembly
+                .image
+                .chunks(4)
+                .map(|chunk| {
+                    let mut word = [0u8; 4];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    u32::from_le_bytes(word)
+                })
+                .collect()),
+            Err(diagnostics) => Err(diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.line, diagnostic.message))
+                .collect()),
+        }
+    }
+
+    #[test]
+    fn each_expansion_gets_its_own_count() {
+        let source = ".macro spin
+loop_\\@:
+    j loop_\\@
+.endm
+    spin
+    spin
+";
+        // both jumps go to themselves, the labels do not clash
+        assert_eq!(assemble_words(source), Ok(vec![0x0000006f, 0x0000006f]));
+    }
+
+    #[test]
+    fn binds_defaults_keywords_and_remaining_arguments() {
+        let source = ".macro set reg, value=7
+    li \\reg, \\value
+.endm
+.macro words first:req, rest:vararg
+    .word \\first, \\rest
+.endm
+    set a0
+    set a1, 3
+    set value = 9, reg=a2
+    words 1, 2, 3
+";
+        assert_eq!(
+            assemble_words(source),
+            Ok(vec![0x00700513, 0x00300593, 0x00900613, 1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn arguments_keep_their_kind() {
+        let source = ".macro load reg, address
+    lw \\reg, \\address
+.endm
+    load a0, 8(sp)
+    load a1, %lo(12)(a0)
+";
+        assert_eq!(assemble_words(source), Ok(vec![0x00812503, 0x00c52583]));
+    }
+
+    #[test]
+    fn reports_missing_and_extra_arguments() {
+        let source = ".macro words first:req, second
+    .word \\first, \\second
+.endm
+    words
+    words 1, 2, 3
+";
+        assert_eq!(
+            assemble_words(source),
+            Err(vec![
+                (4, "Macro words needs an argument for first".to_string()),
+                (5, "Macro words takes 2 arguments".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn stops_a_macro_that_expands_itself() {
+        let source = ".macro forever
+    forever
+.endm
+    forever
+";
+        assert_eq!(
+            assemble_words(source),
+            Err(vec![(
+                4,
+                "Macro expansion is nested more than 64 deep, in the expansion of forever"
+                    .to_string()
+            )])
+        );
+    }
+
+    #[test]
+    fn repeats_blocks_for_counts_and_values() {
+        let source = ".rept 2
+    .byte 1
+.endr
+.irp value, 2, 3
+    .byte \\value
+.endr
+.irpc digit, 45
+    .byte \\digit
+.endr
+";
+        assert_eq!(assemble_words(source), Ok(vec![0x03020101, 0x0504]));
+    }
+}
@@ -0,0 +1,1407 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use inst_defs::*;
+use instructions::Instruction;
+
+mod conditional;
+mod diagnostic;
+mod expression;
+mod include;
+mod lexer;
+mod listing;
+mod macros;
+mod parser;
+
+use self::conditional::{is_conditional, Conditions};
+use self::diagnostic::{find_token, Error, Span};
+pub use self::diagnostic::{Diagnostic, Severity};
+use self::expression::{Expr, Value};
+use self::include::Source;
+use self::listing::Listing;
+use self::macros::Macros;
+use self::parser::{Node, Operand, Statement, Word};
+
+fn str_is_in_list(list: &[&str], str: &str) -> Option<usize> {
+    list.iter().position(|&keyword| keyword == str)
+}
+
+pub struct AssemblerOptions {
+    // accepts the old operand order lw rd, rs1, imm and sw rs1, rs2, imm
+    pub legacy_syntax: bool,
+    // searched by .include and .incbin after the directory of the including file
+    pub include_paths: Vec<PathBuf>,
+    // constants defined before the first line, like .equ
+    pub defines: Vec<(String, i64)>,
+    // also returns the listing of the addresses and code of every statement
+    pub listing: bool,
+    // the address .text starts at, the other sections follow it
+    pub base: u32,
+}
+
+impl AssemblerOptions {
+    pub fn new() -> AssemblerOptions {
+        AssemblerOptions {
+            legacy_syntax: false,
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            listing: false,
+            base: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+}
+
+// the order sections are laid out in the output image
+const SECTIONS: [Section; 4] = [Section::Text, Section::Rodata, Section::Data, Section::Bss];
+
+impl Section {
+    fn from_name(name: &str) -> Option<Section> {
+        let matches = |prefix: &str| name == prefix || name.starts_with(&format!("{}.", prefix));
+        if matches(".text") {
+            Some(Section::Text)
+        } else if matches(".rodata") || matches(".srodata") {
+            Some(Section::Rodata)
+        } else if matches(".data") || matches(".sdata") {
+            Some(Section::Data)
+        } else if matches(".bss") || matches(".sbss") {
+            Some(Section::Bss)
+        } else {
+            None
+        }
+    }
+}
+
+// the text of operands for messages, as written
+fn texts(operands: &[Operand]) -> String {
+    operands
+        .iter()
+        .map(|operand| operand.word.text.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+fn string(operand: &Operand) -> Result<&[u8], Error> {
+    let text = &operand.word.text;
+    match &operand.node {
+        Node::String(bytes) => Ok(bytes),
+        Node::Invalid(message) => Err(Error::at(text, message.clone())),
+        _ => Err(Error::at(text, format!("Invalid string: {}", text))),
+    }
+}
+
+// a symbol, section or macro name
+fn symbol_name(operand: &Operand) -> Result<&str, Error> {
+    let text = &operand.word.text;
+    match &operand.node {
+        Node::Expression(Expr::Symbol(name)) => Ok(name),
+        _ => Err(Error::at(text, format!("Expected a name, got: {}", text))),
+    }
+}
+
+fn register(operand: &Operand, name: &str) -> Result<u32, Error> {
+    let text = &operand.word.text;
+    match operand.node {
+        Node::Register(number) => Ok(number),
+        _ => Err(Error::at(
+            text,
+            format!("Unknown register {}: {}", name, text),
+        )),
+    }
+}
+
+fn expect_operands(keyword: &str, operands: &[Operand], expected: &str) -> Result<(), Error> {
+    let count = expected.split(", ").filter(|name| !name.is_empty()).count();
+    if operands.len() != count {
+        return Err(Error::statement(format!(
+            "{} needs {} operands: {}, got: {}",
+            keyword,
+            count,
+            expected,
+            texts(operands)
+        )));
+    }
+    Ok(())
+}
+
+// returns (rd or rs2, rs1, offset) for loads, stores and jalr
+fn memory_operands<'a>(
+    keyword: &str,
+    operands: &'a [Operand],
+    store: bool,
+    options: &AssemblerOptions,
+) -> Result<(&'a Operand, &'a Operand, &'a Operand), Error> {
+    match operands {
+        [register, memory] => match &memory.node {
+            Node::Memory { offset, base } => Ok((register, base, offset)),
+            _ => Err(Error::at(
+                &memory.word.text,
+                format!(
+                    "{} expects a memory operand like 8(sp), got: {}",
+                    keyword, memory.word.text
+                ),
+            )),
+        },
+        // jalr rd, rs1, imm is also valid standard syntax
+        [register, base, offset] if keyword == "jalr" => Ok((register, base, offset)),
+        [base, register, offset] if store && options.legacy_syntax => Ok((register, base, offset)),
+        [register, base, offset] if !store && options.legacy_syntax => {
+            Ok((register, base, offset))
+        }
+        [first, second, offset] => Err(Error::statement(format!(
+            "{} uses the legacy operand order {}, write it as {} {}, {}({}) or assemble with --legacy-syntax",
+            keyword,
+            if store { "rs1, rs2, imm" } else { "rd, rs1, imm" },
+            keyword,
+            if store { second } else { first }.word.text,
+            offset.word.text,
+            if store { first } else { second }.word.text
+        ))),
+        _ => Err(Error::statement(format!(
+            "{} needs 2 operands like {} a0, 8(sp), got: {}",
+            keyword,
+            keyword,
+            texts(operands)
+        ))),
+    }
+}
+
+const PSEUDO_INSTRUCTIONS: &[&str] = &[
+    "nop", "li", "la", "mv", "not", "neg", "seqz", "snez", "sltz", "sgtz", "j", "jr", "call",
+    "tail", "ret", "beqz", "bnez", "blez", "bgez", "bltz", "bgtz", "bgt", "ble", "bgtu", "bleu",
+    "csrr", "csrw", "csrs", "csrc", "csrwi", "csrsi", "csrci",
+];
+
+const LOADS: &[&str] = &["lb", "lh", "lw", "lbu", "lhu"];
+const STORES: &[&str] = &["sb", "sh", "sw"];
+
+// upper and lower parts for lui/auipc plus addi, the low part is sign extended
+fn split_hi_lo(value: u32) -> (u32, i32) {
+    let hi = value.wrapping_add(0x800) >> 12;
+    let lo = value.wrapping_sub(hi << 12) as i32;
+    (hi, lo)
+}
+
+// the range of I and S type immediates
+const I12: RangeInclusive<i64> = -2048..=2047;
+
+fn fits_i12(value: u32) -> bool {
+    (-2048..=2047).contains(&(value as i32))
+}
+
+// lw rd, symbol and sw rs2, symbol, rt go through auipc
+fn is_symbol_access(mnemonic: &str, operands: &[Operand]) -> bool {
+    match operands {
+        [_, symbol] if LOADS.contains(&mnemonic) => !matches!(symbol.node, Node::Memory { .. }),
+        [_, symbol, _] if STORES.contains(&mnemonic) => {
+            !matches!(symbol.node, Node::Memory { .. } | Node::Register(_))
+        }
+        _ => false,
+    }
+}
+
+// jal and jalr with a single operand are shorthands, not their base form
+fn is_pseudo(mnemonic: &str, operands: &[Operand]) -> bool {
+    PSEUDO_INSTRUCTIONS.contains(&mnemonic)
+        || (matches!(mnemonic, "jal" | "jalr") && operands.len() == 1)
+        || is_symbol_access(mnemonic, operands)
+}
+
+// 1: can be defined again and again, each definition is kept under its own name
+fn local_label(number: &str, ordinal: usize) -> String {
+    format!("{}\u{2}{}", number, ordinal)
+}
+
+fn is_local_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// 1b is the closest 1: before, 1f the closest one after
+fn local_reference(name: &str) -> Option<(&str, bool)> {
+    let (number, backward) = match name.strip_suffix('b') {
+        Some(number) => (number, true),
+        None => (name.strip_suffix('f')?, false),
+    };
+    is_local_label(number).then_some((number, backward))
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+struct Assembler<'a> {
+    sources: Vec<Source>,
+    include_depth: usize,
+    options: &'a AssemblerOptions,
+    // labels are kept relative to their section until the layout is known
+    labels: HashMap<String, (Section, usize)>,
+    constants: HashMap<String, Value>,
+    // labels placed so far in this pass, for .ifdef
+    defined: HashSet<String>,
+    // how many times each numeric label was placed so far in this pass
+    local_labels: HashMap<String, usize>,
+    // offsets computed by %pcrel_hi, by the address of their auipc
+    pcrel_offsets: RefCell<HashMap<u32, u32>>,
+    section: Section,
+    sections: [Vec<u8>; 4],
+    alignments: [usize; 4],
+    bases: [usize; 4],
+    li_sizes: Vec<usize>,
+    li_index: usize,
+    macros: Macros,
+    conditions: Conditions,
+    listing: Listing,
+    // false during the first pass, which only sizes the sections
+    resolved: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Assembler<'a> {
+    fn new(source: Source, options: &'a AssemblerOptions) -> Assembler<'a> {
+        Assembler {
+            sources: vec![source],
+            include_depth: 0,
+            options,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            defined: HashSet::new(),
+            local_labels: HashMap::new(),
+            pcrel_offsets: RefCell::new(HashMap::new()),
+            section: Section::Text,
+            sections: Default::default(),
+            alignments: [4; 4],
+            // the first pass already sees label addresses close to their final ones
+            bases: [options.base as usize; 4],
+            li_sizes: Vec::new(),
+            li_index: 0,
+            macros: Macros::default(),
+            conditions: Conditions::default(),
+            listing: Listing::default(),
+            resolved: false,
+            diagnostics: Vec::new(),
+        }
+    }
+    fn address(&self) -> u32 {
+        let index = self.section as usize;
+        (self.bases[index] + self.sections[index].len()) as u32
+    }
+    fn symbol(&self, name: &str) -> Result<Value, String> {
+        if let Some((number, backward)) = local_reference(name) {
+            let count = self.local_labels.get(number).copied().unwrap_or(0);
+            let ordinal = if backward {
+                count.checked_sub(1)
+            } else {
+                Some(count)
+            };
+            let label = ordinal
+                .map(|ordinal| local_label(number, ordinal))
+                .filter(|label| self.labels.contains_key(label));
+            return match label {
+                Some(label) => self.symbol(&label),
+                None if self.resolved => Err(format!(
+                    "No {} definition of {}: for {}",
+                    if backward { "previous" } else { "following" },
+                    number,
+                    name
+                )),
+                None => Ok(Value {
+                    number: 0,
+                    labels: 0,
+                    known: false,
+                }),
+            };
+        }
+        if let Some(&value) = self.constants.get(name) {
+            return Ok(value);
+        }
+        match self.labels.get(name) {
+            // label addresses are only final once the layout is done
+            Some(&(section, offset)) => Ok(Value {
+                number: (self.bases[section as usize] + offset) as i64,
+                labels: 1,
+                known: self.resolved,
+            }),
+            None if self.resolved => Err(format!("Unknown symbol: {}", name)),
+            None => Ok(Value {
+                number: 0,
+                labels: 0,
+                known: false,
+            }),
+        }
+    }
+    fn relocation(&self, name: &str, value: Value) -> Result<Value, String> {
+        let number = value.number as u32;
+        let number = match name {
+            "hi" => split_hi_lo(number).0 & 0xfffff,
+            "lo" => split_hi_lo(number).1 as u32,
+            "pcrel_hi" => {
+                let offset = number.wrapping_sub(self.address());
+                // %pcrel_lo refers back to this auipc by its address
+                if self.resolved {
+                    self.pcrel_offsets
+                        .borrow_mut()
+                        .insert(self.address(), offset);
+                }
+                split_hi_lo(offset).0 & 0xfffff
+            }
+            "pcrel_lo" => match self.pcrel_offsets.borrow().get(&number) {
+                Some(&offset) => split_hi_lo(offset).1 as u32,
+                None if !self.resolved => 0,
+                None => {
+                    return Err(format!(
+                        "%pcrel_lo needs the label of an auipc using %pcrel_hi, got: {:#x}",
+                        number
+                    ))
+                }
+            },
+            _ => return Err(format!("Unknown relocation: %{}", name)),
+        };
+        Ok(Value {
+            number: number as i32 as i64,
+            labels: 0,
+            known: value.known,
+        })
+    }
+    fn expression(&self, operand: &Operand) -> Result<Value, Error> {
+        let text = &operand.word.text;
+        let value = match &operand.node {
+            Node::Expression(expression) => expression::evaluate(self, expression, text),
+            Node::Invalid(message) => Err(message.clone()),
+            Node::Register(_) => Err(format!("Expected an expression, got register {}", text)),
+            _ => Err(format!("Expected an expression, got: {}", text)),
+        };
+        value.map_err(|message| Error::at(text, message))
+    }
+    fn value(&self, operand: &Operand) -> Result<u32, Error> {
+        Ok(self.expression(operand)?.number as u32)
+    }
+    // an immediate that has to fit its field, like -2048..=2047 for addi
+    fn immediate(&self, operand: &Operand, range: RangeInclusive<i64>) -> Result<u32, Error> {
+        let token = &operand.word.text;
+        match self.expression(operand)?.number {
+            number if range.contains(&number) => Ok(number as u32),
+            number => Err(Error::at(
+                token,
+                format!(
+                    "Immediate {} is not between {} and {}: {}",
+                    number,
+                    range.start(),
+                    range.end(),
+                    token
+                ),
+            )),
+        }
+    }
+    // a branch or jump offset, it has to be even and within reach
+    fn offset(&self, operand: &Operand, range: RangeInclusive<i64>) -> Result<u32, Error> {
+        let token = &operand.word.text;
+        let offset = self.relative(operand)? as i32 as i64;
+        if !range.contains(&offset) {
+            return Err(Error::at(
+                token,
+                format!(
+                    "Target is out of range, offset {} is not between {} and {}: {}",
+                    offset,
+                    range.start(),
+                    range.end(),
+                    token
+                ),
+            ));
+        }
+        if offset % 2 != 0 {
+            return Err(Error::at(
+                token,
+                format!("Target offset {} is odd: {}", offset, token),
+            ));
+        }
+        Ok(offset as u32)
+    }
+    // a CSR name like mstatus, or its number
+    fn csr(&self, operand: &Operand) -> Result<u32, Error> {
+        if let Node::Expression(Expr::Symbol(symbol)) = &operand.node {
+            if let Some(&(_, number)) = CSRS.iter().find(|&&(name, _)| name == symbol) {
+                return Ok(number);
+            }
+        }
+        match self.value(operand)? {
+            number if number <= 0xfff => Ok(number),
+            _ => Err(Error::at(
+                &operand.word.text,
+                format!(
+                    "CSR must be a name or a number up to 0xfff: {}",
+                    operand.word.text
+                ),
+            )),
+        }
+    }
+    // sizes and alignments have to be known when the line is reached
+    fn absolute(&self, operand: &Operand) -> Result<u32, Error> {
+        let token = &operand.word.text;
+        match self.expression(operand)? {
+            value if value.known && value.labels == 0 => Ok(value.number as u32),
+            _ => Err(Error::at(
+                token,
+                format!(
+                    "Expression must be a constant defined before use: {}",
+                    token
+                ),
+            )),
+        }
+    }
+    // labels are pc relative, plain numbers are used as the offset itself
+    fn relative(&self, operand: &Operand) -> Result<u32, Error> {
+        let value = self.expression(operand)?;
+        match value.labels {
+            0 => Ok(value.number as u32),
+            1 => Ok((value.number as u32).wrapping_sub(self.address())),
+            _ => Err(Error::at(
+                &operand.word.text,
+                format!("Expression is not a single address: {}", operand.word.text),
+            )),
+        }
+    }
+    // li is sized in the first pass and keeps that size so labels do not move
+    fn li_size(&mut self, operand: &Operand) -> usize {
+        if self.resolved {
+            self.li_index += 1;
+            return self.li_sizes[self.li_index - 1];
+        }
+        let size = match self.expression(operand) {
+            Ok(value)
+                if value.known
+                    && value.labels == 0
+                    && (fits_i12(value.number as u32) || value.number & 0xfff == 0) =>
+            {
+                1
+            }
+            _ => 2,
+        };
+        self.li_sizes.push(size);
+        size
+    }
+    // rewrites a pseudo-instruction into base instructions at the current address
+    fn expand_pseudo(
+        &mut self,
+        mnemonic: &str,
+        operands: &[Operand],
+    ) -> Result<Vec<(&'static str, Vec<Operand>)>, Error> {
+        let ops = |list: &[&Operand]| list.iter().map(|&op| op.clone()).collect::<Vec<Operand>>();
+        let reg = Operand::register;
+        let num = Operand::number;
+        if is_symbol_access(mnemonic, operands) {
+            let keyword = KEYWORDS[str_is_in_list(KEYWORDS, mnemonic).unwrap()];
+            let (register, symbol, base) = match operands {
+                [rd, symbol] => (rd, symbol, rd),
+                [rs2, symbol, rt] => (rs2, symbol, rt),
+                _ => unreachable!(),
+            };
+            let (hi, lo) = split_hi_lo(self.value(symbol)?.wrapping_sub(self.address()));
+            return Ok(vec![
+                ("auipc", ops(&[base, &num((hi & 0xfffff) as i64)])),
+                (keyword, ops(&[register, &Operand::memory(lo as i64, base)])),
+            ]);
+        }
+        let expected = match mnemonic {
+            "nop" | "ret" => "",
+            "j" | "jal" | "jr" | "jalr" | "call" | "tail" => "target",
+            "li" | "la" | "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" => "rd, source",
+            "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => "rs, label",
+            "bgt" | "ble" | "bgtu" | "bleu" => "rs, rt, label",
+            "csrr" => "rd, csr",
+            _ => "csr, source",
+        };
+        expect_operands(mnemonic, operands, expected)?;
+        Ok(match (mnemonic, operands) {
+            ("nop", []) => vec![("addi", ops(&[&reg("zero"), &reg("zero"), &num(0)]))],
+            ("ret", []) => vec![("jalr", ops(&[&reg("zero"), &reg("ra"), &num(0)]))],
+            ("li", [rd, imm]) => {
+                let value = self.value(imm)?;
+                let (hi, lo) = split_hi_lo(value);
+                match self.li_size(imm) {
+                    1 if fits_i12(value) => {
+                        vec![("addi", ops(&[rd, &reg("zero"), &num(value as i32 as i64)]))]
+                    }
+                    1 => vec![("lui", ops(&[rd, &num(hi as i64)]))],
+                    _ => vec![
+                        ("lui", ops(&[rd, &num((hi & 0xfffff) as i64)])),
+                        ("addi", ops(&[rd, rd, &num(lo as i64)])),
+                    ],
+                }
+            }
+            ("la", [rd, label]) => {
+                let offset = self.value(label)?.wrapping_sub(self.address());
+                let (hi, lo) = split_hi_lo(offset);
+                vec![
+                    ("auipc", ops(&[rd, &num((hi & 0xfffff) as i64)])),
+                    ("addi", ops(&[rd, rd, &num(lo as i64)])),
+                ]
+            }
+            ("call" | "tail", [label]) => {
+                let offset = self.value(label)?.wrapping_sub(self.address());
+                let (hi, lo) = split_hi_lo(offset);
+                let (rd, scratch) = if mnemonic == "call" {
+                    (reg("ra"), reg("ra"))
+                } else {
+                    (reg("zero"), reg("t1"))
+                };
+                vec![
+                    ("auipc", ops(&[&scratch, &num((hi & 0xfffff) as i64)])),
+                    ("jalr", ops(&[&rd, &scratch, &num(lo as i64)])),
+                ]
+            }
+            ("mv", [rd, rs]) => vec![("addi", ops(&[rd, rs, &num(0)]))],
+            ("not", [rd, rs]) => vec![("xori", ops(&[rd, rs, &num(-1)]))],
+            ("neg", [rd, rs]) => vec![("sub", ops(&[rd, &reg("zero"), rs]))],
+            ("seqz", [rd, rs]) => vec![("sltiu", ops(&[rd, rs, &num(1)]))],
+            ("snez", [rd, rs]) => vec![("sltu", ops(&[rd, &reg("zero"), rs]))],
+            ("sltz", [rd, rs]) => vec![("slt", ops(&[rd, rs, &reg("zero")]))],
+            ("sgtz", [rd, rs]) => vec![("slt", ops(&[rd, &reg("zero"), rs]))],
+            ("j", [label]) => vec![("jal", ops(&[&reg("zero"), label]))],
+            ("jal", [label]) => vec![("jal", ops(&[&reg("ra"), label]))],
+            ("jr", [rs]) => vec![("jalr", ops(&[&reg("zero"), rs, &num(0)]))],
+            ("jalr", [rs]) => vec![("jalr", ops(&[&reg("ra"), rs, &num(0)]))],
+            ("beqz", [rs, label]) => vec![("beq", ops(&[rs, &reg("zero"), label]))],
+            ("bnez", [rs, label]) => vec![("bne", ops(&[rs, &reg("zero"), label]))],
+            ("blez", [rs, label]) => vec![("bge", ops(&[&reg("zero"), rs, label]))],
+            ("bgez", [rs, label]) => vec![("bge", ops(&[rs, &reg("zero"), label]))],
+            ("bltz", [rs, label]) => vec![("blt", ops(&[rs, &reg("zero"), label]))],
+            ("bgtz", [rs, label]) => vec![("blt", ops(&[&reg("zero"), rs, label]))],
+            ("bgt", [rs, rt, label]) => vec![("blt", ops(&[rt, rs, label]))],
+            ("ble", [rs, rt, label]) => vec![("bge", ops(&[rt, rs, label]))],
+            ("bgtu", [rs, rt, label]) => vec![("bltu", ops(&[rt, rs, label]))],
+            ("bleu", [rs, rt, label]) => vec![("bgeu", ops(&[rt, rs, label]))],
+            ("csrr", [rd, csr]) => vec![("csrrs", ops(&[rd, csr, &reg("zero")]))],
+            ("csrw", [csr, rs]) => vec![("csrrw", ops(&[&reg("zero"), csr, rs]))],
+            ("csrs", [csr, rs]) => vec![("csrrs", ops(&[&reg("zero"), csr, rs]))],
+            ("csrc", [csr, rs]) => vec![("csrrc", ops(&[&reg("zero"), csr, rs]))],
+            ("csrwi", [csr, zimm]) => vec![("csrrwi", ops(&[&reg("zero"), csr, zimm]))],
+            ("csrsi", [csr, zimm]) => vec![("csrrsi", ops(&[&reg("zero"), csr, zimm]))],
+            ("csrci", [csr, zimm]) => vec![("csrrci", ops(&[&reg("zero"), csr, zimm]))],
+            _ => unreachable!(),
+        })
+    }
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.section == Section::Bss && bytes.iter().any(|&byte| byte != 0) {
+            return Err(Error::statement(
+                "Only zeros can be placed in .bss, use .space or .zero".to_string(),
+            ));
+        }
+        self.sections[self.section as usize].extend_from_slice(bytes);
+        Ok(())
+    }
+    fn emit_instruction(&mut self, opcode: usize, operands: &[Operand]) -> Result<(), Error> {
+        if self.section == Section::Bss {
+            return Err(Error::statement(
+                "Instructions cannot be placed in .bss".to_string(),
+            ));
+        }
+        let offset = self.sections[self.section as usize].len();
+        if !offset.is_multiple_of(4) {
+            return Err(Error::statement(format!(
+                "Instruction at 0x{:x} is not aligned to 4 bytes, add .align 2 before it",
+                self.address()
+            )));
+        }
+        // the first pass only needs the size, labels may not be known yet
+        let inst = if self.resolved {
+            self.encode(opcode, operands)?
+        } else {
+            0
+        };
+        self.list_instruction(opcode, operands, inst);
+        self.emit(&inst.to_le_bytes())
+    }
+    // code is padded with nops, data with the fill byte
+    fn align(&mut self, alignment: usize, fill: Option<u8>) -> Result<(), Error> {
+        if alignment == 0 || !alignment.is_power_of_two() {
+            return Err(Error::statement(format!(
+                "Alignment must be a power of two: {}",
+                alignment
+            )));
+        }
+        let index = self.section as usize;
+        self.alignments[index] = self.alignments[index].max(alignment);
+        let offset = self.sections[index].len();
+        let padding = align_up(offset, alignment) - offset;
+        if self.section == Section::Text && fill.is_none() && offset.is_multiple_of(4) {
+            let nop = inst_addi(0, 0, 0).get_bits().to_le_bytes();
+            self.emit(&nop.repeat(padding / 4))
+        } else {
+            self.emit(&vec![fill.unwrap_or(0); padding])
+        }
+    }
+    fn directive(&mut self, name: &str, operands: &[Operand]) -> Result<(), Error> {
+        match name {
+            ".text" | ".data" | ".rodata" | ".bss" => {
+                expect_operands(name, operands, "")?;
+                self.section = Section::from_name(name).unwrap();
+            }
+            ".section" => {
+                let section = match operands.first() {
+                    Some(operand) => symbol_name(operand)?,
+                    None => "",
+                };
+                self.section = Section::from_name(section)
+                    .ok_or_else(|| Error::at(section, format!("Unknown section: {}", section)))?;
+            }
+            ".purgem" => {
+                expect_operands(name, operands, "name")?;
+                self.purge_macro(symbol_name(&operands[0])?)?;
+            }
+            ".endm" | ".endr" => {
+                return Err(Error::at(
+                    name,
+                    format!("{} without a block to close", name),
+                ))
+            }
+            // every symbol ends up in the same flat image
+            ".globl" | ".global" | ".local" | ".type" | ".size" | ".option" | ".file" => {}
+            ".equ" | ".set" => {
+                expect_operands(name, operands, "symbol, value")?;
+                let value = self.expression(&operands[1])?;
+                self.constants
+                    .insert(symbol_name(&operands[0])?.to_string(), value);
+            }
+            ".byte" | ".half" | ".short" | ".word" | ".long" | ".dword" | ".quad" => {
+                let size = match name {
+                    ".byte" => 1,
+                    ".half" | ".short" => 2,
+                    ".word" | ".long" => 4,
+                    _ => 8,
+                };
+                for operand in operands {
+                    let value = self.expression(operand)?;
+                    // signed and unsigned values both fit, like -1 and 255 for .byte
+                    let bits = 8 * size as u32;
+                    if bits < 64 && !(-(1 << (bits - 1))..(1 << bits)).contains(&value.number) {
+                        return Err(Error::at(
+                            &operand.word.text,
+                            format!(
+                                "Value {} does not fit in {}: {}",
+                                value.number, name, operand.word.text
+                            ),
+                        ));
+                    }
+                    self.emit(&(value.number as u64).to_le_bytes()[..size])?;
+                }
+            }
+            ".ascii" | ".asciz" | ".string" => {
+                for operand in operands {
+                    let mut bytes = string(operand)?.to_vec();
+                    if name != ".ascii" {
+                        bytes.push(0);
+                    }
+                    self.emit(&bytes)?;
+                }
+            }
+            ".space" | ".zero" | ".skip" => {
+                let (size, fill) = match operands {
+                    [size] => (size, 0),
+                    [size, fill] => (size, self.absolute(fill)? as u8),
+                    _ => {
+                        return Err(Error::statement(format!(
+                            "{} needs a size and an optional fill byte",
+                            name
+                        )))
+                    }
+                };
+                self.emit(&vec![fill; self.absolute(size)? as usize])?;
+            }
+            ".align" | ".p2align" | ".balign" => {
+                let (alignment, fill) = match operands {
+                    [alignment] => (self.absolute(alignment)?, None),
+                    [alignment, fill] => {
+                        (self.absolute(alignment)?, Some(self.absolute(fill)? as u8))
+                    }
+                    _ => {
+                        return Err(Error::statement(format!(
+                            "{} needs an alignment and an optional fill byte",
+                            name
+                        )))
+                    }
+                };
+                // .align is a power of two on RISC-V, like .p2align
+                let alignment = if name == ".balign" {
+                    alignment as usize
+                } else {
+                    1 << alignment
+                };
+                self.align(alignment, fill)?;
+            }
+            _ => {
+                return Err(Error::warning(
+                    name,
+                    format!("Skipping unknown directive: {}", name),
+                ))
+            }
+        }
+        Ok(())
+    }
+    fn statement(&mut self, statement: &Statement) -> Result<(), Error> {
+        let mnemonic = match &statement.mnemonic {
+            Some(mnemonic) => mnemonic.text.as_str(),
+            None => return Ok(()),
+        };
+        let operands = &statement.operands;
+        if matches!(mnemonic, ".include" | ".incbin") {
+            return self.include(mnemonic, operands, statement.span.file);
+        }
+        if mnemonic.starts_with('.') {
+            return self.directive(mnemonic, operands);
+        }
+        if self.is_macro(mnemonic) {
+            return self.invoke(mnemonic, statement);
+        }
+        if is_pseudo(mnemonic, operands) {
+            for (keyword, operands) in self.expand_pseudo(mnemonic, operands)? {
+                self.emit_instruction(str_is_in_list(KEYWORDS, keyword).unwrap(), &operands)?;
+            }
+            return Ok(());
+        }
+        match str_is_in_list(KEYWORDS, mnemonic) {
+            Some(opcode) => self.emit_instruction(opcode, operands),
+            None => Err(Error::at(
+                mnemonic,
+                format!("Unknown instruction: {}", mnemonic),
+            )),
+        }
+    }
+    fn diagnostic(&self, severity: Severity, span: Span, message: String) -> Diagnostic {
+        let source = &self.sources[span.file];
+        Diagnostic {
+            severity,
+            file: source.path.display().to_string(),
+            line: span.line,
+            column: span.column,
+            length: span.length,
+            message,
+            snippet: source.lines.get(span.line - 1).cloned().unwrap_or_default(),
+        }
+    }
+    // points at the word holding the token the error is about, or the whole statement
+    fn statement_diagnostic(&self, statement: &Statement, error: Error) -> Diagnostic {
+        let words = statement
+            .mnemonic
+            .iter()
+            .chain(statement.operands.iter().map(|operand| &operand.word))
+            .chain(&statement.labels);
+        let span = error
+            .token
+            .as_deref()
+            .and_then(|token| {
+                words
+                    .filter(|word| word.span.line == statement.span.line)
+                    .find_map(|word: &Word| {
+                        let offset = find_token(&word.text, token)?;
+                        Some(Span {
+                            column: word.span.column + offset,
+                            length: token.chars().count(),
+                            ..word.span
+                        })
+                    })
+            })
+            .unwrap_or(statement.span);
+        self.diagnostic(error.severity, span, error.message)
+    }
+    // inside a macro expansion the diagnostic points at where it was expanded
+    fn report(&mut self, statement: &Statement, error: Error) {
+        let diagnostic = match &self.macros.site {
+            Some((site, name)) => {
+                let error = Error {
+                    message: format!("{}, in the expansion of {}", error.message, name),
+                    token: None,
+                    ..error
+                };
+                self.statement_diagnostic(site, error)
+            }
+            None => self.statement_diagnostic(statement, error),
+        };
+        self.diagnostics.push(diagnostic);
+    }
+    fn process(&mut self, statement: &Statement) {
+        // conditions inside a block being recorded are checked when it is expanded
+        if !self.macros.is_recording() {
+            let mnemonic = statement.mnemonic.as_ref().map_or("", |word| &word.text);
+            if is_conditional(mnemonic) {
+                if let Err(error) = self.conditional(mnemonic, statement) {
+                    self.report(statement, error);
+                }
+                return;
+            }
+            if !self.active() {
+                return;
+            }
+        }
+        if self.record(statement) {
+            return;
+        }
+        for label in &statement.labels {
+            if let Err(error) = self.define(&label.text) {
+                self.report(statement, error);
+            }
+        }
+        let start = (
+            self.section,
+            self.sections[self.section as usize].len(),
+            self.address(),
+        );
+        let rows = self.listing.position();
+        if let Err(error) = self.statement(statement) {
+            self.report(statement, error);
+        }
+        self.list(statement, start, rows);
+    }
+    fn define(&mut self, name: &str) -> Result<(), Error> {
+        let place = (self.section, self.sections[self.section as usize].len());
+        if is_local_label(name) {
+            let count = self.local_labels.entry(name.to_string()).or_insert(0);
+            self.labels.insert(local_label(name, *count), place);
+            *count += 1;
+            return Ok(());
+        }
+        // the first definition is kept
+        if !self.defined.insert(name.to_string()) {
+            return Err(Error::at(
+                name,
+                format!("Label {} is already defined", name),
+            ));
+        }
+        self.labels.insert(name.to_string(), place);
+        Ok(())
+    }
+    // only the diagnostics of the last pass are kept, it sees every statement again
+    fn pass(&mut self) {
+        self.section = Section::Text;
+        self.sections = Default::default();
+        self.constants = self
+            .options
+            .defines
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::constant(*value)))
+            .collect();
+        self.defined.clear();
+        self.local_labels.clear();
+        self.macros = Macros::default();
+        self.conditions.reset();
+        self.listing = Listing::default();
+        self.li_index = 0;
+        self.diagnostics.clear();
+        let errors = self.sources[0]
+            .errors
+            .iter()
+            .map(|(span, message)| self.diagnostic(Severity::Error, *span, message.clone()))
+            .collect::<Vec<Diagnostic>>();
+        self.diagnostics.extend(errors);
+        let statements = self.sources[0].statements.clone();
+        for statement in &statements {
+            self.process(statement);
+        }
+        self.unclosed_block();
+        self.unclosed_conditions(0);
+    }
+    // places the sections one after another from the base, each aligned to its largest alignment
+    fn layout(&mut self) {
+        let mut address = self.options.base as usize;
+        for section in SECTIONS {
+            let index = section as usize;
+            address = align_up(address, self.alignments[index]);
+            self.bases[index] = address;
+            address += self.sections[index].len();
+        }
+        self.resolved = true;
+    }
+    fn check_address_space(&mut self) {
+        let last = Section::Bss as usize;
+        let end = self.bases[last] + self.sections[last].len();
+        if end as u64 > 1 << 32 {
+            let message = format!(
+                "The sections from 0x{:X} to 0x{:X} go past the end of the address space",
+                self.bases[Section::Text as usize],
+                end
+            );
+            // not caused by any one line, so it points at the start of the file
+            let span = Span {
+                file: 0,
+                line: 1,
+                column: 1,
+                length: 0,
+            };
+            let diagnostic = self.diagnostic(Severity::Error, span, message);
+            self.diagnostics.push(diagnostic);
+        }
+    }
+    fn entry(&self) -> u32 {
+        let (section, offset) = self
+            .labels
+            .get("_start")
+            .copied()
+            .unwrap_or((Section::Text, 0));
+        (self.bases[section as usize] + offset) as u32
+    }
+    fn image(&self) -> Vec<u8> {
+        let start = self.bases[Section::Text as usize];
+        let mut image = Vec::new();
+        for section in SECTIONS {
+            let index = section as usize;
+            image.resize(self.bases[index] - start, 0);
+            image.extend_from_slice(&self.sections[index]);
+        }
+        image
+    }
+    // the current address is used for pc relative labels
+    fn encode(&self, opcode: usize, operands: &[Operand]) -> Result<u32, Error> {
+        let keyword = KEYWORDS[opcode];
+        let options = self.options;
+
+        let inst = match (opcode, &OPCODE_FUNCTS[opcode]) {
+            // U: lui auipc
+            (0..=1, InstFn2ArgsU32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, imm")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    self.immediate(&operands[1], 0..=0xfffff)?,
+                )
+            }
+            // J: jal
+            (2, InstFn2ArgsI32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, imm/label")?;
+                let imm = self.offset(&operands[1], -0x10_0000..=0xf_fffe)?;
+                inst_funct(register(&operands[0], "rd")?, imm as i32)
+            }
+            // I: jalr and loads
+            (3 | 10 | 11 | 12 | 13 | 14, InstFn3ArgsI32(inst_funct)) => {
+                let (rd, rs1, imm) = memory_operands(keyword, operands, false, options)?;
+                inst_funct(
+                    register(rd, "rd")?,
+                    register(rs1, "rs1")?,
+                    self.immediate(imm, I12)? as i32,
+                )
+            }
+            // S: sb sh sw
+            (15..=17, InstFn3ArgsI32(inst_funct)) => {
+                let (rs2, rs1, imm) = memory_operands(keyword, operands, true, options)?;
+                inst_funct(
+                    register(rs1, "rs1")?,
+                    register(rs2, "rs2")?,
+                    self.immediate(imm, I12)? as i32,
+                )
+            }
+            // I: addi slti sltiu xori ori andi
+            (18..=23, InstFn3ArgsI32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, rs1, imm")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    register(&operands[1], "rs1")?,
+                    self.immediate(&operands[2], I12)? as i32,
+                )
+            }
+            // Shift: slli srli srai
+            (24..=26, InstFn3ArgsU32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, rs1, shamt")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    register(&operands[1], "rs1")?,
+                    self.immediate(&operands[2], 0..=31)?,
+                )
+            }
+            // R
+            (27..=36, InstFn3ArgsU32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, rs1, rs2")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    register(&operands[1], "rs1")?,
+                    register(&operands[2], "rs2")?,
+                )
+            }
+            // B
+            (4..=9, InstFn3ArgsI32(inst_funct)) => {
+                expect_operands(keyword, operands, "rs1, rs2, imm/label")?;
+                let imm = self.offset(&operands[2], -0x1000..=0xffe)?;
+                inst_funct(
+                    register(&operands[0], "rs1")?,
+                    register(&operands[1], "rs2")?,
+                    imm as i32,
+                )
+            }
+            // E: ecall ebreak
+            (37..=38, InstFn0Args(inst_funct)) => {
+                expect_operands(keyword, operands, "")?;
+                inst_funct()
+            }
+            // CSR: csrrw csrrs csrrc
+            (39..=41, InstFn3ArgsI32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, csr, rs1")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    register(&operands[2], "rs1")?,
+                    self.csr(&operands[1])? as i32,
+                )
+            }
+            // CSR I: csrrwi csrrsi csrrci
+            (42..=44, InstFn3ArgsI32(inst_funct)) => {
+                expect_operands(keyword, operands, "rd, csr, zimm")?;
+                inst_funct(
+                    register(&operands[0], "rd")?,
+                    self.immediate(&operands[2], 0..=31)?,
+                    self.csr(&operands[1])? as i32,
+                )
+            }
+            _ => unreachable!(),
+        };
+        Ok(inst.get_bits())
+    }
+}
+
+pub struct Assembly {
+    // the flat image: .text, .rodata, .data and the zeroed .bss, each aligned,
+    // a raw file of it has to be loaded at address to run
+    pub image: Vec<u8>,
+    // where the image starts, the base aligned for .text, and _start or the start of .text
+    pub address: u32,
+    pub entry: u32,
+    pub listing: Option<String>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub fn assemble(
+    source: &str,
+    file: &str,
+    options: &AssemblerOptions,
+) -> Result<Assembly, Vec<Diagnostic>> {
+    println!("Assembling file");
+    let mut assembler = Assembler::new(Source::new(PathBuf::from(file), source, 0), options);
+    assembler.pass();
+    assembler.layout();
+    assembler.pass();
+    assembler.check_address_space();
+
+    let mut diagnostics = std::mem::take(&mut assembler.diagnostics);
+    // grouped by file in the order they were read, in line order within each
+    let files = assembler
+        .sources
+        .iter()
+        .map(|source| source.path.display().to_string())
+        .collect::<Vec<String>>();
+    diagnostics.sort_by_key(|diagnostic| {
+        let file = files.iter().position(|file| *file == diagnostic.file);
+        (file, diagnostic.line, diagnostic.column)
+    });
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return Err(diagnostics);
+    }
+
+    println!("Finished assembling file");
+
+    Ok(Assembly {
+        image: assembler.image(),
+        address: assembler.bases[Section::Text as usize] as u32,
+        entry: assembler.entry(),
+        listing: options.listing.then(|| assembler.listing()),
+        warnings: diagnostics,
+    })
+}
+
+const KEYWORDS: &[&str; 45] = &[
+    "lui", "auipc", "jal", "jalr", "beq", "bne", "blt", "bge", "bltu", "bgeu", "lb", "lh", "lw",
+    "lbu", "lhu", "sb", "sh", "sw", "addi", "slti", "sltiu", "xori", "ori", "andi", "slli", "srli",
+    "srai", "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and", "ecall",
+    "ebreak", "csrrw", "csrrs", "csrrc", "csrrwi", "csrrsi", "csrrci",
+];
+
+enum InstFnTypes {
+    InstFn0Args(fn() -> Instruction),
+    InstFn2ArgsI32(fn(u32, i32) -> Instruction),
+    InstFn2ArgsU32(fn(u32, u32) -> Instruction),
+    InstFn3ArgsI32(fn(u32, u32, i32) -> Instruction),
+    InstFn3ArgsU32(fn(u32, u32, u32) -> Instruction),
+}
+
+use self::InstFnTypes::*;
+
+const OPCODE_FUNCTS: &[InstFnTypes; 45] = &[
+    InstFn2ArgsU32(inst_lui),
+    InstFn2ArgsU32(inst_auipc),
+    InstFn2ArgsI32(inst_jal),
+    InstFn3ArgsI32(inst_jalr),
+    InstFn3ArgsI32(inst_beq),
+    InstFn3ArgsI32(inst_bne),
+    InstFn3ArgsI32(inst_blt),
+    InstFn3ArgsI32(inst_bge),
+    InstFn3ArgsI32(inst_bltu),
+    InstFn3ArgsI32(inst_bgeu),
+    InstFn3ArgsI32(inst_lb),
+    InstFn3ArgsI32(inst_lh),
+    InstFn3ArgsI32(inst_lw),
+    InstFn3ArgsI32(inst_lbu),
+    InstFn3ArgsI32(inst_lhu),
+    InstFn3ArgsI32(inst_sb),
+    InstFn3ArgsI32(inst_sh),
+    InstFn3ArgsI32(inst_sw),
+    InstFn3ArgsI32(inst_addi),
+    InstFn3ArgsI32(inst_slti),
+    InstFn3ArgsI32(inst_sltiu),
+    InstFn3ArgsI32(inst_xori),
+    InstFn3ArgsI32(inst_ori),
+    InstFn3ArgsI32(inst_andi),
+    InstFn3ArgsU32(inst_slli),
+    InstFn3ArgsU32(inst_srli),
+    InstFn3ArgsU32(inst_srai),
+    InstFn3ArgsU32(inst_add),
+    InstFn3ArgsU32(inst_sub),
+    InstFn3ArgsU32(inst_sll),
+    InstFn3ArgsU32(inst_slt),
+    InstFn3ArgsU32(inst_sltu),
+    InstFn3ArgsU32(inst_xor),
+    InstFn3ArgsU32(inst_srl),
+    InstFn3ArgsU32(inst_sra),
+    InstFn3ArgsU32(inst_or),
+    InstFn3ArgsU32(inst_and),
+    InstFn0Args(inst_ecall),
+    InstFn0Args(inst_ebreak),
+    InstFn3ArgsI32(inst_csrrw),
+    InstFn3ArgsI32(inst_csrrs),
+    InstFn3ArgsI32(inst_csrrc),
+    InstFn3ArgsI32(inst_csrrwi),
+    InstFn3ArgsI32(inst_csrrsi),
+    InstFn3ArgsI32(inst_csrrci),
+];
+
+const REGISTERS: &[&str; 65] = &[
+    "zero", "ra", "sp", "gp", "tp", "fp", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s0",
+    "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t0", "t1", "t2", "t3",
+    "t4", "t5", "t6", "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11",
+    "x12", "x13", "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24",
+    "x25", "x26", "x27", "x28", "x29", "x30", "x31",
+];
+
+const REGISTERS_INDEX: &[u32; 65] = &[
+    0, 1, 2, 3, 4, 8, 10, 11, 12, 13, 14, 15, 16, 17, 8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    5, 6, 7, 28, 29, 30, 31, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+    20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+];
+
+const CSRS: &[(&str, u32)] = &[
+    ("ustatus", 0x000),
+    ("fflags", 0x001),
+    ("frm", 0x002),
+    ("fcsr", 0x003),
+    ("uie", 0x004),
+    ("utvec", 0x005),
+    ("uscratch", 0x040),
+    ("uepc", 0x041),
+    ("ucause", 0x042),
+    ("utval", 0x043),
+    ("uip", 0x044),
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("scounteren", 0x106),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mcounteren", 0x306),
+    ("mstatush", 0x310),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("mcycle", 0xb00),
+    ("minstret", 0xb02),
+    ("mcycleh", 0xb80),
+    ("minstreth", 0xb82),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("instret", 0xc02),
+    ("cycleh", 0xc80),
+    ("timeh", 0xc81),
+    ("instreth", 0xc82),
+    ("mvendorid", 0xf11),
+    ("marchid", 0xf12),
+    ("mimpid", 0xf13),
+    ("mhartid", 0xf14),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the line, column and message of every error
+    fn errors(source: &str) -> Vec<(usize, usize, String)> {
+        match assemble(source, "test.s", &AssemblerOptions::new()) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message))
+                .collect(),
+        }
+    }
+
+    fn assembled(source: &str) -> Assembly {
+        match assemble(source, "test.s", &AssemblerOptions::new()) {
+            Ok(assembly) => assembly,
+            Err(diagnostics) => panic!("{}", diagnostics[0]),
+        }
+    }
+
+    #[test]
+    fn entry_is_start_or_the_start_of_text() {
+        let assembly = assembled("    nop\n_start:\n    nop\n");
+        assert_eq!((assembly.address, assembly.entry), (0, 4));
+        let assembly = assembled("    nop\n");
+        assert_eq!((assembly.address, assembly.entry), (0, 0));
+    }
+
+    #[test]
+    fn sections_are_laid_out_from_the_base() {
+        let mut options = AssemblerOptions::new();
+        options.base = 0x0040_0000;
+        let source = "_start:\n    nop\n.data\nvalue:\n    .word value, _start\n";
+        let assembly = match assemble(source, "test.s", &options) {
+            Ok(assembly) => assembly,
+            Err(_) => panic!("Could not assemble"),
+        };
+        assert_eq!(
+            (assembly.address, assembly.entry),
+            (0x0040_0000, 0x0040_0000)
+        );
+        // the image starts at the base, labels hold absolute addresses
+        assert_eq!(assembly.image.len(), 12);
+        assert_eq!(&assembly.image[4..8], &0x0040_0004u32.to_le_bytes());
+        assert_eq!(&assembly.image[8..12], &0x0040_0000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn sections_past_the_address_space_are_an_error() {
+        let mut options = AssemblerOptions::new();
+        options.base = 0xffff_fffc;
+        assert!(assemble("    nop\n", "test.s", &options).is_ok());
+        let diagnostics = match assemble("    nop\n    nop\n", "test.s", &options) {
+            Ok(_) => panic!("Assembled past the address space"),
+            Err(diagnostics) => diagnostics,
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("address space"));
+    }
+
+    #[test]
+    fn numeric_labels_refer_to_the_closest_definition() {
+        let source = "1:
+    j 1f
+    nop
+1:
+    j 1b
+    j 1b
+1:
+    beq a0, a1, 1b
+";
+        let words = assembled(source)
+            .image
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect::<Vec<u32>>();
+        assert_eq!(
+            words,
+            [0x0080006f, 0x00000013, 0x0000006f, 0xffdff06f, 0x00b50063]
+        );
+    }
+
+    #[test]
+    fn rejects_numeric_labels_without_a_definition() {
+        assert_eq!(
+            errors("    j 2b\n2:\n    j 2f\n"),
+            [
+                (1, 7, "No previous definition of 2: for 2b".to_string()),
+                (3, 7, "No following definition of 2: for 2f".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_immediates_at_the_ends_of_their_ranges() {
+        let source = "start:
+    addi a0, a0, -2048
+    sw a0, 2047(sp)
+    slli a0, a0, 31
+    csrrwi a0, mstatus, 31
+    lui a0, 0xfffff
+    beq a0, a1, start
+    .byte -128, 255
+    .half -32768, 65535
+";
+        assert!(errors(source).is_empty());
+    }
+
+    #[test]
+    fn rejects_immediates_out_of_range() {
+        let source = "    addi a0, a0, 2048
+    lw a0, -2049(sp)
+    srai a0, a0, 32
+    csrrsi a0, mstatus, 32
+    auipc a0, 0x100000
+";
+        let errors = errors(source);
+        let positions = errors
+            .iter()
+            .map(|&(line, column, _)| (line, column))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [(1, 18), (2, 12), (3, 18), (4, 25), (5, 15)]);
+        assert_eq!(
+            errors[0].2,
+            "Immediate 2048 is not between -2048 and 2047: 2048"
+        );
+    }
+
+    #[test]
+    fn rejects_odd_and_distant_branch_targets() {
+        let errors = errors("    beq a0, a1, 3\n    bne a0, a1, 4096\n    jal ra, 0x100000\n");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].2, "Target offset 3 is odd: 3");
+        assert!(errors[1].2.starts_with("Target is out of range"));
+        assert!(errors[2].2.starts_with("Target is out of range"));
+    }
+
+    #[test]
+    fn rejects_data_that_does_not_fit() {
+        let errors = errors("    .byte 256\n    .half -32769\n");
+        assert_eq!(
+            errors[0],
+            (1, 11, "Value 256 does not fit in .byte: 256".to_string())
+        );
+        assert_eq!(errors[1].0, 2);
+    }
+
+    #[test]
+    fn rejects_misaligned_instructions() {
+        let errors = errors("    .byte 1\n    nop\n");
+        assert_eq!(
+            errors,
+            [(
+                2,
+                5,
+                "Instruction at 0x1 is not aligned to 4 bytes, add .align 2 before it".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use super::diagnostic::Span;
+use super::expression::{self, Expr};
+use super::lexer::{parse_string, Token, TokenKind};
+use super::{str_is_in_list, REGISTERS, REGISTERS_INDEX};
+
+// a label, mnemonic or operand with where it came from
+#[derive(Clone)]
+pub struct Word {
+    pub text: String,
+    pub span: Span,
+    pub tokens: Vec<Token>,
+}
+
+// what an operand is, read once by the parser
+#[derive(Clone)]
+pub enum Node {
+    // the register number, like 10 for a0
+    Register(u32),
+    Expression(Expr),
+    // offset(base), the offset is 0 when left out
+    Memory {
+        offset: Box<Operand>,
+        base: Box<Operand>,
+    },
+    // the bytes of a string literal
+    String(Vec<u8>),
+    // not a register, memory operand, string or expression, with why
+    Invalid(String),
+}
+
+#[derive(Clone)]
+pub struct Operand {
+    pub word: Word,
+    pub node: Node,
+}
+
+// operands made up by the assembler, like those of an expanded pseudo-instruction
+impl Operand {
+    fn new(text: String, node: Node) -> Operand {
+        Operand {
+            word: Word {
+                text,
+                span: Span::default(),
+                tokens: Vec::new(),
+            },
+            node,
+        }
+    }
+    pub fn register(name: &str) -> Operand {
+        let number = register_number(name).unwrap();
+        Operand::new(name.to_string(), Node::Register(number))
+    }
+    pub fn number(number: i64) -> Operand {
+        Operand::new(number.to_string(), Node::Expression(Expr::Number(number)))
+    }
+    pub fn memory(offset: i64, base: &Operand) -> Operand {
+        let node = Node::Memory {
+            offset: Box::new(Operand::number(offset)),
+            base: Box::new(base.clone()),
+        };
+        Operand::new(format!("{}({})", offset, base.word.text), node)
+    }
+}
+
+#[derive(Clone)]
+pub struct Statement {
+    pub span: Span,
+    pub labels: Vec<Word>,
+    pub mnemonic: Option<Word>,
+    pub operands: Vec<Operand>,
+    // macro bodies keep the tokens and substitute arguments into them
+    pub tokens: Vec<Token>,
+}
+
+impl Statement {
+    // the statement written back out, for the listing
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for label in &self.labels {
+            text.push_str(&label.text);
+            text.push_str(": ");
+        }
+        if let Some(mnemonic) = &self.mnemonic {
+            text.push_str(&mnemonic.text);
+        }
+        let operands = self
+            .operands
+            .iter()
+            .map(|operand| operand.word.text.as_str())
+            .collect::<Vec<&str>>();
+        if !operands.is_empty() {
+            text.push(' ');
+            text.push_str(&operands.join(", "));
+        }
+        text.trim_end().to_string()
+    }
+}
+
+fn register_number(name: &str) -> Option<u32> {
+    str_is_in_list(REGISTERS, name).map(|index| REGISTERS_INDEX[index])
+}
+
+// covers the tokens as long as they stay on the first one's line
+fn span_of(tokens: &[Token]) -> Span {
+    let first = tokens[0].span;
+    let last = tokens[tokens.len() - 1].span;
+    if first.line != last.line {
+        return first;
+    }
+    Span {
+        length: last.column + last.length - first.column,
+        ..first
+    }
+}
+
+// tokens keep a single space where the source had whitespace or a comment
+pub fn text_of(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && token.spaced {
+            text.push(' ');
+        }
+        text.push_str(&token.text);
+    }
+    text
+}
+
+fn word(tokens: &[Token]) -> Word {
+    Word {
+        text: text_of(tokens),
+        span: span_of(tokens),
+        tokens: tokens.to_vec(),
+    }
+}
+
+fn is_operator(token: &Token, operator: &str) -> bool {
+    token.kind == TokenKind::Operator && token.text == operator
+}
+
+fn register_token(token: &Token) -> Option<u32> {
+    match token.kind {
+        TokenKind::Identifier => register_number(&token.text),
+        _ => None,
+    }
+}
+
+// an operand that is not valid only fails when it is used, directives take names too
+fn node(tokens: &[Token], text: &str) -> Node {
+    match tokens {
+        [token] if token.kind == TokenKind::String => match parse_string(&token.text) {
+            Some(bytes) => Node::String(bytes),
+            None => Node::Invalid(format!("Invalid escape sequence in string: {}", text)),
+        },
+        [token] if register_token(token).is_some() => {
+            Node::Register(register_token(token).unwrap())
+        }
+        // the offset can hold parentheses itself, like %lo(x)(a0)
+        [offset @ .., open, base, close]
+            if is_operator(open, "(")
+                && is_operator(close, ")")
+                && register_token(base).is_some() =>
+        {
+            let offset = match offset {
+                [] => Operand::number(0),
+                _ => operand(offset),
+            };
+            Node::Memory {
+                offset: Box::new(offset),
+                base: Box::new(operand(std::slice::from_ref(base))),
+            }
+        }
+        _ => match expression::parse(tokens, text) {
+            Ok(expression) => Node::Expression(expression),
+            Err(message) => Node::Invalid(message),
+        },
+    }
+}
+
+fn operand(tokens: &[Token]) -> Operand {
+    let word = word(tokens);
+    let node = node(tokens, &word.text);
+    Operand { word, node }
+}
+
+// commas inside parentheses do not separate operands
+fn split_operands(tokens: &[Token], errors: &mut Vec<(Span, String)>) -> Vec<Operand> {
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match (token.kind, token.text.as_str()) {
+            (TokenKind::Operator, "(") => depth += 1,
+            (TokenKind::Operator, ")") => depth -= 1,
+            (TokenKind::Comma, _) if depth <= 0 => {
+                if start == i {
+                    errors.push((token.span, "Missing operand before ','".to_string()));
+                } else {
+                    operands.push(operand(&tokens[start..i]));
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        operands.push(operand(&tokens[start..]));
+    } else if let Some(comma) = tokens.last() {
+        errors.push((comma.span, "Missing operand after ','".to_string()));
+    }
+    operands
+}
+
+fn statement(tokens: &[Token], errors: &mut Vec<(Span, String)>) -> Statement {
+    let mut labels = Vec::new();
+    let mut rest = tokens;
+    while let [label, colon, after @ ..] = rest {
+        let is_label = matches!(label.kind, TokenKind::Identifier | TokenKind::Number);
+        if !is_label || colon.kind != TokenKind::Colon {
+            break;
+        }
+        labels.push(word(std::slice::from_ref(label)));
+        rest = after;
+    }
+    let mut statement = Statement {
+        span: span_of(tokens),
+        labels,
+        mnemonic: None,
+        operands: Vec::new(),
+        tokens: tokens.to_vec(),
+    };
+    match rest {
+        [] => {}
+        [mnemonic, operands @ ..] if mnemonic.kind == TokenKind::Identifier => {
+            statement.mnemonic = Some(word(std::slice::from_ref(mnemonic)));
+            statement.operands = split_operands(operands, errors);
+        }
+        [unexpected, ..] => errors.push((
+            unexpected.span,
+            format!(
+                "Expected an instruction or directive, found '{}'",
+                unexpected.text
+            ),
+        )),
+    }
+    statement
+}
+
+// one statement per line or per ; separated part
+pub fn parse(tokens: &[Token]) -> (Vec<Statement>, Vec<(Span, String)>) {
+    let mut errors = Vec::new();
+    let statements = tokens
+        .split(|token| token.kind == TokenKind::Separator)
+        .filter(|tokens| !tokens.is_empty())
+        .map(|tokens| statement(tokens, &mut errors))
+        .collect();
+    (statements, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::lex;
+    use super::*;
+
+    fn statements(source: &str) -> Vec<String> {
+        let (tokens, _) = lex(source, 0);
+        let (statements, errors) = parse(&tokens);
+        assert!(errors.is_empty());
+        statements.iter().map(Statement::text).collect()
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        let (tokens, _) = lex(source, 0);
+        parse(&tokens)
+            .1
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    #[test]
+    fn semicolons_separate_statements() {
+        assert_eq!(
+            statements("loop: addi a0, a0, -1; bnez a0, loop;; ret\n\n"),
+            ["loop: addi a0, a0, -1", "bnez a0, loop", "ret"]
+        );
+    }
+
+    #[test]
+    fn reads_labels_mnemonics_and_operands() {
+        let (tokens, _) = lex("1: start: lw a0, %lo(x + 4)(a1)", 0);
+        let (statements, _) = parse(&tokens);
+        let statement = &statements[0];
+        let labels = statement
+            .labels
+            .iter()
+            .map(|label| label.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(labels, ["1", "start"]);
+        assert_eq!(statement.mnemonic.as_ref().unwrap().text, "lw");
+        let operands = statement
+            .operands
+            .iter()
+            .map(|operand| operand.word.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(operands, ["a0", "%lo(x + 4)(a1)"]);
+        assert_eq!(statement.operands[1].word.span.column, 18);
+        assert_eq!(statement.operands[1].word.span.length, 14);
+    }
+
+    fn nodes(source: &str) -> Vec<Node> {
+        let (tokens, _) = lex(source, 0);
+        let (mut statements, _) = parse(&tokens);
+        statements
+            .remove(0)
+            .operands
+            .into_iter()
+            .map(|operand| operand.node)
+            .collect()
+    }
+
+    #[test]
+    fn reads_operands_into_nodes() {
+        let symbol = |name: &str| Box::new(Expr::Symbol(name.to_string()));
+        match nodes("sw t0, %lo(x + 4)(a1)").as_slice() {
+            [Node::Register(5), Node::Memory { offset, base }] => {
+                let relocation = Expr::Relocation(
+                    "lo".to_string(),
+                    Box::new(Expr::Binary("+", symbol("x"), Box::new(Expr::Number(4)))),
+                );
+                assert!(matches!(&offset.node, Node::Expression(e) if *e == relocation));
+                assert!(matches!(base.node, Node::Register(11)));
+            }
+            _ => panic!("expected a register and a memory operand"),
+        }
+        match nodes("lw a0, (sp)").as_slice() {
+            [_, Node::Memory { offset, .. }] => {
+                assert!(matches!(&offset.node, Node::Expression(Expr::Number(0))))
+            }
+            _ => panic!("expected a memory operand"),
+        }
+        match nodes(".ascii \"a\\n\", 2 * (3 + y), 1 +").as_slice() {
+            [Node::String(bytes), Node::Expression(Expr::Binary("*", _, _)), Node::Invalid(message)] =>
+            {
+                assert_eq!(bytes, b"a\n");
+                assert_eq!(message, "Unexpected end of expression: 1 +");
+            }
+            _ => panic!("expected a string, an expression and an invalid operand"),
+        }
+    }
+
+    #[test]
+    fn strings_and_comments_do_not_split_operands() {
+        assert_eq!(
+            statements(".ascii \"a, b; c\", \"d\" # e, f"),
+            [".ascii \"a, b; c\", \"d\""]
+        );
+        assert_eq!(
+            statements("add a0, /* x, y */ a1, a2 // z; w"),
+            ["add a0, a1, a2"]
+        );
+    }
+
+    #[test]
+    fn reports_missing_operands_and_stray_tokens() {
+        assert_eq!(errors("add a0,, a1"), ["Missing operand before ','"]);
+        assert_eq!(errors("add a0, a1,"), ["Missing operand after ','"]);
+        assert_eq!(
+            errors("label: , nop"),
+            ["Expected an instruction or directive, found ','"]
+        );
+    }
+}
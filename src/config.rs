@@ -0,0 +1,149 @@
+pub const MEMORY_BASE: u32 = 0;
+pub const MEMORY_SIZE: usize = 0xfffff;
+
+// the default memory configuration of RARS, .data starts at 0x10010000
+pub const RARS_TEXT_BASE: u32 = 0x0040_0000;
+pub const RARS_DATA_BASE: u32 = 0x1001_0000;
+pub const RARS_GLOBAL_POINTER: u32 = 0x1000_8000;
+pub const RARS_STACK_POINTER: u32 = 0x7fff_effc;
+const RARS_MEMORY_END: u64 = 0x8000_0000;
+
+// room left above the initial stack, the device tree goes there
+const STACK_TOP_RESERVED: u32 = 0x8000;
+
+#[derive(Clone)]
+pub struct MachineConfig {
+    pub ram_base: u32,
+    pub ram_size: usize,
+    pub reset_pc: Option<u32>,
+    pub stack_pointer: Option<u32>,
+    pub global_pointer: Option<u32>,
+    // seed used to fill the registers at reset instead of zeroing them
+    pub random_registers: Option<u64>,
+}
+
+impl MachineConfig {
+    pub fn new() -> MachineConfig {
+        MachineConfig {
+            ram_base: MEMORY_BASE,
+            ram_size: MEMORY_SIZE,
+            reset_pc: None,
+            stack_pointer: None,
+            global_pointer: None,
+            random_registers: None,
+        }
+    }
+    // memory reaches from .text to the stack, pages are only backed once used
+    pub fn rars() -> MachineConfig {
+        MachineConfig::new()
+            .ram_base(RARS_TEXT_BASE)
+            .ram_size((RARS_MEMORY_END - RARS_TEXT_BASE as u64) as usize)
+            .stack_pointer(RARS_STACK_POINTER)
+            .global_pointer(RARS_GLOBAL_POINTER)
+    }
+    pub fn ram_base(mut self, ram_base: u32) -> MachineConfig {
+        self.ram_base = ram_base;
+        self
+    }
+    pub fn ram_size(mut self, ram_size: usize) -> MachineConfig {
+        self.ram_size = ram_size;
+        self
+    }
+    pub fn reset_pc(mut self, reset_pc: u32) -> MachineConfig {
+        self.reset_pc = Some(reset_pc);
+        self
+    }
+    pub fn stack_pointer(mut self, stack_pointer: u32) -> MachineConfig {
+        self.stack_pointer = Some(stack_pointer);
+        self
+    }
+    pub fn global_pointer(mut self, global_pointer: u32) -> MachineConfig {
+        self.global_pointer = Some(global_pointer);
+        self
+    }
+    pub fn random_registers(mut self, seed: u64) -> MachineConfig {
+        self.random_registers = Some(seed);
+        self
+    }
+    // memory has to be there and end within the 32-bit address space
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ram_size == 0 {
+            return Err("Memory size must not be zero".to_string());
+        }
+        if self.ram_end() > 1 << 32 {
+            return Err(format!(
+                "0x{:X} bytes of memory at 0x{:X} go past the end of the address space",
+                self.ram_size, self.ram_base
+            ));
+        }
+        Ok(())
+    }
+    pub fn ram_end(&self) -> u64 {
+        self.ram_base as u64 + self.ram_size as u64
+    }
+    pub fn initial_pc(&self) -> u32 {
+        self.reset_pc.unwrap_or(self.ram_base)
+    }
+    pub fn initial_sp(&self) -> u32 {
+        self.stack_pointer.unwrap_or_else(|| {
+            let top = (self.ram_end() + 0xf) & !0xf;
+            top.saturating_sub(STACK_TOP_RESERVED as u64) as u32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_start_at_memory_base() {
+        let config = MachineConfig::new();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.initial_pc(), MEMORY_BASE);
+        assert_eq!(config.ram_end(), MEMORY_BASE as u64 + MEMORY_SIZE as u64);
+        // aligned to 16 bytes and leaving room for the device tree
+        assert_eq!(config.initial_sp(), 0x100000 - STACK_TOP_RESERVED);
+    }
+
+    #[test]
+    fn explicit_values_override_the_defaults() {
+        let config = MachineConfig::new()
+            .ram_base(0x8000_0000)
+            .ram_size(0x1000_0000)
+            .reset_pc(0x8000_1000)
+            .stack_pointer(0x8100_0000);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.initial_pc(), 0x8000_1000);
+        assert_eq!(config.initial_sp(), 0x8100_0000);
+    }
+
+    #[test]
+    fn memory_may_end_at_the_top_of_the_address_space() {
+        let config = MachineConfig::new().ram_base(0xffff_0000).ram_size(0x10000);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.initial_sp(), 0u32.wrapping_sub(STACK_TOP_RESERVED));
+    }
+
+    #[test]
+    fn rars_layout_holds_text_data_and_stack() {
+        let config = MachineConfig::rars();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.initial_pc(), RARS_TEXT_BASE);
+        assert_eq!(config.initial_sp(), RARS_STACK_POINTER);
+        assert_eq!(config.global_pointer, Some(RARS_GLOBAL_POINTER));
+        assert!(config.ram_base <= RARS_DATA_BASE);
+        assert!(config.ram_end() > RARS_STACK_POINTER as u64 + 4);
+    }
+
+    #[test]
+    fn rejects_empty_memory() {
+        assert!(MachineConfig::new().ram_size(0).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_memory_past_the_address_space() {
+        let config = MachineConfig::new().ram_base(0xffff_0000).ram_size(0x10001);
+        assert!(config.validate().is_err());
+    }
+}
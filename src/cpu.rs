@@ -1,37 +1,120 @@
-use std::convert::TryInto;
-
+use config::MachineConfig;
+use devices::{Device, MachineEvent, MappedDevice};
+use elf::ElfFile;
+use guest_fs::GuestFs;
+use image::Image;
 use imm_enc_dec::sign_extend;
 use instructions;
+use rng::XorShift;
+use semihosting::{Semihosting, SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT};
+use std::mem;
+use symbols::SymbolMap;
+use syscalls::EcallMode;
 use types::*;
 
-const MEMORY_SIZE: usize = 0xfffff;
-const STACK_POINTER: u32 = 0xf8000;
+// why run and run_until returned, they can be called again to resume
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Ended,
+    Reached,
+    Breakpoint(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Privilege {
+    User,
+    Supervisor,
+    Machine,
+}
 
 pub struct RiscvCpu {
     pub csrs: Vec<u64>,
     pub memory: Vec<u8>,
     pub registers: Vec<u32>,
     pub program_counter: u32,
+    pub devices: Vec<MappedDevice>,
+    pub instret: u64,
+    pub config: MachineConfig,
+    pub symbols: SymbolMap,
+    pub breakpoints: Vec<u32>,
+    // where run_to returned for a breakpoint or location
+    stopped_at: Option<u32>,
+    pub trace: bool,
+    pub ecall: EcallMode,
+    pub semihosting: Option<Semihosting>,
+    pub filesystem: GuestFs,
+    pub privilege: Privilege,
+    // supervisor timer compare, set through SBI
+    pub timer_compare: Option<u64>,
+    pub exit_code: Option<i32>,
+    // first address past the loaded program, where the heap starts
+    pub image_end: u32,
 }
 
 impl RiscvCpu {
-    pub fn new() -> RiscvCpu {
+    pub fn with_config(config: MachineConfig) -> RiscvCpu {
         RiscvCpu {
-            program_counter: 0,
+            program_counter: config.initial_pc(),
             csrs: vec![0u64; 4096],
             registers: vec![0u32; 32],
-            memory: vec![0u8; MEMORY_SIZE],
+            memory: vec![0u8; config.ram_size],
+            devices: Vec::new(),
+            instret: 0,
+            config,
+            symbols: SymbolMap::new(),
+            breakpoints: Vec::new(),
+            stopped_at: None,
+            trace: false,
+            ecall: EcallMode::None,
+            semihosting: None,
+            filesystem: GuestFs::new(),
+            privilege: Privilege::Machine,
+            timer_compare: None,
+            exit_code: None,
+            image_end: 0,
         }
     }
     pub fn reset(&mut self) {
-        self.program_counter = 0;
+        self.program_counter = self.config.initial_pc();
+        self.instret = 0;
+        self.stopped_at = None;
+        self.exit_code = None;
+        self.image_end = 0;
+        self.privilege = Privilege::Machine;
+        self.timer_compare = None;
         self.csrs.iter_mut().for_each(|csr| *csr = 0);
-        self.memory.iter_mut().for_each(|mem| *mem = 0);
-        self.registers.iter_mut().for_each(|reg| *reg = 0);
-        self.set_register(2, STACK_POINTER);
+        // a fresh allocation is zeroed lazily, large memories stay cheap
+        self.memory = vec![0u8; self.config.ram_size];
+        match self.config.random_registers {
+            Some(seed) => {
+                let mut rng = XorShift::new(seed);
+                self.registers
+                    .iter_mut()
+                    .for_each(|reg| *reg = rng.next_u32());
+                self.set_register(R_ZERO, 0);
+            }
+            None => self.registers.iter_mut().for_each(|reg| *reg = 0),
+        }
+        self.set_register(R_SP, self.config.initial_sp());
+        if let Some(global_pointer) = self.config.global_pointer {
+            self.set_register(R_GP, global_pointer);
+        }
+    }
+    // "0x0000101C <main+0x1c>" when the address has a symbol
+    pub fn describe_address(&self, address: u32) -> String {
+        match self.symbols.describe(address) {
+            Some(symbol) => format!("0x{:08X} <{}>", address, symbol),
+            None => format!("0x{:08X}", address),
+        }
+    }
+    pub fn location(&self) -> String {
+        format!("pc {}", self.describe_address(self.program_counter))
     }
     pub fn dump_registers(&self) {
-        println!("\npc: {0} 0x{0:X}", self.program_counter);
+        match self.symbols.describe(self.program_counter) {
+            Some(symbol) => println!("\npc: {0} 0x{0:X} <{1}>", self.program_counter, symbol),
+            None => println!("\npc: {0} 0x{0:X}", self.program_counter),
+        }
         self.registers.iter().enumerate().for_each(|(i, reg)| {
             println!(
                 "x{0:<2} {1:>5}: 0x{2:08X} {2:>10} {3:>11}",
@@ -39,45 +122,229 @@ impl RiscvCpu {
             );
         });
     }
+    pub fn attach_device(&mut self, base: u32, device: Box<dyn Device>) -> Result<(), String> {
+        let end = base as u64 + device.size() as u64;
+        if (base as u64) < self.config.ram_end() && end > self.config.ram_base as u64 {
+            return Err(format!(
+                "{} at 0x{:X}..0x{:X} overlaps memory at 0x{:X}..0x{:X}",
+                device.name(),
+                base,
+                end,
+                self.config.ram_base,
+                self.config.ram_end()
+            ));
+        }
+        if let Some(other) = self.devices.iter().find(|other| {
+            (base as u64) < other.base as u64 + other.device.size() as u64
+                && end > other.base as u64
+        }) {
+            return Err(format!(
+                "{} at 0x{:X} overlaps {} at 0x{:X}",
+                device.name(),
+                base,
+                other.device.name(),
+                other.base
+            ));
+        }
+        self.devices.push(MappedDevice { base, device });
+        Ok(())
+    }
+    fn notify_devices(&mut self, event: MachineEvent) {
+        self.devices
+            .iter_mut()
+            .for_each(|mapped| mapped.device.event(&event));
+    }
+    fn update_interrupts(&mut self) {
+        // the SBI firmware passes device interrupts on to the supervisor
+        let external = match self.ecall {
+            EcallMode::Sbi(_) => MIP_SEIP,
+            _ => MIP_MEIP,
+        };
+        if self
+            .devices
+            .iter()
+            .any(|mapped| mapped.device.interrupt_pending())
+        {
+            self.csrs[CSR_MIP] |= external;
+        } else {
+            self.csrs[CSR_MIP] &= !external;
+        }
+        self.csrs[CSR_TIME] = self.instret & 0xffff_ffff;
+        self.csrs[CSR_TIMEH] = self.instret >> 32;
+        if self
+            .timer_compare
+            .is_some_and(|compare| self.instret >= compare)
+        {
+            self.csrs[CSR_MIP] |= MIP_STIP;
+        }
+    }
+    fn read_memory(&mut self, address: usize, size: usize) -> u32 {
+        let offset = (address as u32).wrapping_sub(self.config.ram_base) as usize;
+        if let Some(access) = self.memory.get(offset..offset + size) {
+            let mut bytes = [0u8; 4];
+            bytes[..size].copy_from_slice(access);
+            return u32::from_le_bytes(bytes);
+        }
+        match self
+            .devices
+            .iter_mut()
+            .find(|mapped| mapped.contains(address as u32, size as u32))
+        {
+            Some(mapped) => mapped
+                .device
+                .read(address as u32 - mapped.base, size as u32),
+            None => panic!(
+                "Memory read out of bounds 0x{:X} at {}",
+                address,
+                self.location()
+            ),
+        }
+    }
+    fn write_memory(&mut self, address: usize, size: usize, value: u32) {
+        let offset = (address as u32).wrapping_sub(self.config.ram_base) as usize;
+        if let Some(access) = self.memory.get_mut(offset..offset + size) {
+            access.copy_from_slice(&value.to_le_bytes()[..size]);
+            return;
+        }
+        match self
+            .devices
+            .iter_mut()
+            .find(|mapped| mapped.contains(address as u32, size as u32))
+        {
+            Some(mapped) => mapped
+                .device
+                .write(address as u32 - mapped.base, size as u32, value),
+            None => panic!(
+                "Memory write out of bounds 0x{:X} at {}",
+                address,
+                self.location()
+            ),
+        }
+    }
+    fn write_u8_memory(&mut self, address: usize, value: u8) {
+        self.write_memory(address, 1, value as u32);
+    }
+    fn read_u8_memory(&mut self, address: usize) -> u8 {
+        self.read_memory(address, 1) as u8
+    }
     fn write_u16_memory(&mut self, address: usize, value: u16) {
-        let access = self
-            .memory
-            .get_mut(address..address + 2)
-            .expect("Memory write out of bounds");
-        access.copy_from_slice(&value.to_le_bytes());
+        self.write_memory(address, 2, value as u32);
     }
-    fn read_u16_memory(&self, address: usize) -> u16 {
-        let access = self
-            .memory
-            .get(address..address + 2)
-            .expect("Memory read out of bounds");
-        u16::from_le_bytes(access.try_into().unwrap())
+    fn read_u16_memory(&mut self, address: usize) -> u16 {
+        self.read_memory(address, 2) as u16
     }
     fn write_u32_memory(&mut self, address: usize, value: u32) {
-        let access = self
-            .memory
-            .get_mut(address..address + 4)
-            .expect("Memory write out of bounds");
-        access.copy_from_slice(&value.to_le_bytes());
+        self.write_memory(address, 4, value);
+    }
+    fn read_u32_memory(&mut self, address: usize) -> u32 {
+        self.read_memory(address, 4)
+    }
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), String> {
+        let offset = address.wrapping_sub(self.config.ram_base) as usize;
+        match self.memory.get_mut(offset..offset + data.len()) {
+            Some(access) => {
+                access.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(format!(
+                "0x{:X} bytes at 0x{:X} do not fit in memory",
+                data.len(),
+                address
+            )),
+        }
+    }
+    // how many bytes of memory there are from address on, None outside of memory
+    pub fn memory_after(&self, address: u32) -> Option<usize> {
+        let offset = address.wrapping_sub(self.config.ram_base) as usize;
+        self.memory.len().checked_sub(offset)
     }
-    fn read_u32_memory(&self, address: usize) -> u32 {
-        let access = self
+    pub fn read_bytes(&self, address: u32, size: usize) -> Result<&[u8], String> {
+        let offset = address.wrapping_sub(self.config.ram_base) as usize;
+        self.memory
+            .get(offset..offset.saturating_add(size))
+            .ok_or_else(|| format!("0x{:X} bytes at 0x{:X} are not in memory", size, address))
+    }
+    // the bytes of a NUL terminated string, without the terminator
+    pub fn read_c_bytes(&self, address: u32) -> Result<&[u8], String> {
+        let offset = address.wrapping_sub(self.config.ram_base) as usize;
+        let bytes = self
             .memory
-            .get(address..address + 4)
-            .expect("Memory read out of bounds");
-        u32::from_le_bytes(access.try_into().unwrap())
+            .get(offset..)
+            .ok_or_else(|| format!("String at 0x{:X} is not in memory", address))?;
+        match bytes.iter().position(|&byte| byte == 0) {
+            Some(len) => Ok(&bytes[..len]),
+            None => Err(format!("String at 0x{:X} is not terminated", address)),
+        }
     }
-    pub fn load_from_u8(&mut self, inst_list: &[u8]) {
+    pub fn load_elf(&mut self, elf: &ElfFile) -> Result<(), String> {
+        println!("Loading ELF file");
+        self.reset();
+        for segment in &elf.segments {
+            self.write_bytes(segment.address, &segment.data)?;
+            let bss_size = segment.mem_size as usize - segment.data.len();
+            let bss_address = segment.address.wrapping_add(segment.data.len() as u32);
+            self.write_bytes(bss_address, &vec![0u8; bss_size])?;
+            self.image_end = self
+                .image_end
+                .max(segment.address.wrapping_add(segment.mem_size));
+        }
+        self.program_counter = elf.entry;
+        self.symbols = SymbolMap::new();
+        elf.symbols.iter().for_each(|symbol| {
+            self.symbols
+                .insert(&symbol.name, symbol.address, symbol.size)
+        });
+        println!("Finished Loading ELF file");
+        Ok(())
+    }
+    // the sentinel after the image stops the run loop
+    pub fn load_image(&mut self, image: &Image, sentinel: bool) -> Result<(), String> {
         println!("Loading file");
         self.reset();
-        self.memory[..inst_list.len()].copy_from_slice(inst_list);
-        self.write_u32_memory(inst_list.len(), 0xDEADC0DE);
+        for (i, segment) in image.segments.iter().enumerate() {
+            if let Some(other) = image.segments[..i]
+                .iter()
+                .find(|other| segment.overlaps(other))
+            {
+                return Err(format!(
+                    "Data at 0x{:X} overlaps data at 0x{:X}",
+                    segment.address, other.address
+                ));
+            }
+            self.write_bytes(segment.address, &segment.data)?;
+        }
+        let end = image.end();
+        if sentinel && end + 4 > u32::MAX as u64 {
+            return Err(format!(
+                "There is no room for the end marker after the image at 0x{:X}",
+                end
+            ));
+        }
+        self.image_end = end.min(u32::MAX as u64) as u32;
+        if sentinel {
+            self.write_bytes(self.image_end, &0xDEADC0DEu32.to_le_bytes())?;
+            self.image_end += 4;
+        }
+        if let Some(entry) = image.entry {
+            self.program_counter = entry;
+        }
         println!("Finished Loading file");
+        Ok(())
+    }
+    // the ebreak at pc is wrapped in the semihosting entry and exit nops
+    fn is_semihosting_call(&self) -> bool {
+        let word = |address: u32| {
+            self.read_bytes(address, 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        self.semihosting.is_some()
+            && word(self.program_counter.wrapping_sub(4)) == Ok(SEMIHOSTING_ENTRY)
+            && word(self.program_counter.wrapping_add(4)) == Ok(SEMIHOSTING_EXIT)
     }
     pub fn get_register(&self, index: u32) -> u32 {
         self.registers[index as usize]
     }
-    fn set_register(&mut self, index: u32, value: u32) {
+    pub fn set_register(&mut self, index: u32, value: u32) {
         self.registers[index as usize] = value;
     }
     fn execute_opcode_branch(&mut self, bits: u32) {
@@ -146,7 +413,7 @@ impl RiscvCpu {
                     self.program_counter += 4;
                 }
             }
-            _ => panic!("Unimplemented B funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     fn execute_opcode_load(&mut self, bits: u32) {
@@ -158,7 +425,7 @@ impl RiscvCpu {
                 let address = self
                     .get_register(inst_i.rs1())
                     .wrapping_add(inst_i.imm_dec() as u32);
-                let value = self.memory[address as usize];
+                let value = self.read_u8_memory(address as usize);
                 self.set_register(inst_i.rd(), sign_extend(value as u32, 8) as u32);
                 self.program_counter += 4;
             }
@@ -176,7 +443,8 @@ impl RiscvCpu {
                 let address = self
                     .get_register(inst_i.rs1())
                     .wrapping_add(inst_i.imm_dec() as u32);
-                self.set_register(inst_i.rd(), self.read_u32_memory(address as usize));
+                let value = self.read_u32_memory(address as usize);
+                self.set_register(inst_i.rd(), value);
                 self.program_counter += 4;
             }
             FUNCT3_100 => {
@@ -184,7 +452,8 @@ impl RiscvCpu {
                 let address = self
                     .get_register(inst_i.rs1())
                     .wrapping_add(inst_i.imm_dec() as u32);
-                self.set_register(inst_i.rd(), self.memory[address as usize] as u32);
+                let value = self.read_u8_memory(address as usize);
+                self.set_register(inst_i.rd(), value as u32);
                 self.program_counter += 4;
             }
             FUNCT3_101 => {
@@ -192,10 +461,11 @@ impl RiscvCpu {
                 let address = self
                     .get_register(inst_i.rs1())
                     .wrapping_add(inst_i.imm_dec() as u32);
-                self.set_register(inst_i.rd(), self.read_u16_memory(address as usize) as u32);
+                let value = self.read_u16_memory(address as usize);
+                self.set_register(inst_i.rd(), value as u32);
                 self.program_counter += 4;
             }
-            _ => panic!("Unimplemented I funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     fn execute_opcode_store(&mut self, bits: u32) {
@@ -207,7 +477,7 @@ impl RiscvCpu {
                 let address = self
                     .get_register(inst_s.rs1())
                     .wrapping_add(inst_s.imm_dec() as u32);
-                self.memory[address as usize] = self.get_register(inst_s.rs2()) as u8;
+                self.write_u8_memory(address as usize, self.get_register(inst_s.rs2()) as u8);
                 self.program_counter += 4;
             }
             FUNCT3_001 => {
@@ -226,7 +496,7 @@ impl RiscvCpu {
                 self.write_u32_memory(address as usize, self.get_register(inst_s.rs2()));
                 self.program_counter += 4;
             }
-            _ => panic!("Unimplemented S funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     fn execute_opcode_alu_and_shift_imm(&mut self, bits: u32) {
@@ -302,7 +572,7 @@ impl RiscvCpu {
                         );
                         self.program_counter += 4;
                     }
-                    _ => panic!("Unimplemented Shift 101 funct7 {}", funct7),
+                    _ => self.illegal_instruction(bits),
                 }
             }
             FUNCT3_110 => {
@@ -321,7 +591,7 @@ impl RiscvCpu {
                 );
                 self.program_counter += 4;
             }
-            _ => panic!("Unimplemented I funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     fn execute_opcode_alu_register(&mut self, bits: u32) {
@@ -350,7 +620,7 @@ impl RiscvCpu {
                         );
                         self.program_counter += 4;
                     }
-                    _ => panic!("Unimplemented R 000 funct7 {}", funct7),
+                    _ => self.illegal_instruction(bits),
                 }
             }
             FUNCT3_001 => {
@@ -413,7 +683,7 @@ impl RiscvCpu {
                         );
                         self.program_counter += 4;
                     }
-                    _ => panic!("Unimplemented R 101 funct7 {}", funct7),
+                    _ => self.illegal_instruction(bits),
                 }
             }
             FUNCT3_110 => {
@@ -432,7 +702,7 @@ impl RiscvCpu {
                 );
                 self.program_counter += 4;
             }
-            _ => panic!("Unimplemented R funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     fn execute_opcode_e_and_system(&mut self, bits: u32) {
@@ -445,63 +715,106 @@ impl RiscvCpu {
                 match imm11_0 {
                     IMM11_0_000000000000 => {
                         // ecall
-                        self.program_counter += 4;
+                        let mut ecall = mem::replace(&mut self.ecall, EcallMode::None);
+                        let handled = match &mut ecall {
+                            // without a trap handler ecall does nothing
+                            EcallMode::None => self.csrs[CSR_MTVEC] == 0,
+                            EcallMode::Linux(syscalls) => {
+                                syscalls.handle(self);
+                                true
+                            }
+                            EcallMode::Rars(services) => {
+                                services.handle(self);
+                                true
+                            }
+                            EcallMode::Sbi(firmware) if self.privilege == Privilege::Supervisor => {
+                                firmware.handle(self);
+                                true
+                            }
+                            // calls from user mode go to the supervisor
+                            EcallMode::Sbi(_) => false,
+                        };
+                        self.ecall = ecall;
+                        if handled {
+                            self.program_counter += 4;
+                        } else {
+                            let cause = self.ecall_cause();
+                            self.trap(cause, 0);
+                        }
                     }
                     IMM11_0_000000000001 => {
                         // ebreak
                         // #ifdef DEBUG
                         //     __debugbreak();
                         // #endif
-                        self.dump_registers();
+                        if self.is_semihosting_call() {
+                            let mut semihosting = self.semihosting.take();
+                            if let Some(semihosting) = semihosting.as_mut() {
+                                semihosting.handle(self);
+                            }
+                            self.semihosting = semihosting;
+                        } else {
+                            self.dump_registers();
+                            self.notify_devices(MachineEvent::Ebreak);
+                        }
+                        self.program_counter += 4;
+                    }
+                    IMM11_0_000100000010 => self.sret(bits),
+                    IMM11_0_001100000010 => self.mret(bits),
+                    IMM11_0_000100000101 => {
+                        // wfi, time only moves as instructions retire so there is nothing to wait for
                         self.program_counter += 4;
                     }
-                    _ => panic!("Unimplemented E 000 imm11_0 {}", imm11_0),
+                    _ => self.illegal_instruction(bits),
                 }
             }
             FUNCT3_001 => {
                 // csrrw
-                let oldcsr = self.csrs[inst_i.imm11_0() as usize];
-                self.csrs[inst_i.imm11_0() as usize] = self.get_register(inst_i.rs1()) as u64;
+                let oldcsr = self.read_csr(inst_i.imm11_0() as usize);
+                self.write_csr(
+                    inst_i.imm11_0() as usize,
+                    self.get_register(inst_i.rs1()) as u64,
+                );
                 self.set_register(inst_i.rd(), oldcsr as u32);
                 self.program_counter += 4;
             }
             FUNCT3_010 => {
                 // csrrs
                 let mask = self.get_register(inst_i.rs1());
-                let oldcsr = self.csrs[inst_i.imm11_0() as usize];
-                self.csrs[inst_i.imm11_0() as usize] = oldcsr | mask as u64;
+                let oldcsr = self.read_csr(inst_i.imm11_0() as usize);
+                self.write_csr(inst_i.imm11_0() as usize, oldcsr | mask as u64);
                 self.set_register(inst_i.rd(), oldcsr as u32);
                 self.program_counter += 4;
             }
             FUNCT3_011 => {
                 // csrrc
                 let mask = self.get_register(inst_i.rs1());
-                let oldcsr = self.csrs[inst_i.imm11_0() as usize];
-                self.csrs[inst_i.imm11_0() as usize] = oldcsr & !(mask as u64);
+                let oldcsr = self.read_csr(inst_i.imm11_0() as usize);
+                self.write_csr(inst_i.imm11_0() as usize, oldcsr & !(mask as u64));
                 self.set_register(inst_i.rd(), oldcsr as u32);
                 self.program_counter += 4;
             }
             FUNCT3_101 => {
                 // csrrwi
-                self.set_register(inst_i.rd(), self.csrs[inst_i.imm11_0() as usize] as u32);
-                self.csrs[inst_i.imm11_0() as usize] = inst_i.rs1() as u64;
+                self.set_register(inst_i.rd(), self.read_csr(inst_i.imm11_0() as usize) as u32);
+                self.write_csr(inst_i.imm11_0() as usize, inst_i.rs1() as u64);
                 self.program_counter += 4;
             }
             FUNCT3_110 => {
                 // csrrsi
-                let oldcsr = self.csrs[inst_i.imm11_0() as usize];
-                self.csrs[inst_i.imm11_0() as usize] = oldcsr | inst_i.rs1() as u64;
+                let oldcsr = self.read_csr(inst_i.imm11_0() as usize);
+                self.write_csr(inst_i.imm11_0() as usize, oldcsr | inst_i.rs1() as u64);
                 self.set_register(inst_i.rd(), oldcsr as u32);
                 self.program_counter += 4;
             }
             FUNCT3_111 => {
                 // csrrci
-                let oldcsr = self.csrs[inst_i.imm11_0() as usize];
-                self.csrs[inst_i.imm11_0() as usize] = oldcsr & !(inst_i.rs1() as u64);
+                let oldcsr = self.read_csr(inst_i.imm11_0() as usize);
+                self.write_csr(inst_i.imm11_0() as usize, oldcsr & !(inst_i.rs1() as u64));
                 self.set_register(inst_i.rd(), oldcsr as u32);
                 self.program_counter += 4;
             }
-            _ => panic!("Unimplemented E funct3 {}", funct3),
+            _ => self.illegal_instruction(bits),
         }
     }
     pub fn execute_inst(&mut self, bits: u32) {
@@ -552,20 +865,64 @@ impl RiscvCpu {
             OPCODE_E_AND_SYSTEM => {
                 self.execute_opcode_e_and_system(bits);
             }
-            _ => panic!("Unimplemented opcode {}", opcode),
+            _ => self.illegal_instruction(bits),
         }
     }
-    pub fn run(&mut self) {
-        println!("Running file");
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<u32, String> {
+        let address = self.symbols.resolve(location)?;
+        self.breakpoints.push(address);
+        Ok(address)
+    }
+    pub fn run_until(&mut self, location: &str) -> Result<Stop, String> {
+        let address = self.symbols.resolve(location)?;
+        Ok(self.run_to(Some(address)))
+    }
+    fn run_to(&mut self, until: Option<u32>) -> Stop {
+        // resuming goes past the location it stopped at
+        let mut resumed = self.stopped_at.take() == Some(self.program_counter);
         loop {
             self.set_register(0, 0);
+            if !resumed {
+                let stop = if Some(self.program_counter) == until {
+                    Some(Stop::Reached)
+                } else if self.breakpoints.contains(&self.program_counter) {
+                    Some(Stop::Breakpoint(self.program_counter))
+                } else {
+                    None
+                };
+                if let Some(stop) = stop {
+                    self.stopped_at = Some(self.program_counter);
+                    return stop;
+                }
+            }
+            resumed = false;
             let bits = self.read_u32_memory(self.program_counter as usize);
             if bits == 0xDEADC0DE {
-                break;
+                return Stop::Ended;
+            }
+            if self.trace {
+                println!(
+                    "{}: 0x{:08X}",
+                    self.describe_address(self.program_counter),
+                    bits
+                );
             }
             self.execute_inst(bits);
+            self.instret += 1;
+            self.notify_devices(MachineEvent::Instruction(self.instret));
+            self.update_interrupts();
+            self.take_interrupt();
+            if self.exit_code.is_some() {
+                return Stop::Ended;
+            }
         }
-        println!("Finished running file");
+    }
+    pub fn run(&mut self) -> Stop {
+        self.run_to(None)
+    }
+    // lets the devices know the program is done, like writing a final snapshot
+    pub fn finish(&mut self) {
+        self.notify_devices(MachineEvent::Exit);
     }
 }
 
@@ -574,3 +931,46 @@ const REGISTERS_DUMP: &[&str; 32] = &[
     "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
     "t5", "t6",
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nops followed by the sentinel that ends the run
+    fn cpu(nops: usize) -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        cpu.reset();
+        for i in 0..nops {
+            cpu.write_bytes(4 * i as u32, &0x13u32.to_le_bytes())
+                .unwrap();
+        }
+        cpu.write_bytes(4 * nops as u32, &0xDEADC0DEu32.to_le_bytes())
+            .unwrap();
+        cpu
+    }
+
+    #[test]
+    fn breakpoints_stop_and_resume() {
+        let mut cpu = cpu(4);
+        cpu.symbols.insert("second", 4, 4);
+        cpu.add_breakpoint("0").unwrap();
+        cpu.add_breakpoint("second+4").unwrap();
+        assert_eq!(cpu.run(), Stop::Breakpoint(0));
+        assert_eq!(cpu.instret, 0);
+        assert_eq!(cpu.run(), Stop::Breakpoint(8));
+        assert_eq!(cpu.instret, 2);
+        assert_eq!(cpu.run(), Stop::Ended);
+        assert_eq!(cpu.instret, 4);
+    }
+
+    #[test]
+    fn run_until_stops_at_the_location() {
+        let mut cpu = cpu(4);
+        cpu.add_breakpoint("4").unwrap();
+        assert_eq!(cpu.run_until("12"), Ok(Stop::Breakpoint(4)));
+        assert_eq!(cpu.run_until("12"), Ok(Stop::Reached));
+        assert_eq!(cpu.program_counter, 12);
+        assert_eq!(cpu.run_until("12"), Ok(Stop::Ended));
+        assert!(cpu.run_until("missing").is_err());
+    }
+}
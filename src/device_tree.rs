@@ -0,0 +1,242 @@
+// The tree only describes the cpu, the memory and the MMIO regions of the devices,
+// there is no interrupt controller, CLINT, UART or virtio node.
+
+use std::collections::HashMap;
+
+use cpu::RiscvCpu;
+use types::*;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const FDT_HEADER_SIZE: u32 = 40;
+// a single empty reservation entry
+const FDT_RSVMAP_SIZE: u32 = 16;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtBuilder {
+    pub fn new() -> FdtBuilder {
+        FdtBuilder {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+        }
+    }
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value = cells
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect::<Vec<u8>>();
+        self.property(name, &value);
+    }
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+    pub fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len() as u32;
+        let total_size = off_dt_strings + self.strings.len() as u32;
+
+        let mut blob = Vec::with_capacity(total_size as usize);
+        [
+            FDT_MAGIC,
+            total_size,
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpu
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ]
+        .iter()
+        .for_each(|field| blob.extend_from_slice(&field.to_be_bytes()));
+        blob.extend_from_slice(&[0u8; FDT_RSVMAP_SIZE as usize]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl RiscvCpu {
+    pub fn build_device_tree(&self) -> Vec<u8> {
+        let mut fdt = FdtBuilder::new();
+
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "risc-v_emulator-rust");
+
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", "");
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", "rv32i");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", self.config.ram_base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &[self.config.ram_base, self.memory.len() as u32]);
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+        self.devices
+            .iter()
+            .for_each(|mapped| mapped.device.device_tree(&mut fdt, mapped.base));
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+    // places the blob at the end of memory and passes it like a boot loader would
+    pub fn load_device_tree(&mut self) -> Result<u32, String> {
+        let blob = self.build_device_tree();
+        let offset = self.memory.len().checked_sub(blob.len()).ok_or_else(|| {
+            format!(
+                "The 0x{:X} byte device tree does not fit in 0x{:X} bytes of memory",
+                blob.len(),
+                self.memory.len()
+            )
+        })? & !7;
+        let address = self.config.ram_base + offset as u32;
+        if address < self.image_end {
+            return Err(format!(
+                "The device tree at 0x{:X} would overwrite the program, which ends at 0x{:X}",
+                address, self.image_end
+            ));
+        }
+        // the stack grows down from sp, so it has to start below the blob
+        let stack_pointer = self.get_register(R_SP);
+        if stack_pointer > address {
+            return Err(format!(
+                "The device tree at 0x{:X} would be overwritten by the stack at 0x{:X}, use a lower --sp",
+                address, stack_pointer
+            ));
+        }
+        self.write_bytes(address, &blob)?;
+        self.set_register(R_A0, 0);
+        self.set_register(R_A1, address);
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+
+    fn cpu(config: MachineConfig) -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(config);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn places_the_blob_above_the_default_stack() {
+        let mut cpu = cpu(MachineConfig::new().ram_base(0x8000_0000).ram_size(0x10000));
+        let address = cpu.load_device_tree().unwrap();
+        assert_eq!(address % 8, 0);
+        assert!(address >= cpu.get_register(R_SP));
+        assert_eq!(cpu.get_register(R_A1), address);
+        assert_eq!(cpu.read_bytes(address, 4), Ok(&FDT_MAGIC.to_be_bytes()[..]));
+    }
+
+    #[test]
+    fn rejects_a_stack_above_the_blob() {
+        let config = MachineConfig::new()
+            .ram_size(0x10000)
+            .stack_pointer(0x10000);
+        let err = cpu(config).load_device_tree().unwrap_err();
+        assert!(
+            err.contains("overwritten by the stack at 0x10000"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_a_blob_over_the_program() {
+        let mut cpu = cpu(MachineConfig::new().ram_size(0x10000));
+        cpu.image_end = 0x10000;
+        assert!(cpu.load_device_tree().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs::File, io::prelude::*, path::Path, path::PathBuf};
+
+use device_tree::FdtBuilder;
+use devices::{Device, MachineEvent};
+use png;
+
+pub const FRAMEBUFFER_BASE: u32 = 0x5000_0000;
+// the pixels have to fit between the base and the end of the address space
+pub const FRAMEBUFFER_MAX_SIZE: u64 = (1 << 32) - FRAMEBUFFER_BASE as u64;
+
+#[derive(Clone, Copy)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+}
+
+impl PixelFormat {
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "r5g6b5" => Some(PixelFormat::R5G6B5),
+            "r8g8b8" => Some(PixelFormat::R8G8B8),
+            "x8r8g8b8" => Some(PixelFormat::X8R8G8B8),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::R8G8B8 => "r8g8b8",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+        }
+    }
+    pub const LARGEST: PixelFormat = PixelFormat::X8R8G8B8;
+    pub fn bytes_per_pixel(&self) -> u32 {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            PixelFormat::R8G8B8 => 3,
+            PixelFormat::X8R8G8B8 => 4,
+        }
+    }
+    fn to_rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let r = ((value >> 11) & 0x1F) as u8;
+                let g = ((value >> 5) & 0x3F) as u8;
+                let b = (value & 0x1F) as u8;
+                [
+                    (r << 3) | (r >> 2),
+                    (g << 2) | (g >> 4),
+                    (b << 3) | (b >> 2),
+                ]
+            }
+            // little endian words, blue is the lowest byte
+            PixelFormat::R8G8B8 | PixelFormat::X8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum SnapshotTrigger {
+    Ebreak,
+    Exit,
+    Every(u64),
+}
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pixels: Vec<u8>,
+    snapshot: Option<(PathBuf, SnapshotTrigger)>,
+    snapshot_count: u32,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Result<Framebuffer, String> {
+        let size = Framebuffer::size_of(width, height, format)?;
+        Ok(Framebuffer {
+            width,
+            height,
+            format,
+            pixels: vec![0u8; size as usize],
+            snapshot: None,
+            snapshot_count: 0,
+        })
+    }
+    pub fn size_of(width: u32, height: u32, format: PixelFormat) -> Result<u32, String> {
+        if width == 0 || height == 0 {
+            return Err(format!("Dimensions must not be zero: {}x{}", width, height));
+        }
+        width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(format.bytes_per_pixel()))
+            .filter(|&size| size as u64 <= FRAMEBUFFER_MAX_SIZE)
+            .ok_or_else(|| {
+                format!(
+                    "{}x{} {} does not fit in the 0x{:X} bytes at 0x{:X}",
+                    width,
+                    height,
+                    format.name(),
+                    FRAMEBUFFER_MAX_SIZE,
+                    FRAMEBUFFER_BASE
+                )
+            })
+    }
+    pub fn snapshot_to(&mut self, path: &Path, trigger: SnapshotTrigger) {
+        self.snapshot = Some((path.to_path_buf(), trigger));
+    }
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixels
+            .chunks(self.format.bytes_per_pixel() as usize)
+            .flat_map(|pixel| self.format.to_rgb(pixel))
+            .collect()
+    }
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend_from_slice(&self.to_rgb());
+        ppm
+    }
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode_rgb(self.width, self.height, &self.to_rgb())
+    }
+    pub fn save_snapshot(&self, path: &Path) {
+        let is_png = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+        let image = if is_png { self.to_png() } else { self.to_ppm() };
+        let mut file = File::create(path).expect("Could not create file");
+        println!("Writing framebuffer to file: {}", path.to_str().unwrap());
+        file.write_all(&image).expect("Could not write file");
+    }
+    fn numbered_snapshot(&mut self) {
+        let path = match &self.snapshot {
+            Some((path, _)) => path.clone(),
+            None => return,
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, self.snapshot_count, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, self.snapshot_count),
+        };
+        self.snapshot_count += 1;
+        self.save_snapshot(&path.with_file_name(name));
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &str {
+        "framebuffer"
+    }
+    fn size(&self) -> u32 {
+        self.pixels.len() as u32
+    }
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes[..size as usize]
+            .copy_from_slice(&self.pixels[offset as usize..(offset + size) as usize]);
+        u32::from_le_bytes(bytes)
+    }
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        self.pixels[offset as usize..(offset + size) as usize]
+            .copy_from_slice(&value.to_le_bytes()[..size as usize]);
+    }
+    fn event(&mut self, event: &MachineEvent) {
+        match (event, &self.snapshot) {
+            (MachineEvent::Ebreak, Some((_, SnapshotTrigger::Ebreak))) => self.numbered_snapshot(),
+            (MachineEvent::Instruction(count), Some((_, SnapshotTrigger::Every(every))))
+                if *every != 0 && count % every == 0 =>
+            {
+                self.numbered_snapshot()
+            }
+            (MachineEvent::Exit, Some((path, SnapshotTrigger::Exit))) => self.save_snapshot(path),
+            _ => {}
+        }
+    }
+    fn device_tree(&self, fdt: &mut FdtBuilder, base: u32) {
+        fdt.begin_node(&format!("framebuffer@{:x}", base));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_cells("reg", &[base, self.size()]);
+        fdt.property_u32("width", self.width);
+        fdt.property_u32("height", self.height);
+        fdt.property_u32("stride", self.width * self.format.bytes_per_pixel());
+        fdt.property_string("format", self.format.name());
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_empty_and_oversized_framebuffers() {
+        assert!(Framebuffer::new(0, 480, PixelFormat::R5G6B5).is_err());
+        assert!(Framebuffer::new(640, 0, PixelFormat::R5G6B5).is_err());
+        assert!(Framebuffer::new(0x10000, 0x10000, PixelFormat::X8R8G8B8).is_err());
+        assert_eq!(
+            Framebuffer::size_of(640, 480, PixelFormat::R8G8B8),
+            Ok(640 * 480 * 3)
+        );
+    }
+
+    #[test]
+    fn converts_pixels_to_rgb() {
+        let mut framebuffer = Framebuffer::new(2, 1, PixelFormat::R5G6B5).unwrap();
+        framebuffer.write(0, 4, 0x07e0_f800);
+        assert_eq!(framebuffer.to_rgb(), [0xff, 0, 0, 0, 0xff, 0]);
+        assert!(framebuffer.to_ppm().starts_with(b"P6\n2 1\n255\n"));
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use device_tree::FdtBuilder;
+use devices::{Device, MachineEvent};
+
+// same address as the qemu virt machine
+pub const GOLDFISH_RTC_BASE: u32 = 0x0010_1000;
+
+const RTC_TIME_LOW: u32 = 0x00;
+const RTC_TIME_HIGH: u32 = 0x04;
+const RTC_ALARM_LOW: u32 = 0x08;
+const RTC_ALARM_HIGH: u32 = 0x0c;
+const RTC_IRQ_ENABLED: u32 = 0x10;
+const RTC_CLEAR_ALARM: u32 = 0x14;
+const RTC_ALARM_STATUS: u32 = 0x18;
+const RTC_CLEAR_INTERRUPT: u32 = 0x1c;
+
+// the deterministic clock runs as if every instruction took 10ns, a 100 MHz core
+const NANOS_PER_INSTRUCTION: u64 = 10;
+// the host clock is only polled for the alarm every this many instructions
+const ALARM_CHECK_INTERVAL: u64 = 1024;
+
+pub struct GoldfishRtc {
+    // None uses the host wall-clock, Some is the time in nanoseconds at the first instruction
+    epoch: Option<u64>,
+    offset: i64,
+    instructions: u64,
+    time_high: u32,
+    set_high: u32,
+    alarm_low: u32,
+    alarm_high: u32,
+    irq_enabled: u32,
+    // 1 while an alarm is armed and has not fired yet
+    alarm_status: u32,
+    // raised when the alarm fires with the interrupt enabled, until cleared
+    interrupt: bool,
+}
+
+impl GoldfishRtc {
+    pub fn new(epoch: Option<u64>) -> GoldfishRtc {
+        GoldfishRtc {
+            epoch,
+            offset: 0,
+            instructions: 0,
+            time_high: 0,
+            set_high: 0,
+            alarm_low: 0,
+            alarm_high: 0,
+            irq_enabled: 0,
+            alarm_status: 0,
+            interrupt: false,
+        }
+    }
+    fn host_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Host clock is before the unix epoch")
+            .as_nanos() as u64
+    }
+    pub fn time(&self) -> u64 {
+        match self.epoch {
+            Some(epoch) => {
+                epoch.wrapping_add(self.instructions.wrapping_mul(NANOS_PER_INSTRUCTION))
+            }
+            None => (GoldfishRtc::host_time() as i64).wrapping_add(self.offset) as u64,
+        }
+    }
+    fn set_time(&mut self, time: u64) {
+        match self.epoch {
+            Some(_) => {
+                let elapsed = self.instructions.wrapping_mul(NANOS_PER_INSTRUCTION);
+                self.epoch = Some(time.wrapping_sub(elapsed));
+            }
+            None => self.offset = (time as i64).wrapping_sub(GoldfishRtc::host_time() as i64),
+        }
+    }
+    fn alarm(&self) -> u64 {
+        ((self.alarm_high as u64) << 32) | self.alarm_low as u64
+    }
+    fn check_alarm(&mut self) {
+        if self.alarm_status == 1 && self.time() >= self.alarm() {
+            self.alarm_status = 0;
+            if self.irq_enabled != 0 {
+                self.interrupt = true;
+            }
+        }
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn name(&self) -> &str {
+        "goldfish-rtc"
+    }
+    fn size(&self) -> u32 {
+        0x1000
+    }
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            RTC_TIME_LOW => {
+                // reading the low half latches the high half
+                let time = self.time();
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            }
+            RTC_TIME_HIGH => self.time_high,
+            RTC_ALARM_LOW => self.alarm_low,
+            RTC_ALARM_HIGH => self.alarm_high,
+            RTC_IRQ_ENABLED => self.irq_enabled,
+            RTC_ALARM_STATUS => self.alarm_status,
+            _ => 0,
+        }
+    }
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            RTC_TIME_LOW => {
+                let time = ((self.set_high as u64) << 32) | value as u64;
+                self.set_time(time);
+            }
+            RTC_TIME_HIGH => self.set_high = value,
+            // writing the low half arms the alarm, an alarm in the past fires right away
+            RTC_ALARM_LOW => {
+                self.alarm_low = value;
+                self.alarm_status = 1;
+                self.check_alarm();
+            }
+            RTC_ALARM_HIGH => self.alarm_high = value,
+            RTC_IRQ_ENABLED => self.irq_enabled = value & 1,
+            RTC_CLEAR_ALARM => self.alarm_status = 0,
+            RTC_CLEAR_INTERRUPT => self.interrupt = false,
+            _ => {}
+        }
+    }
+    fn event(&mut self, event: &MachineEvent) {
+        if let MachineEvent::Instruction(count) = *event {
+            self.instructions = count;
+            if self.alarm_status == 1 && (self.epoch.is_some() || count % ALARM_CHECK_INTERVAL == 0)
+            {
+                self.check_alarm();
+            }
+        }
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt
+    }
+    fn device_tree(&self, fdt: &mut FdtBuilder, base: u32) {
+        fdt.begin_node(&format!("rtc@{:x}", base));
+        fdt.property_string("compatible", "google,goldfish-rtc");
+        fdt.property_cells("reg", &[base, self.size()]);
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_time(rtc: &mut GoldfishRtc) -> u64 {
+        let low = rtc.read(RTC_TIME_LOW, 4) as u64;
+        let high = rtc.read(RTC_TIME_HIGH, 4) as u64;
+        (high << 32) | low
+    }
+
+    fn set_alarm(rtc: &mut GoldfishRtc, alarm: u64) {
+        rtc.write(RTC_ALARM_HIGH, 4, (alarm >> 32) as u32);
+        rtc.write(RTC_ALARM_LOW, 4, alarm as u32);
+    }
+
+    #[test]
+    fn deterministic_time_advances_with_instructions() {
+        let mut rtc = GoldfishRtc::new(Some(0x1_0000_0000));
+        assert_eq!(read_time(&mut rtc), 0x1_0000_0000);
+        rtc.event(&MachineEvent::Instruction(100));
+        assert_eq!(
+            read_time(&mut rtc),
+            0x1_0000_0000 + 100 * NANOS_PER_INSTRUCTION
+        );
+
+        // setting the time keeps it advancing from the new value
+        rtc.write(RTC_TIME_HIGH, 4, 2);
+        rtc.write(RTC_TIME_LOW, 4, 5);
+        assert_eq!(read_time(&mut rtc), 0x2_0000_0005);
+        rtc.event(&MachineEvent::Instruction(101));
+        assert_eq!(read_time(&mut rtc), 0x2_0000_0005 + NANOS_PER_INSTRUCTION);
+    }
+
+    #[test]
+    fn host_time_follows_the_written_offset() {
+        let mut rtc = GoldfishRtc::new(None);
+        rtc.write(RTC_TIME_HIGH, 4, 0);
+        rtc.write(RTC_TIME_LOW, 4, 1000);
+        let time = read_time(&mut rtc);
+        // well under a minute passes between the write and the read
+        assert!((1000..60_000_000_000).contains(&time), "{}", time);
+    }
+
+    #[test]
+    fn alarm_raises_the_interrupt_when_enabled() {
+        let mut rtc = GoldfishRtc::new(Some(0));
+        rtc.write(RTC_IRQ_ENABLED, 4, 1);
+        set_alarm(&mut rtc, 50 * NANOS_PER_INSTRUCTION);
+        assert_eq!(rtc.read(RTC_ALARM_STATUS, 4), 1);
+
+        rtc.event(&MachineEvent::Instruction(49));
+        assert!(!rtc.interrupt_pending());
+        rtc.event(&MachineEvent::Instruction(50));
+        assert!(rtc.interrupt_pending());
+        assert_eq!(rtc.read(RTC_ALARM_STATUS, 4), 0);
+
+        rtc.write(RTC_CLEAR_INTERRUPT, 4, 1);
+        assert!(!rtc.interrupt_pending());
+    }
+
+    #[test]
+    fn alarm_without_interrupts_only_clears_the_status() {
+        let mut rtc = GoldfishRtc::new(Some(0));
+        set_alarm(&mut rtc, 10);
+        rtc.event(&MachineEvent::Instruction(1));
+        assert_eq!(rtc.read(RTC_ALARM_STATUS, 4), 0);
+        assert!(!rtc.interrupt_pending());
+    }
+
+    #[test]
+    fn alarm_in_the_past_fires_when_armed() {
+        let mut rtc = GoldfishRtc::new(None);
+        rtc.write(RTC_IRQ_ENABLED, 4, 1);
+        set_alarm(&mut rtc, 1);
+        assert!(rtc.interrupt_pending());
+    }
+
+    #[test]
+    fn cleared_alarm_does_not_fire() {
+        let mut rtc = GoldfishRtc::new(Some(0));
+        rtc.write(RTC_IRQ_ENABLED, 4, 1);
+        set_alarm(&mut rtc, 100);
+        rtc.write(RTC_CLEAR_ALARM, 4, 1);
+        rtc.event(&MachineEvent::Instruction(1000));
+        assert!(!rtc.interrupt_pending());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs::File, io::prelude::*};
+
+use device_tree::FdtBuilder;
+use devices::{Device, MachineEvent};
+
+// same address as the sifive fu540 gpio block
+pub const GPIO_BASE: u32 = 0x1006_0000;
+
+const GPIO_INPUT_VAL: u32 = 0x00;
+const GPIO_OUTPUT_VAL: u32 = 0x04;
+const GPIO_OUTPUT_EN: u32 = 0x08;
+const GPIO_CHANGE_IE: u32 = 0x0c;
+const GPIO_CHANGE_IP: u32 = 0x10;
+
+pub struct Gpio {
+    input_val: u32,
+    output_val: u32,
+    output_en: u32,
+    change_ie: u32,
+    change_ip: u32,
+    instruction: u64,
+    // (instruction, pin, level) sorted by instruction
+    timeline: Vec<(u64, u32, bool)>,
+    next_input: usize,
+    log: Option<File>,
+}
+
+impl Gpio {
+    pub fn new() -> Gpio {
+        Gpio {
+            input_val: 0,
+            output_val: 0,
+            output_en: 0,
+            change_ie: 0,
+            change_ip: 0,
+            instruction: 0,
+            timeline: Vec::new(),
+            next_input: 0,
+            log: None,
+        }
+    }
+    // one "instruction pin level" entry per line, # starts a comment
+    pub fn load_timeline(&mut self, contents: &str) -> Result<(), String> {
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            if fields.len() != 3 {
+                return Err(format!(
+                    "line {}: expected instruction pin level",
+                    number + 1
+                ));
+            }
+            let instruction = fields[0]
+                .parse::<u64>()
+                .map_err(|_| format!("line {}: invalid instruction: {}", number + 1, fields[0]))?;
+            let pin = match fields[1].parse::<u32>() {
+                Ok(pin) if pin < 32 => pin,
+                _ => return Err(format!("line {}: invalid pin: {}", number + 1, fields[1])),
+            };
+            let level = match fields[2] {
+                "0" | "low" => false,
+                "1" | "high" => true,
+                _ => return Err(format!("line {}: invalid level: {}", number + 1, fields[2])),
+            };
+            self.timeline.push((instruction, pin, level));
+        }
+        self.timeline.sort_by_key(|entry| entry.0);
+        Ok(())
+    }
+    pub fn log_to(&mut self, file: File) {
+        self.log = Some(file);
+    }
+    pub fn outputs(&self) -> u32 {
+        self.output_val & self.output_en
+    }
+    fn set_outputs(&mut self, output_val: u32, output_en: u32) {
+        let previous = self.outputs();
+        self.output_val = output_val;
+        self.output_en = output_en;
+        let value = self.outputs();
+        if value == previous {
+            return;
+        }
+        let line = format!(
+            "instruction {}: gpio output 0x{:08X} -> 0x{:08X}",
+            self.instruction, previous, value
+        );
+        // stderr keeps the trace apart from what the program prints
+        eprintln!("{}", line);
+        if let Some(log) = &mut self.log {
+            writeln!(log, "{}", line).expect("Could not write gpio log");
+        }
+    }
+    fn drive_inputs(&mut self) {
+        while let Some(&(instruction, pin, level)) = self.timeline.get(self.next_input) {
+            if instruction > self.instruction {
+                break;
+            }
+            let previous = self.input_val;
+            if level {
+                self.input_val |= 1 << pin;
+            } else {
+                self.input_val &= !(1 << pin);
+            }
+            self.change_ip |= (previous ^ self.input_val) & self.change_ie;
+            self.next_input += 1;
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn name(&self) -> &str {
+        "gpio"
+    }
+    fn size(&self) -> u32 {
+        0x1000
+    }
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            GPIO_INPUT_VAL => self.input_val,
+            GPIO_OUTPUT_VAL => self.output_val,
+            GPIO_OUTPUT_EN => self.output_en,
+            GPIO_CHANGE_IE => self.change_ie,
+            GPIO_CHANGE_IP => self.change_ip,
+            _ => 0,
+        }
+    }
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            GPIO_OUTPUT_VAL => self.set_outputs(value, self.output_en),
+            GPIO_OUTPUT_EN => self.set_outputs(self.output_val, value),
+            GPIO_CHANGE_IE => self.change_ie = value,
+            // write one to clear
+            GPIO_CHANGE_IP => self.change_ip &= !value,
+            _ => {}
+        }
+    }
+    fn event(&mut self, event: &MachineEvent) {
+        if let MachineEvent::Instruction(count) = event {
+            self.instruction = *count;
+            self.drive_inputs();
+        }
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.change_ip != 0
+    }
+    fn device_tree(&self, fdt: &mut FdtBuilder, base: u32) {
+        fdt.begin_node(&format!("gpio@{:x}", base));
+        fdt.property_string("compatible", "risc-v_emulator-rust,gpio");
+        fdt.property_cells("reg", &[base, self.size()]);
+        fdt.property_empty("gpio-controller");
+        fdt.property_u32("#gpio-cells", 2);
+        fdt.property_u32("ngpios", 32);
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_are_masked_by_the_enable_register() {
+        let mut gpio = Gpio::new();
+        gpio.write(GPIO_OUTPUT_VAL, 4, 0b1011);
+        assert_eq!(gpio.outputs(), 0);
+        gpio.write(GPIO_OUTPUT_EN, 4, 0b0011);
+        assert_eq!(gpio.outputs(), 0b0011);
+        assert_eq!(gpio.read(GPIO_OUTPUT_VAL, 4), 0b1011);
+        assert_eq!(gpio.read(GPIO_OUTPUT_EN, 4), 0b0011);
+    }
+
+    #[test]
+    fn inputs_follow_the_timeline() {
+        let mut gpio = Gpio::new();
+        gpio.load_timeline("# instruction pin level\n10 3 high\n5 0 1\n20 3 low\n")
+            .unwrap();
+        gpio.event(&MachineEvent::Instruction(4));
+        assert_eq!(gpio.read(GPIO_INPUT_VAL, 4), 0);
+        gpio.event(&MachineEvent::Instruction(10));
+        assert_eq!(gpio.read(GPIO_INPUT_VAL, 4), 0b1001);
+        gpio.event(&MachineEvent::Instruction(25));
+        assert_eq!(gpio.read(GPIO_INPUT_VAL, 4), 0b0001);
+    }
+
+    #[test]
+    fn rejects_invalid_timelines() {
+        assert!(Gpio::new().load_timeline("10 3").is_err());
+        assert!(Gpio::new().load_timeline("10 32 1").is_err());
+        assert!(Gpio::new().load_timeline("10 3 maybe").is_err());
+        assert!(Gpio::new().load_timeline("x 3 1").is_err());
+    }
+
+    #[test]
+    fn enabled_input_changes_raise_the_interrupt() {
+        let mut gpio = Gpio::new();
+        gpio.load_timeline("1 2 1\n2 5 1\n").unwrap();
+        gpio.write(GPIO_CHANGE_IE, 4, 1 << 5);
+        gpio.event(&MachineEvent::Instruction(1));
+        assert!(!gpio.interrupt_pending());
+        gpio.event(&MachineEvent::Instruction(2));
+        assert!(gpio.interrupt_pending());
+        assert_eq!(gpio.read(GPIO_CHANGE_IP, 4), 1 << 5);
+
+        // write one to clear
+        gpio.write(GPIO_CHANGE_IP, 4, 1 << 5);
+        assert!(!gpio.interrupt_pending());
+    }
+}
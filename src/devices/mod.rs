@@ -0,0 +1,36 @@
+use device_tree::FdtBuilder;
+
+pub mod framebuffer;
+pub mod goldfish_rtc;
+pub mod gpio;
+
+pub enum MachineEvent {
+    Ebreak,
+    Exit,
+    // instructions retired so far
+    Instruction(u64),
+}
+
+pub trait Device {
+    fn name(&self) -> &str;
+    fn size(&self) -> u32;
+    fn read(&mut self, offset: u32, size: u32) -> u32;
+    fn write(&mut self, offset: u32, size: u32, value: u32);
+    fn event(&mut self, _event: &MachineEvent) {}
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+    fn device_tree(&self, _fdt: &mut FdtBuilder, _base: u32) {}
+}
+
+pub struct MappedDevice {
+    pub base: u32,
+    pub device: Box<dyn Device>,
+}
+
+impl MappedDevice {
+    pub fn contains(&self, address: u32, size: u32) -> bool {
+        address >= self.base
+            && (address - self.base) as u64 + size as u64 <= self.device.size() as u64
+    }
+}
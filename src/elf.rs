@@ -0,0 +1,314 @@
+use std::convert::TryInto;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+const ELF32_EHDR_SIZE: usize = 52;
+const ELF32_PHDR_SIZE: usize = 32;
+const ELF32_SHDR_SIZE: usize = 40;
+const ELF32_SYM_SIZE: usize = 16;
+
+pub struct ElfSegment {
+    pub address: u32,
+    pub data: Vec<u8>,
+    // bytes past the file data up to this size are .bss and get zeroed
+    pub mem_size: u32,
+}
+
+pub struct ElfSymbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+pub struct ElfFile {
+    pub entry: u32,
+    // where the program headers end up in memory, for the auxiliary vector
+    pub program_headers: Option<u32>,
+    pub program_header_size: u32,
+    pub program_header_count: u32,
+    pub segments: Vec<ElfSegment>,
+    pub symbols: Vec<ElfSymbol>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated ELF file".to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated ELF file".to_string())
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+pub fn parse(bytes: &[u8], use_virtual_address: bool) -> Result<ElfFile, String> {
+    if !is_elf(bytes) || bytes.len() < ELF32_EHDR_SIZE {
+        return Err("Not an ELF file".to_string());
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(format!(
+            "ELF class {} does not match the RV32 cpu, expected ELFCLASS32",
+            bytes[4]
+        ));
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err("ELF file is not little endian".to_string());
+    }
+    let e_type = read_u16(bytes, 16)?;
+    if e_type != ET_EXEC {
+        return Err(format!("ELF type {} is not an executable", e_type));
+    }
+    let e_machine = read_u16(bytes, 18)?;
+    if e_machine != EM_RISCV {
+        return Err(format!(
+            "ELF machine {} does not match the RISC-V cpu",
+            e_machine
+        ));
+    }
+
+    let entry = read_u32(bytes, 24)?;
+    let phoff = read_u32(bytes, 28)? as usize;
+    let phentsize = read_u16(bytes, 42)? as usize;
+    let phnum = read_u16(bytes, 44)? as usize;
+    if phentsize < ELF32_PHDR_SIZE {
+        return Err(format!("Invalid program header size {}", phentsize));
+    }
+
+    let mut segments = Vec::new();
+    let mut program_headers = None;
+    for i in 0..phnum {
+        let phdr = phoff + i * phentsize;
+        if read_u32(bytes, phdr)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u32(bytes, phdr + 4)? as usize;
+        let vaddr = read_u32(bytes, phdr + 8)?;
+        let paddr = read_u32(bytes, phdr + 12)?;
+        let file_size = read_u32(bytes, phdr + 16)? as usize;
+        let mem_size = read_u32(bytes, phdr + 20)?;
+        if file_size > mem_size as usize {
+            return Err(format!(
+                "Segment {} is larger in the file than in memory",
+                i
+            ));
+        }
+        let data = bytes
+            .get(offset..offset + file_size)
+            .ok_or_else(|| format!("Segment {} is outside of the file", i))?;
+        let address = if use_virtual_address { vaddr } else { paddr };
+        if address as u64 + mem_size as u64 > 1 << 32 {
+            return Err(format!(
+                "Segment {} at 0x{:X} goes past the end of the address space",
+                i, address
+            ));
+        }
+        if offset <= phoff && phoff + phnum * phentsize <= offset + file_size {
+            let headers = address
+                .checked_add((phoff - offset) as u32)
+                .ok_or_else(|| {
+                    format!(
+                        "Program headers in segment {} are past the end of the address space",
+                        i
+                    )
+                })?;
+            program_headers = Some(headers);
+        }
+        segments.push(ElfSegment {
+            address,
+            data: data.to_vec(),
+            mem_size,
+        });
+    }
+
+    let symbols = parse_symbols(bytes)?;
+
+    Ok(ElfFile {
+        entry,
+        program_headers,
+        program_header_size: phentsize as u32,
+        program_header_count: phnum as u32,
+        segments,
+        symbols,
+    })
+}
+
+fn read_string(bytes: &[u8], offset: usize) -> Result<String, String> {
+    let rest = bytes
+        .get(offset..)
+        .ok_or_else(|| "Symbol name is outside of the file".to_string())?;
+    let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+    Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+}
+
+// .symtab and its linked .strtab, an ELF without them just has no symbols
+fn parse_symbols(bytes: &[u8]) -> Result<Vec<ElfSymbol>, String> {
+    let shoff = read_u32(bytes, 32)? as usize;
+    let shentsize = read_u16(bytes, 46)? as usize;
+    let shnum = read_u16(bytes, 48)? as usize;
+    if shoff == 0 || shentsize < ELF32_SHDR_SIZE {
+        return Ok(Vec::new());
+    }
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let shdr = shoff + i * shentsize;
+        if read_u32(bytes, shdr + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = read_u32(bytes, shdr + 16)? as usize;
+        let size = read_u32(bytes, shdr + 20)? as usize;
+        let link = read_u32(bytes, shdr + 24)? as usize;
+        let strtab = read_u32(bytes, shoff + link * shentsize + 16)? as usize;
+
+        for sym in (offset..offset + size).step_by(ELF32_SYM_SIZE).skip(1) {
+            let name = read_u32(bytes, sym)? as usize;
+            let address = read_u32(bytes, sym + 4)?;
+            let size = read_u32(bytes, sym + 8)?;
+            let kind = *bytes
+                .get(sym + 12)
+                .ok_or_else(|| "Truncated ELF file".to_string())?
+                & 0xf;
+            let section = read_u16(bytes, sym + 14)?;
+            if name == 0
+                || section == SHN_UNDEF
+                || section == SHN_ABS
+                || kind == STT_SECTION
+                || kind == STT_FILE
+            {
+                continue;
+            }
+            let name = read_string(bytes, strtab + name)?;
+            // skip mapping symbols like $x and $d
+            if name.starts_with('$') {
+                continue;
+            }
+            symbols.push(ElfSymbol {
+                name,
+                address,
+                size,
+            });
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+    use cpu::RiscvCpu;
+
+    // an executable with one PT_LOAD segment holding the headers and code
+    fn executable(code: &[u8], mem_size: u32) -> Vec<u8> {
+        let mut bytes = vec![0u8; ELF32_EHDR_SIZE];
+        bytes[..4].copy_from_slice(ELF_MAGIC);
+        bytes[4] = ELFCLASS32;
+        bytes[5] = ELFDATA2LSB;
+        bytes[6] = 1;
+        bytes[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        bytes[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        bytes[24..28].copy_from_slice(&0x1054u32.to_le_bytes());
+        bytes[28..32].copy_from_slice(&(ELF32_EHDR_SIZE as u32).to_le_bytes());
+        bytes[42..44].copy_from_slice(&(ELF32_PHDR_SIZE as u16).to_le_bytes());
+        bytes[44..46].copy_from_slice(&1u16.to_le_bytes());
+        let file_size = (ELF32_EHDR_SIZE + ELF32_PHDR_SIZE + code.len()) as u32;
+        for value in [PT_LOAD, 0, 0x8000_1000, 0x1000, file_size, mem_size, 5, 4] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(code);
+        bytes
+    }
+
+    #[test]
+    fn parses_header_and_load_segment() {
+        let bytes = executable(&[0x13, 0, 0, 0], 0x100);
+        let elf = parse(&bytes, false).unwrap();
+        assert_eq!(elf.entry, 0x1054);
+        assert_eq!(elf.program_header_count, 1);
+        assert_eq!(elf.program_header_size, ELF32_PHDR_SIZE as u32);
+        assert_eq!(elf.program_headers, Some(0x1000 + ELF32_EHDR_SIZE as u32));
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].address, 0x1000);
+        assert_eq!(elf.segments[0].mem_size, 0x100);
+        assert_eq!(elf.segments[0].data, bytes);
+        assert!(elf.symbols.is_empty());
+
+        let elf = parse(&bytes, true).unwrap();
+        assert_eq!(elf.segments[0].address, 0x8000_1000);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = executable(&[0x13, 0, 0, 0], 0x100);
+        assert!(parse(&bytes[..ELF32_EHDR_SIZE - 1], false).is_err());
+        // the program header is cut off
+        assert!(parse(&bytes[..ELF32_EHDR_SIZE + 8], false).is_err());
+        // the segment data is cut off
+        assert!(parse(&bytes[..bytes.len() - 1], false).is_err());
+    }
+
+    #[test]
+    fn rejects_other_classes_and_machines() {
+        let mut bytes = executable(&[], 0x100);
+        bytes[4] = 2;
+        assert!(parse(&bytes, false).is_err());
+        let mut bytes = executable(&[], 0x100);
+        bytes[18] = 62;
+        assert!(parse(&bytes, false).is_err());
+        // more data in the file than in memory
+        assert!(parse(&executable(&[0; 16], 8), false).is_err());
+    }
+
+    #[test]
+    fn rejects_segments_past_the_address_space() {
+        let mut bytes = executable(&[0x13, 0, 0, 0], 0x100);
+        // paddr of the only program header
+        let paddr = ELF32_EHDR_SIZE + 12;
+        bytes[paddr..paddr + 4].copy_from_slice(&0xffff_ff80u32.to_le_bytes());
+        assert!(parse(&bytes, false).is_err());
+        bytes[paddr..paddr + 4].copy_from_slice(&0xffff_ff00u32.to_le_bytes());
+        assert!(parse(&bytes, false).is_ok());
+    }
+
+    #[test]
+    fn loads_segments_and_zeroes_bss() {
+        let code = [0x13, 0, 0, 0];
+        let bytes = executable(&code, 0x100);
+        let elf = parse(&bytes, false).unwrap();
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        cpu.memory.iter_mut().for_each(|byte| *byte = 0xaa);
+        cpu.load_elf(&elf).unwrap();
+
+        assert_eq!(cpu.program_counter, 0x1054);
+        assert_eq!(cpu.read_bytes(0x1000, bytes.len()), Ok(&bytes[..]));
+        let bss = cpu
+            .read_bytes(0x1000 + bytes.len() as u32, 0x100 - bytes.len())
+            .unwrap();
+        assert!(bss.iter().all(|&byte| byte == 0));
+        assert_eq!(cpu.image_end, 0x1100);
+    }
+
+    #[test]
+    fn rejects_segments_outside_memory() {
+        let elf = parse(&executable(&[0x13, 0, 0, 0], 0x100), true).unwrap();
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        assert!(cpu.load_elf(&elf).is_err());
+    }
+}
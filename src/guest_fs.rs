@@ -0,0 +1,503 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+const ENOENT: i32 = 2;
+const ENOTDIR: i32 = 20;
+const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+const EROFS: i32 = 30;
+const ELOOP: i32 = 40;
+
+// as many links as Linux follows for one path
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Default)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    // fail when the file already exists
+    pub exclusive: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+pub struct OverlayFile {
+    data: Rc<RefCell<Vec<u8>>>,
+    position: u64,
+    mode: OpenMode,
+}
+
+pub enum GuestFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host(File),
+    Overlay(OverlayFile),
+}
+
+pub struct FileStat {
+    pub is_char_device: bool,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: u64,
+}
+
+fn error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn read_only() -> OpenMode {
+    OpenMode {
+        read: true,
+        ..OpenMode::default()
+    }
+}
+
+// the names of a path in order, a leading / or prefix is dropped
+fn push_components(names: &mut VecDeque<OsString>, path: &Path) {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push_back(name.to_os_string()),
+            Component::ParentDir => names.push_back(OsString::from("..")),
+            _ => {}
+        }
+    }
+}
+
+fn open_host(
+    root: Option<&Path>,
+    host_path: &Path,
+    names: &[OsString],
+    mode: &OpenMode,
+) -> io::Result<File> {
+    match root {
+        Some(root) => open_beneath(root, names, mode),
+        None => OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create && !mode.exclusive)
+            .create_new(mode.create && mode.exclusive)
+            .open(host_path),
+    }
+}
+
+// every name below the root is opened relative to its parent directory without
+// following symlinks, so a name swapped for a link after resolve fails to open
+#[cfg(unix)]
+fn open_beneath(root: &Path, names: &[OsString], mode: &OpenMode) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let open_at = |directory: &File, name: &OsString, flags: i32| {
+        let name = CString::new(name.as_bytes()).map_err(|_| error(EINVAL))?;
+        let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(directory.as_raw_fd(), name.as_ptr(), flags, 0o666) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    };
+    let (last, directories) = match names.split_last() {
+        Some(split) => split,
+        None => return open_host(None, root, names, mode),
+    };
+    let mut directory = File::open(root)?;
+    for name in directories {
+        directory = open_at(&directory, name, libc::O_RDONLY | libc::O_DIRECTORY)?;
+    }
+    let mut flags = match (mode.read, mode.write || mode.append) {
+        (true, true) => libc::O_RDWR,
+        (false, true) => libc::O_WRONLY,
+        _ => libc::O_RDONLY,
+    };
+    if mode.append {
+        flags |= libc::O_APPEND;
+    }
+    if mode.truncate {
+        flags |= libc::O_TRUNC;
+    }
+    if mode.create {
+        flags |= libc::O_CREAT;
+    }
+    if mode.create && mode.exclusive {
+        flags |= libc::O_EXCL;
+    }
+    open_at(&directory, last, flags)
+}
+
+#[cfg(not(unix))]
+fn open_beneath(root: &Path, names: &[OsString], mode: &OpenMode) -> io::Result<File> {
+    let host_path = root.join(names.iter().collect::<PathBuf>());
+    open_host(None, &host_path, names, mode)
+}
+
+impl GuestFile {
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GuestFile::Stdin => io::stdin().read(buffer),
+            GuestFile::Host(file) => file.read(buffer),
+            GuestFile::Overlay(file) if file.mode.read => {
+                let data = file.data.borrow();
+                let start = (file.position as usize).min(data.len());
+                let read = buffer.len().min(data.len() - start);
+                buffer[..read].copy_from_slice(&data[start..start + read]);
+                file.position += read as u64;
+                Ok(read)
+            }
+            _ => Err(error(EBADF)),
+        }
+    }
+    pub fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        match self {
+            GuestFile::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(buffer).and_then(|_| stdout.flush())
+            }
+            GuestFile::Stderr => io::stderr().write_all(buffer),
+            GuestFile::Host(file) => file.write_all(buffer),
+            GuestFile::Overlay(file) if file.mode.write => {
+                let mut data = file.data.borrow_mut();
+                if file.mode.append {
+                    file.position = data.len() as u64;
+                }
+                let start = file.position as usize;
+                let end = start + buffer.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].copy_from_slice(buffer);
+                file.position = end as u64;
+                Ok(())
+            }
+            _ => Err(error(EBADF)),
+        }
+    }
+    pub fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            GuestFile::Host(file) => file.seek(position),
+            GuestFile::Overlay(file) => {
+                let position = match position {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::Current(offset) => file.position.checked_add_signed(offset),
+                    SeekFrom::End(offset) => {
+                        (file.data.borrow().len() as u64).checked_add_signed(offset)
+                    }
+                };
+                file.position = position.ok_or_else(|| error(EINVAL))?;
+                Ok(file.position)
+            }
+            // the standard streams cannot seek
+            _ => Err(error(ESPIPE)),
+        }
+    }
+    pub fn stat(&self) -> io::Result<FileStat> {
+        match self {
+            GuestFile::Host(file) => {
+                let metadata = file.metadata()?;
+                Ok(FileStat {
+                    is_char_device: false,
+                    is_dir: metadata.is_dir(),
+                    size: metadata.len(),
+                    modified: metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |time| time.as_secs()),
+                })
+            }
+            GuestFile::Overlay(file) => Ok(FileStat {
+                is_char_device: false,
+                is_dir: false,
+                size: file.data.borrow().len() as u64,
+                modified: 0,
+            }),
+            _ => Ok(FileStat {
+                is_char_device: true,
+                is_dir: false,
+                size: 0,
+                modified: 0,
+            }),
+        }
+    }
+}
+
+// how guest programs see host files, by default they have full access
+pub struct GuestFs {
+    // guest paths are resolved under this directory and cannot leave it
+    root: Option<PathBuf>,
+    read_only: bool,
+    // writes are kept in memory, keyed by host path, when enabled
+    overlay: Option<HashMap<PathBuf, Rc<RefCell<Vec<u8>>>>>,
+}
+
+impl GuestFs {
+    pub fn new() -> GuestFs {
+        GuestFs {
+            root: None,
+            read_only: false,
+            overlay: None,
+        }
+    }
+    pub fn root(mut self, root: &Path) -> Result<GuestFs, String> {
+        let root = root
+            .canonicalize()
+            .map_err(|err| format!("{}: {}", root.display(), err))?;
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", root.display()));
+        }
+        self.root = Some(root);
+        Ok(self)
+    }
+    pub fn read_only(mut self) -> GuestFs {
+        self.read_only = true;
+        self
+    }
+    pub fn overlay(mut self) -> GuestFs {
+        self.overlay = Some(HashMap::new());
+        self
+    }
+    // relative and absolute guest paths both start at the root, symlinks are
+    // followed here so every name that is left is a plain file or directory
+    fn resolve(root: &Path, path: &str) -> Result<Vec<OsString>, i32> {
+        let mut names: Vec<OsString> = Vec::new();
+        let mut pending: VecDeque<OsString> = VecDeque::new();
+        push_components(&mut pending, Path::new(path));
+        let mut links = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                names.pop().ok_or(EACCES)?;
+                continue;
+            }
+            let host_path = root.join(names.iter().collect::<PathBuf>()).join(&name);
+            match fs::symlink_metadata(&host_path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(ELOOP);
+                    }
+                    let target = fs::read_link(&host_path).map_err(|_| EACCES)?;
+                    // dangling links too, creating a file through one makes its target
+                    let target = if target.is_absolute() {
+                        names.clear();
+                        target.strip_prefix(root).map_err(|_| EACCES)?.to_path_buf()
+                    } else {
+                        target
+                    };
+                    let mut expanded = VecDeque::new();
+                    push_components(&mut expanded, &target);
+                    expanded.extend(pending.drain(..));
+                    pending = expanded;
+                }
+                // only the last name may be missing or something other than a directory
+                Ok(metadata) if !metadata.is_dir() && !pending.is_empty() => return Err(ENOTDIR),
+                Err(_) if !pending.is_empty() => return Err(ENOENT),
+                _ => names.push(name),
+            }
+        }
+        Ok(names)
+    }
+    fn host_path(&self, path: &str) -> Result<(PathBuf, Vec<OsString>), i32> {
+        match &self.root {
+            Some(root) => {
+                let names = GuestFs::resolve(root, path)?;
+                let host_path = root.join(names.iter().collect::<PathBuf>());
+                Ok((host_path, names))
+            }
+            None => Ok((PathBuf::from(path), Vec::new())),
+        }
+    }
+    pub fn open(&mut self, path: &str, mode: OpenMode) -> Result<GuestFile, i32> {
+        let (host_path, names) = self.host_path(path)?;
+        let writes = mode.write || mode.create || mode.truncate || mode.append;
+        if self.read_only && writes {
+            return Err(EROFS);
+        }
+        let root = self.root.as_deref();
+        let overlay = match self.overlay.as_mut() {
+            Some(overlay) => overlay,
+            None => {
+                return open_host(self.root.as_deref(), &host_path, &names, &mode)
+                    .map(GuestFile::Host)
+                    .map_err(|err| err.raw_os_error().unwrap_or(EACCES));
+            }
+        };
+        let data = match overlay.get(&host_path) {
+            Some(_) if mode.create && mode.exclusive => return Err(EEXIST),
+            Some(data) => data.clone(),
+            None => {
+                if !writes {
+                    return open_host(root, &host_path, &names, &read_only())
+                        .map(GuestFile::Host)
+                        .map_err(|err| err.raw_os_error().unwrap_or(EACCES));
+                }
+                // the host file is copied on the first write and never changed
+                let contents =
+                    match open_host(root, &host_path, &names, &read_only()).and_then(|mut file| {
+                        let mut contents = Vec::new();
+                        file.read_to_end(&mut contents).map(|_| contents)
+                    }) {
+                        Ok(_) if mode.create && mode.exclusive => return Err(EEXIST),
+                        Ok(contents) => contents,
+                        Err(_) if mode.create => Vec::new(),
+                        Err(err) => return Err(err.raw_os_error().unwrap_or(ENOENT)),
+                    };
+                let data = Rc::new(RefCell::new(contents));
+                overlay.insert(host_path, data.clone());
+                data
+            }
+        };
+        if mode.truncate {
+            data.borrow_mut().clear();
+        }
+        Ok(GuestFile::Overlay(OverlayFile {
+            data,
+            position: 0,
+            mode,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory holding root/inside.txt and a secret.txt next to root
+    fn sandbox(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("guest_fs_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("root/sub")).unwrap();
+        fs::write(directory.join("root/inside.txt"), b"inside").unwrap();
+        fs::write(directory.join("secret.txt"), b"secret").unwrap();
+        directory
+    }
+
+    fn read_mode() -> OpenMode {
+        OpenMode {
+            read: true,
+            ..OpenMode::default()
+        }
+    }
+
+    #[test]
+    fn opens_files_inside_the_root() {
+        let directory = sandbox("inside");
+        let mut guest = GuestFs::new().root(&directory.join("root")).unwrap();
+        for path in ["inside.txt", "/inside.txt", "sub/../inside.txt"] {
+            let mut file = guest.open(path, read_mode()).ok().unwrap();
+            let mut buffer = [0u8; 16];
+            let read = file.read(&mut buffer).unwrap();
+            assert_eq!(&buffer[..read], b"inside");
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn parent_directories_cannot_leave_the_root() {
+        let directory = sandbox("parent");
+        let mut guest = GuestFs::new().root(&directory.join("root")).unwrap();
+        for path in ["../secret.txt", "/../secret.txt", "sub/../../secret.txt"] {
+            assert_eq!(
+                guest.open(path, read_mode()).err(),
+                Some(EACCES),
+                "{}",
+                path
+            );
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_leave_the_root() {
+        let directory = sandbox("symlink");
+        let root = directory.join("root");
+        std::os::unix::fs::symlink(directory.join("secret.txt"), root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&directory, root.join("outside")).unwrap();
+        let mut guest = GuestFs::new().root(&root).unwrap();
+        for path in ["escape.txt", "outside/secret.txt", "outside/new.txt"] {
+            assert_eq!(
+                guest.open(path, read_mode()).err(),
+                Some(EACCES),
+                "{}",
+                path
+            );
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlinks_cannot_create_files_outside_the_root() {
+        let directory = sandbox("dangling");
+        let root = directory.join("root");
+        std::os::unix::fs::symlink(directory.join("created.txt"), root.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("../created.txt", root.join("relative")).unwrap();
+        let mut guest = GuestFs::new().root(&root).unwrap();
+        let create = || OpenMode {
+            write: true,
+            create: true,
+            ..OpenMode::default()
+        };
+        for path in ["absolute", "relative"] {
+            assert_eq!(guest.open(path, create()).err(), Some(EACCES), "{}", path);
+        }
+        assert!(!directory.join("created.txt").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_within_the_root_are_followed() {
+        let directory = sandbox("within");
+        let root = directory.join("root");
+        std::os::unix::fs::symlink("../inside.txt", root.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        let mut guest = GuestFs::new().root(&root).unwrap();
+        for path in ["sub/link", "absolute/link", "absolute/../inside.txt"] {
+            let mut file = guest.open(path, read_mode()).ok().unwrap();
+            let mut buffer = [0u8; 16];
+            let read = file.read(&mut buffer).unwrap();
+            assert_eq!(&buffer[..read], b"inside", "{}", path);
+        }
+        // .. applies to where a link leads, not to the link itself
+        assert_eq!(
+            guest.open("absolute/../../secret.txt", read_mode()).err(),
+            Some(EACCES)
+        );
+        assert_eq!(guest.open("loop", read_mode()).err(), Some(ELOOP));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn parent_directories_inside_the_root_are_allowed() {
+        let directory = sandbox("dotdot");
+        let mut guest = GuestFs::new().root(&directory.join("root")).unwrap();
+        for path in ["sub/../sub/../inside.txt", "./sub/./../inside.txt"] {
+            assert!(guest.open(path, read_mode()).is_ok(), "{}", path);
+        }
+        assert_eq!(
+            guest.open("missing/../inside.txt", read_mode()).err(),
+            Some(ENOENT)
+        );
+        assert_eq!(
+            guest.open("inside.txt/../inside.txt", read_mode()).err(),
+            Some(ENOTDIR)
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+    pub fn overlaps(&self, other: &Segment) -> bool {
+        (self.address as u64) < other.end() && (other.address as u64) < self.end()
+    }
+}
+
+// memory contents from raw, hex or s-record files
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    pub fn new() -> Image {
+        Image {
+            segments: Vec::new(),
+            entry: None,
+        }
+    }
+    pub fn from_raw(address: u32, data: &[u8]) -> Image {
+        let mut image = Image::new();
+        image.push(address, data);
+        image
+    }
+    // appends to the last segment when contiguous
+    pub fn push(&mut self, address: u32, data: &[u8]) {
+        if let Some(last) = self.segments.last_mut() {
+            if last.address.wrapping_add(last.data.len() as u32) == address {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+        self.segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    // up to 1 << 32 when the last segment reaches the top of the address space
+    pub fn end(&self) -> u64 {
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+}
+
+fn hex_byte(text: &str, index: usize) -> Result<u8, String> {
+    text.get(index * 2..index * 2 + 2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .ok_or_else(|| format!("Invalid hex digits: {}", text))
+}
+
+fn hex_bytes(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("Odd number of hex digits: {}", text));
+    }
+    (0..text.len() / 2).map(|i| hex_byte(text, i)).collect()
+}
+
+fn looks_like(contents: &[u8], first: fn(&str) -> bool) -> bool {
+    match std::str::from_utf8(contents) {
+        Ok(text) => text
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .is_some_and(first),
+        Err(_) => false,
+    }
+}
+
+pub fn is_ihex(contents: &[u8]) -> bool {
+    looks_like(contents, |line| {
+        line.starts_with(':') && line[1..].chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+pub fn is_srec(contents: &[u8]) -> bool {
+    looks_like(contents, |line| {
+        line.starts_with('S')
+            && line.len() > 2
+            && line.as_bytes()[1].is_ascii_digit()
+            && line[2..].chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+// intel hex record types 00 data, 01 end of file, 04 extended linear address
+// and 05 start linear address
+pub fn parse_ihex(contents: &str) -> Result<Image, String> {
+    let mut image = Image::new();
+    let mut upper: u32 = 0;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| format!("line {}: missing ':'", number + 1))
+            .and_then(hex_bytes)
+            .map_err(|err| format!("line {}: {}", number + 1, err))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(format!("line {}: wrong record length", number + 1));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(format!("line {}: bad checksum", number + 1));
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => image.push(upper | address, data),
+            0x01 => break,
+            0x04 if data.len() == 2 => {
+                upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16
+            }
+            0x05 if data.len() == 4 => {
+                image.entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+            }
+            kind => {
+                return Err(format!(
+                    "line {}: unsupported record type {:02X}",
+                    number + 1,
+                    kind
+                ))
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn ihex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&address.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+    let hex = record
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<String>();
+    format!(":{}\n", hex)
+}
+
+pub fn write_ihex(address: u32, data: &[u8], entry: u32) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let chunk_address = address + (i * 16) as u32;
+        if upper != Some(chunk_address >> 16) {
+            upper = Some(chunk_address >> 16);
+            out += &ihex_record(0x04, 0, &((chunk_address >> 16) as u16).to_be_bytes());
+        }
+        out += &ihex_record(0x00, chunk_address as u16, chunk);
+    }
+    out += &ihex_record(0x05, 0, &entry.to_be_bytes());
+    out += &ihex_record(0x01, 0, &[]);
+    out
+}
+
+// s-records S1 S2 S3 carry data with 2, 3 and 4 address bytes,
+// S7 S8 S9 the start address, S0 S5 S6 are headers and counts
+pub fn parse_srec(contents: &str) -> Result<Image, String> {
+    let mut image = Image::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let kind = line
+            .strip_prefix('S')
+            .and_then(|rest| rest.chars().next())
+            .ok_or_else(|| format!("line {}: missing 'S'", number + 1))?;
+        let record = hex_bytes(&line[1 + kind.len_utf8()..])
+            .map_err(|err| format!("line {}: {}", number + 1, err))?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(format!("line {}: wrong record length", number + 1));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(format!("line {}: bad checksum", number + 1));
+        }
+        let address_size = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => {
+                return Err(format!(
+                    "line {}: unsupported record type S{}",
+                    number + 1,
+                    kind
+                ))
+            }
+        };
+        if record.len() < address_size + 2 {
+            return Err(format!("line {}: wrong record length", number + 1));
+        }
+        let address = record[1..=address_size]
+            .iter()
+            .fold(0u32, |address, byte| (address << 8) | *byte as u32);
+        let data = &record[address_size + 1..record.len() - 1];
+        match kind {
+            '1' | '2' | '3' => image.push(address, data),
+            '7' | '8' | '9' => image.entry = Some(address),
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+fn srec_record(kind: char, address: &[u8], data: &[u8]) -> String {
+    let mut record = vec![(address.len() + data.len() + 1) as u8];
+    record.extend_from_slice(address);
+    record.extend_from_slice(data);
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+    let hex = record
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<String>();
+    format!("S{}{}\n", kind, hex)
+}
+
+pub fn write_srec(address: u32, data: &[u8], entry: u32) -> String {
+    let mut out = srec_record('0', &[0, 0], b"risc-v_emulator-rust");
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let chunk_address = address + (i * 16) as u32;
+        out += &srec_record('3', &chunk_address.to_be_bytes(), chunk);
+    }
+    out += &srec_record('7', &entry.to_be_bytes(), &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[test]
+    fn ihex_round_trip() {
+        // crosses a 64 KiB boundary, which needs a second extended address record
+        let text = write_ihex(0x8000_fff0, DATA, 0x8000_fff4);
+        assert!(is_ihex(text.as_bytes()));
+        let image = parse_ihex(&text).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x8000_fff0);
+        assert_eq!(image.segments[0].data, DATA);
+        assert_eq!(image.entry, Some(0x8000_fff4));
+    }
+
+    #[test]
+    fn srec_round_trip() {
+        let text = write_srec(0x8000_0000, DATA, 0x8000_0004);
+        assert!(is_srec(text.as_bytes()));
+        let image = parse_srec(&text).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x8000_0000);
+        assert_eq!(image.segments[0].data, DATA);
+        assert_eq!(image.entry, Some(0x8000_0004));
+    }
+
+    #[test]
+    fn end_reaches_the_top_of_the_address_space() {
+        assert_eq!(Image::new().end(), 0);
+        let mut image = Image::from_raw(0x1000, &[0; 16]);
+        image.push(0xffff_fff0, &[0; 16]);
+        assert_eq!(image.end(), 1 << 32);
+    }
+
+    #[test]
+    fn ihex_rejects_bad_records() {
+        // the checksum of :0100000041BE is BE
+        assert!(parse_ihex(":0100000041BE\n").is_ok());
+        assert!(parse_ihex(":0100000041BF\n")
+            .err()
+            .unwrap()
+            .contains("bad checksum"));
+        assert!(parse_ihex(":0200000041BE\n")
+            .err()
+            .unwrap()
+            .contains("wrong record length"));
+        assert!(parse_ihex(":01000000411\n").is_err());
+    }
+
+    #[test]
+    fn srec_rejects_bad_records() {
+        // the checksum of S104000041BA is BA
+        assert!(parse_srec("S104000041BA\n").is_ok());
+        assert!(parse_srec("S104000041BB\n")
+            .err()
+            .unwrap()
+            .contains("bad checksum"));
+        assert!(parse_srec("S105000041B9\n")
+            .err()
+            .unwrap()
+            .contains("wrong record length"));
+        // a multibyte character after the S is an error, not a panic
+        assert!(parse_srec("S\u{e9}04000041BA\n").is_err());
+        assert!(!is_srec("S\u{e9}04000041BA\n".as_bytes()));
+    }
+}
@@ -2,13 +2,48 @@
 extern crate bitfield;
 
 extern crate clap;
+#[cfg(unix)]
+extern crate libc;
 
-use std::{fs::File, io::prelude::*, path::Path};
+use std::{
+    fs::File,
+    io::prelude::*,
+    path::{Path, PathBuf},
+};
 
+use assembler::{AssemblerOptions, Severity};
+use config::MachineConfig;
+use cpu::{Privilege, Stop};
+use devices::framebuffer::{Framebuffer, PixelFormat, SnapshotTrigger, FRAMEBUFFER_BASE};
+use devices::goldfish_rtc::{GoldfishRtc, GOLDFISH_RTC_BASE};
+use devices::gpio::{Gpio, GPIO_BASE};
+use guest_fs::GuestFs;
+use image::{Image, Segment};
+use rars::RarsServices;
+use rng::XorShift;
+use sbi::SbiFirmware;
+use semihosting::Semihosting;
+use syscalls::{EcallMode, Libc, LinuxSyscalls};
+
+mod config;
 mod cpu;
+mod device_tree;
+mod devices;
+mod elf;
+mod guest_fs;
+mod image;
 mod imm_enc_dec;
 mod inst_defs;
 mod instructions;
+mod png;
+mod rars;
+mod rng;
+mod sbi;
+mod semihosting;
+mod startup;
+mod symbols;
+mod syscalls;
+mod trap;
 mod types;
 
 mod assembler;
@@ -34,15 +69,293 @@ fn main() {
                         .help("Sets the output file to use")
                         .required(true)
                         .index(2),
+                )
+                .arg(
+                    clap::Arg::new("format")
+                        .long("format")
+                        .help("Sets the output format, defaults to the output file extension")
+                        .value_parser(["bin", "ihex", "srec"]),
+                )
+                .arg(
+                    clap::Arg::new("legacy-syntax")
+                        .long("legacy-syntax")
+                        .help("Also accepts the old load and store operand order lw rd, rs1, imm and sw rs1, rs2, imm")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("include")
+                        .short('I')
+                        .long("include")
+                        .value_name("DIR")
+                        .help("Adds a directory searched by .include and .incbin")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("define")
+                        .short('D')
+                        .long("define")
+                        .value_name("NAME[=VALUE]")
+                        .help("Defines a constant like .equ, the value defaults to 1")
+                        .value_parser(parse_define)
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("listing")
+                        .long("listing")
+                        .value_name("FILE")
+                        .help("Writes the address and code of every line, followed by the symbol table"),
+                )
+                .arg(
+                    clap::Arg::new("base")
+                        .long("base")
+                        .value_name("ADDRESS")
+                        .help("Sets the address .text starts at, with .rodata, .data and .bss after it, a raw output file has to be run with the same --base")
+                        .value_parser(parse_number),
                 ),
         )
         .subcommand(
-            clap::Command::new("run").about("Runs the given file").arg(
-                clap::Arg::new("INPUT")
-                    .help("Sets the input file to use")
-                    .required(true)
-                    .index(1),
-            ),
+            clap::Command::new("run")
+                .about("Runs the given file")
+                .arg(
+                    clap::Arg::new("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("ARGS")
+                        .help("Arguments passed to the program after --, with --ecall linux or --semihosting")
+                        .index(2)
+                        .num_args(0..)
+                        .last(true),
+                )
+                .arg(
+                    clap::Arg::new("env")
+                        .long("env")
+                        .value_name("NAME=VALUE")
+                        .help("Adds a variable to the program environment, with --ecall linux")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("base")
+                        .long("base")
+                        .value_name("ADDRESS")
+                        .help("Sets where a raw input file is loaded and starts running, defaults to the start of memory")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("load")
+                        .long("load")
+                        .value_name("FILE@ADDRESS")
+                        .help("Loads another raw file at the given address")
+                        .value_parser(parse_file_at_address)
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("entry")
+                        .long("entry")
+                        .value_name("ADDRESS")
+                        .help("Sets the pc to start running from")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("no-sentinel")
+                        .long("no-sentinel")
+                        .help("Does not stop at the end of raw and hex input files")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("elf-vaddr")
+                        .long("elf-vaddr")
+                        .help("Loads ELF segments at their virtual instead of physical addresses")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("break")
+                        .long("break")
+                        .value_name("LOCATION")
+                        .help(
+                            "Dumps the registers when reaching an address or symbol like main+0x10",
+                        )
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::Arg::new("until")
+                        .long("until")
+                        .value_name("LOCATION")
+                        .help("Stops running when reaching an address or symbol like main+0x10"),
+                )
+                .arg(
+                    clap::Arg::new("trace")
+                        .long("trace")
+                        .help("Prints every executed instruction")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("ecall")
+                        .long("ecall")
+                        .value_name("MODE")
+                        .help("Sets how ecall is handled, linux emulates Linux syscalls, rars the RARS services and sbi boots the program in supervisor mode on SBI firmware")
+                        .value_parser(["none", "linux", "rars", "sbi"])
+                        .default_value("none"),
+                )
+                .arg(
+                    clap::Arg::new("libc")
+                        .long("libc")
+                        .value_name("LIBC")
+                        .help("Sets the syscall ABI for --ecall linux, auto picks linux when the ELF has __libc_start_main")
+                        .value_parser(["auto", "newlib", "linux"])
+                        .default_value("auto"),
+                )
+                .arg(
+                    clap::Arg::new("semihosting")
+                        .long("semihosting")
+                        .help("Handles RISC-V semihosting calls made with ebreak")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("fs-root")
+                        .long("fs-root")
+                        .value_name("DIRECTORY")
+                        .help("Restricts files opened by the program to this directory"),
+                )
+                .arg(
+                    clap::Arg::new("fs-read-only")
+                        .long("fs-read-only")
+                        .help("Refuses to let the program write files")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("fs-overlay")
+                        .long("fs-overlay")
+                        .help("Keeps files written by the program in memory instead of on the host")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("rars-layout")
+                        .long("rars-layout")
+                        .help("Uses the default RARS memory layout, .text at 0x00400000, .data at 0x10010000, gp at 0x10008000 and sp at 0x7fffeffc")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("ram-base")
+                        .long("ram-base")
+                        .value_name("ADDRESS")
+                        .help("Sets the address where memory starts")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("ram-size")
+                        .long("ram-size")
+                        .value_name("BYTES")
+                        .help("Sets the memory size")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("reset-pc")
+                        .long("reset-pc")
+                        .value_name("ADDRESS")
+                        .help("Sets the pc at reset, defaults to the start of memory")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("sp")
+                        .long("sp")
+                        .value_name("ADDRESS")
+                        .help("Sets the initial stack pointer")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("gp")
+                        .long("gp")
+                        .value_name("ADDRESS")
+                        .help("Sets the initial global pointer")
+                        .value_parser(parse_number),
+                )
+                .arg(
+                    clap::Arg::new("random-registers")
+                        .long("random-registers")
+                        .value_name("SEED")
+                        .help("Fills the registers with random values at reset")
+                        .num_args(0..=1)
+                        .default_missing_value("time")
+                        .value_parser(parse_seed),
+                )
+                .arg(
+                    clap::Arg::new("rtc")
+                        .long("rtc")
+                        .help("Adds a goldfish real-time clock using the host time")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("rtc-epoch")
+                        .long("rtc-epoch")
+                        .value_name("NANOSECONDS")
+                        .help("Adds a goldfish real-time clock starting at the given time and advancing 10ns per instruction")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    clap::Arg::new("fb")
+                        .long("fb")
+                        .value_name("WIDTHxHEIGHT")
+                        .help("Adds a linear framebuffer of the given size")
+                        .value_parser(parse_dimensions),
+                )
+                .arg(
+                    clap::Arg::new("fb-format")
+                        .long("fb-format")
+                        .help("Sets the framebuffer pixel format")
+                        .value_parser(["r5g6b5", "r8g8b8", "x8r8g8b8"])
+                        .default_value("x8r8g8b8"),
+                )
+                .arg(
+                    clap::Arg::new("fb-snapshot")
+                        .long("fb-snapshot")
+                        .value_name("FILE")
+                        .help("Writes the framebuffer to a .ppm or .png file")
+                        .requires("fb"),
+                )
+                .arg(
+                    clap::Arg::new("fb-snapshot-on")
+                        .long("fb-snapshot-on")
+                        .value_name("WHEN")
+                        .help("When to write the framebuffer: exit, ebreak or every N instructions")
+                        .value_parser(parse_snapshot_trigger)
+                        .default_value("exit")
+                        .requires("fb-snapshot"),
+                )
+                .arg(
+                    clap::Arg::new("gpio")
+                        .long("gpio")
+                        .help("Adds a gpio block for leds and switches")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("gpio-inputs")
+                        .long("gpio-inputs")
+                        .value_name("FILE")
+                        .help("Drives the gpio inputs from an \"instruction pin level\" timeline")
+                        .requires("gpio"),
+                )
+                .arg(
+                    clap::Arg::new("gpio-log")
+                        .long("gpio-log")
+                        .value_name("FILE")
+                        .help("Writes the gpio output changes to a file")
+                        .requires("gpio"),
+                )
+                .arg(
+                    clap::Arg::new("dtb")
+                        .long("dtb")
+                        .help("Generates a device tree for the machine and passes its address in a1")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    clap::Arg::new("dtb-out")
+                        .long("dtb-out")
+                        .value_name("FILE")
+                        .help("Writes the generated device tree blob to a file"),
+                ),
         )
         .get_matches();
 
@@ -52,16 +365,271 @@ fn main() {
             let output = args.get_one::<String>("OUTPUT").unwrap();
 
             let contents = read_string(Path::new(input));
-            let compiled_insts = assembler::assemble(&contents);
-            write_u32(Path::new(output), &compiled_insts);
+            let mut options = AssemblerOptions::new();
+            options.legacy_syntax = args.get_flag("legacy-syntax");
+            options.include_paths = args
+                .get_many::<String>("include")
+                .unwrap_or_default()
+                .map(PathBuf::from)
+                .collect();
+            options.defines = args
+                .get_many::<(String, i64)>("define")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            if let Some(base) = args.get_one::<u32>("base") {
+                options.base = *base;
+            }
+            let listing_path = args.get_one::<String>("listing");
+            options.listing = listing_path.is_some();
+            let assembly = match assembler::assemble(&contents, input, &options) {
+                Ok(assembly) => {
+                    assembly
+                        .warnings
+                        .iter()
+                        .for_each(|warning| eprintln!("{}\n", warning));
+                    if let (Some(path), Some(listing)) = (listing_path, &assembly.listing) {
+                        write_u8(Path::new(path), listing.as_bytes());
+                    }
+                    assembly
+                }
+                Err(diagnostics) => {
+                    diagnostics
+                        .iter()
+                        .for_each(|diagnostic| eprintln!("{}\n", diagnostic));
+                    let errors = diagnostics
+                        .iter()
+                        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                        .count();
+                    eprintln!("Could not assemble {}: {} errors", input, errors);
+                    std::process::exit(1);
+                }
+            };
+            let format = match args.get_one::<String>("format") {
+                Some(format) => format.as_str(),
+                None => format_from_extension(Path::new(output)),
+            };
+            match format {
+                "ihex" | "srec" => {
+                    let (address, bytes, entry) =
+                        (assembly.address, &assembly.image, assembly.entry);
+                    let text = if format == "ihex" {
+                        image::write_ihex(address, bytes, entry)
+                    } else {
+                        image::write_srec(address, bytes, entry)
+                    };
+                    write_u8(Path::new(output), text.as_bytes());
+                }
+                _ => write_u8(Path::new(output), &assembly.image),
+            }
         }
         Some(("run", args)) => {
             let input = args.get_one::<String>("INPUT").unwrap();
 
             let contents = read_to_u8(Path::new(input));
-            let mut cpu = cpu::RiscvCpu::new();
-            cpu.load_from_u8(&contents);
-            cpu.run();
+            let mut config = if args.get_flag("rars-layout") {
+                MachineConfig::rars()
+            } else {
+                MachineConfig::new()
+            };
+            if let Some(ram_base) = args.get_one::<u32>("ram-base") {
+                config = config.ram_base(*ram_base);
+            }
+            if let Some(ram_size) = args.get_one::<u32>("ram-size") {
+                config = config.ram_size(*ram_size as usize);
+            }
+            if let Some(reset_pc) = args.get_one::<u32>("reset-pc") {
+                config = config.reset_pc(*reset_pc);
+            }
+            if let Some(sp) = args.get_one::<u32>("sp") {
+                config = config.stack_pointer(*sp);
+            }
+            if let Some(gp) = args.get_one::<u32>("gp") {
+                config = config.global_pointer(*gp);
+            }
+            if let Some(seed) = args.get_one::<u64>("random-registers") {
+                println!("Randomizing registers with seed {}", seed);
+                config = config.random_registers(*seed);
+            }
+            config
+                .validate()
+                .unwrap_or_else(|err| panic!("Invalid machine configuration: {}", err));
+            let mut cpu = cpu::RiscvCpu::with_config(config);
+            let rtc_epoch = args.get_one::<u64>("rtc-epoch").copied();
+            if args.get_flag("rtc") || rtc_epoch.is_some() {
+                cpu.attach_device(GOLDFISH_RTC_BASE, Box::new(GoldfishRtc::new(rtc_epoch)))
+                    .unwrap_or_else(|err| panic!("Could not add RTC: {}", err));
+            }
+            if let Some((width, height)) = args.get_one::<(u32, u32)>("fb") {
+                let format = args.get_one::<String>("fb-format").unwrap();
+                let mut framebuffer =
+                    Framebuffer::new(*width, *height, PixelFormat::from_name(format).unwrap())
+                        .unwrap_or_else(|err| panic!("Could not add framebuffer: {}", err));
+                if let Some(snapshot) = args.get_one::<String>("fb-snapshot") {
+                    let trigger = args.get_one::<SnapshotTrigger>("fb-snapshot-on").unwrap();
+                    framebuffer.snapshot_to(Path::new(snapshot), trigger.clone());
+                }
+                cpu.attach_device(FRAMEBUFFER_BASE, Box::new(framebuffer))
+                    .unwrap_or_else(|err| panic!("Could not add framebuffer: {}", err));
+            }
+            if args.get_flag("gpio") {
+                let mut gpio = Gpio::new();
+                if let Some(inputs) = args.get_one::<String>("gpio-inputs") {
+                    gpio.load_timeline(&read_string(Path::new(inputs)))
+                        .unwrap_or_else(|err| panic!("Invalid gpio timeline: {}", err));
+                }
+                if let Some(log) = args.get_one::<String>("gpio-log") {
+                    gpio.log_to(File::create(log).expect("Could not create file"));
+                }
+                cpu.attach_device(GPIO_BASE, Box::new(gpio))
+                    .unwrap_or_else(|err| panic!("Could not add gpio: {}", err));
+            }
+            let linux = args.get_one::<String>("ecall").unwrap() == "linux";
+            if elf::is_elf(&contents) {
+                elf::parse(&contents, args.get_flag("elf-vaddr"))
+                    .and_then(|elf| {
+                        cpu.load_elf(&elf)?;
+                        if !linux {
+                            return Ok(());
+                        }
+                        let mut guest_args = vec![input.to_string()];
+                        guest_args
+                            .extend(args.get_many::<String>("ARGS").unwrap_or_default().cloned());
+                        let guest_env = args
+                            .get_many::<String>("env")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect::<Vec<String>>();
+                        cpu.setup_process_stack(&elf, &guest_args, &guest_env)
+                    })
+                    .unwrap_or_else(|err| panic!("Could not load ELF file: {}", err));
+            } else {
+                let text = String::from_utf8_lossy(&contents);
+                let parsed = if image::is_ihex(&contents) {
+                    image::parse_ihex(&text)
+                } else if image::is_srec(&contents) {
+                    image::parse_srec(&text)
+                } else {
+                    let base = args
+                        .get_one::<u32>("base")
+                        .copied()
+                        .unwrap_or(cpu.config.ram_base);
+                    let mut image = Image::from_raw(base, &contents);
+                    // a raw file has no entry of its own, it runs from its first byte
+                    if args.get_one::<u32>("reset-pc").is_none() {
+                        image.entry = Some(base);
+                    }
+                    Ok(image)
+                };
+                let sentinel = !args.get_flag("no-sentinel");
+                parsed
+                    .and_then(|mut image| {
+                        for (path, address) in
+                            args.get_many::<(String, u32)>("load").unwrap_or_default()
+                        {
+                            image.segments.push(Segment {
+                                address: *address,
+                                data: read_to_u8(Path::new(path)),
+                            });
+                        }
+                        cpu.load_image(&image, sentinel)
+                    })
+                    .unwrap_or_else(|err| panic!("Could not load file: {}", err));
+            }
+            if let Some(entry) = args.get_one::<u32>("entry") {
+                cpu.program_counter = *entry;
+            }
+            if args.get_flag("dtb") {
+                let address = cpu
+                    .load_device_tree()
+                    .unwrap_or_else(|err| panic!("Could not load device tree: {}", err));
+                println!("Device tree at 0x{:X}", address);
+            }
+            if let Some(dtb_out) = args.get_one::<String>("dtb-out") {
+                write_u8(Path::new(dtb_out), &cpu.build_device_tree());
+            }
+            match args.get_one::<String>("ecall").unwrap().as_str() {
+                "linux" => {
+                    let libc = match args.get_one::<String>("libc").unwrap().as_str() {
+                        "newlib" => Libc::Newlib,
+                        "linux" => Libc::Linux,
+                        // musl and glibc both start main through __libc_start_main
+                        _ if cpu.symbols.get("__libc_start_main").is_some() => Libc::Linux,
+                        _ => Libc::Newlib,
+                    };
+                    cpu.ecall = EcallMode::Linux(LinuxSyscalls::new(&cpu, libc));
+                    cpu.privilege = Privilege::User;
+                }
+                "rars" => {
+                    cpu.ecall = EcallMode::Rars(RarsServices::new(&cpu));
+                    cpu.privilege = Privilege::User;
+                }
+                "sbi" => {
+                    let firmware = SbiFirmware::new();
+                    firmware.delegate_traps(&mut cpu);
+                    cpu.ecall = EcallMode::Sbi(firmware);
+                    cpu.privilege = Privilege::Supervisor;
+                }
+                _ => {}
+            }
+            let mut filesystem = GuestFs::new();
+            if let Some(root) = args.get_one::<String>("fs-root") {
+                filesystem = filesystem
+                    .root(Path::new(root))
+                    .unwrap_or_else(|err| panic!("Invalid filesystem root: {}", err));
+            }
+            if args.get_flag("fs-read-only") {
+                filesystem = filesystem.read_only();
+            }
+            if args.get_flag("fs-overlay") {
+                filesystem = filesystem.overlay();
+            }
+            cpu.filesystem = filesystem;
+            if args.get_flag("semihosting") {
+                let mut cmdline = vec![input.as_str()];
+                cmdline.extend(
+                    args.get_many::<String>("ARGS")
+                        .unwrap_or_default()
+                        .map(String::as_str),
+                );
+                cpu.semihosting = Some(Semihosting::new(&cmdline.join(" ")));
+            }
+            cpu.trace = args.get_flag("trace");
+            for location in args.get_many::<String>("break").unwrap_or_default() {
+                cpu.add_breakpoint(location)
+                    .unwrap_or_else(|err| panic!("Invalid breakpoint: {}", err));
+            }
+            let until = args.get_one::<String>("until");
+            println!("Running file");
+            loop {
+                let stop = match until {
+                    Some(location) => cpu
+                        .run_until(location)
+                        .unwrap_or_else(|err| panic!("Invalid location: {}", err)),
+                    None => cpu.run(),
+                };
+                match (stop, until) {
+                    (Stop::Breakpoint(address), _) => {
+                        println!("\nBreakpoint at {}", cpu.describe_address(address));
+                        cpu.dump_registers();
+                    }
+                    (Stop::Reached, _) => {
+                        cpu.dump_registers();
+                        break;
+                    }
+                    (Stop::Ended, Some(location)) => {
+                        println!("Program ended before reaching {}", location);
+                        cpu.dump_registers();
+                        break;
+                    }
+                    (Stop::Ended, None) => break,
+                }
+            }
+            cpu.finish();
+            println!("Finished running file");
+            if let Some(code) = cpu.exit_code {
+                std::process::exit(code);
+            }
         }
         _ => {}
     }
@@ -87,10 +655,87 @@ fn read_string(path: &Path) -> String {
     contents
 }
 
-fn write_u32(path: &Path, v: &[u32]) {
+fn write_u8(path: &Path, v: &[u8]) {
     let mut file = File::create(path).expect("Could not create file");
-    let v_u8 = v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>();
     println!("Writing to file: {}", path.to_str().unwrap());
-    file.write_all(&v_u8).expect("Could not write file");
+    file.write_all(v).expect("Could not write file");
     println!("Finished writing to file");
 }
+
+fn format_from_extension(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("hex" | "ihex" | "ihx") => "ihex",
+        Some("srec" | "s19" | "s28" | "s37" | "mot") => "srec",
+        _ => "bin",
+    }
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse::<u32>(),
+    };
+    parsed.map_err(|_| format!("Invalid number / hex: {}", value))
+}
+
+fn parse_file_at_address(value: &str) -> Result<(String, u32), String> {
+    let (path, address) = value
+        .rsplit_once('@')
+        .ok_or_else(|| format!("Expected FILE@ADDRESS: {}", value))?;
+    Ok((path.to_string(), parse_number(address)?))
+}
+
+fn parse_define(value: &str) -> Result<(String, i64), String> {
+    let (name, number) = match value.split_once('=') {
+        Some((name, number)) => (name, number),
+        None => (value, "1"),
+    };
+    if name.is_empty() {
+        return Err(format!("Expected NAME[=VALUE]: {}", value));
+    }
+    let number = match number.strip_prefix('-') {
+        Some(number) => -(parse_number(number)? as i64),
+        None => parse_number(number)? as i64,
+    };
+    Ok((name.to_string(), number))
+}
+
+fn parse_seed(value: &str) -> Result<u64, String> {
+    match value {
+        "time" => Ok(XorShift::seed_from_time()),
+        _ => value
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid seed: {}", value)),
+    }
+}
+
+fn parse_dimensions(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT: {}", value))?;
+    let width = width.parse::<u32>().map_err(|e| e.to_string())?;
+    let height = height.parse::<u32>().map_err(|e| e.to_string())?;
+    // checked against the widest pixel format, the format is chosen separately
+    Framebuffer::size_of(width, height, PixelFormat::LARGEST)?;
+    Ok((width, height))
+}
+
+fn parse_snapshot_trigger(value: &str) -> Result<SnapshotTrigger, String> {
+    match value {
+        "exit" => Ok(SnapshotTrigger::Exit),
+        "ebreak" => Ok(SnapshotTrigger::Ebreak),
+        _ => match value.parse::<u64>() {
+            Ok(every) if every != 0 => Ok(SnapshotTrigger::Every(every)),
+            _ => Err(format!(
+                "Expected exit, ebreak or an instruction count: {}",
+                value
+            )),
+        },
+    }
+}
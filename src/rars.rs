@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::RARS_DATA_BASE;
+use cpu::RiscvCpu;
+use rng::XorShift;
+use types::*;
+
+// service numbers as in the RARS and SPIM help
+const PRINT_INT: u32 = 1;
+const PRINT_STRING: u32 = 4;
+const READ_INT: u32 = 5;
+const READ_STRING: u32 = 8;
+const SBRK: u32 = 9;
+const EXIT: u32 = 10;
+const PRINT_CHAR: u32 = 11;
+const READ_CHAR: u32 = 12;
+const EXIT2: u32 = 17;
+const TIME: u32 = 30;
+const SLEEP: u32 = 32;
+const PRINT_INT_HEX: u32 = 34;
+const PRINT_INT_BINARY: u32 = 35;
+const PRINT_INT_UNSIGNED: u32 = 36;
+const RAND_SEED: u32 = 40;
+const RAND_INT: u32 = 41;
+const RAND_INT_RANGE: u32 = 42;
+
+// sbrk starts here when the memory reaches it, as in RARS
+const RARS_HEAP_BASE: u32 = RARS_DATA_BASE + 0x3_0000;
+
+pub struct RarsServices {
+    heap: u32,
+    // generators are selected by the id in a0
+    generators: HashMap<u32, XorShift>,
+}
+
+fn read_line() -> Result<String, String> {
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|err| format!("could not read from stdin: {}", err))?;
+    Ok(line)
+}
+
+fn parse_int(line: &str) -> Result<i32, String> {
+    line.trim()
+        .parse::<i32>()
+        .map_err(|_| format!("invalid integer input {:?}", line.trim()))
+}
+
+impl RarsServices {
+    pub fn new(cpu: &RiscvCpu) -> RarsServices {
+        let image_end = (cpu.image_end + 3) & !3;
+        let heap = if image_end <= RARS_HEAP_BASE && cpu.memory_after(RARS_HEAP_BASE).is_some() {
+            RARS_HEAP_BASE
+        } else {
+            image_end
+        };
+        RarsServices {
+            heap,
+            generators: HashMap::new(),
+        }
+    }
+    fn generator(&mut self, id: u32) -> &mut XorShift {
+        self.generators
+            .entry(id)
+            .or_insert_with(|| XorShift::new(XorShift::seed_from_time()))
+    }
+    fn print(&self, text: &str) {
+        let mut stdout = io::stdout();
+        stdout
+            .write_all(text.as_bytes())
+            .and_then(|_| stdout.flush())
+            .expect("Could not write to stdout");
+    }
+    // the heap grows by a0 bytes, rounded up to a word
+    fn sbrk(&mut self, cpu: &mut RiscvCpu, amount: u32) -> Result<u32, String> {
+        if (amount as i32) < 0 {
+            return Err(format!("sbrk of a negative amount {}", amount as i32));
+        }
+        let address = self.heap;
+        let heap = address
+            .checked_add(amount)
+            .and_then(|heap| heap.checked_add(3))
+            .map(|heap| heap & !3)
+            .ok_or_else(|| format!("sbrk of {} bytes at 0x{:08X} overflows", amount, address))?;
+        let size = (heap - address) as usize;
+        match cpu.memory_after(address) {
+            Some(room) if size <= room => cpu.write_bytes(address, &vec![0u8; size])?,
+            _ => {
+                return Err(format!(
+                    "sbrk of {} bytes at 0x{:08X} is out of memory",
+                    amount, address
+                ))
+            }
+        }
+        self.heap = heap;
+        Ok(address)
+    }
+    // a failing service ends the program like a RARS runtime exception
+    pub fn handle(&mut self, cpu: &mut RiscvCpu) {
+        let service = cpu.get_register(R_A7);
+        if let Err(err) = self.service(cpu, service) {
+            eprintln!(
+                "RARS service {} failed at pc 0x{:08X}: {}",
+                service, cpu.program_counter, err
+            );
+            cpu.exit_code = Some(1);
+        }
+    }
+    fn service(&mut self, cpu: &mut RiscvCpu, service: u32) -> Result<(), String> {
+        let a0 = cpu.get_register(R_A0);
+        let a1 = cpu.get_register(R_A1);
+        match service {
+            PRINT_INT => self.print(&(a0 as i32).to_string()),
+            PRINT_INT_HEX => self.print(&format!("0x{:08x}", a0)),
+            PRINT_INT_BINARY => self.print(&format!("{:032b}", a0)),
+            PRINT_INT_UNSIGNED => self.print(&a0.to_string()),
+            PRINT_STRING => {
+                let text = cpu.read_c_bytes(a0)?;
+                self.print(&String::from_utf8_lossy(text));
+            }
+            PRINT_CHAR => self.print(&(a0 as u8 as char).to_string()),
+            READ_INT => {
+                let value = parse_int(&read_line()?)?;
+                cpu.set_register(R_A0, value as u32);
+            }
+            READ_STRING => {
+                // at most a1 - 1 characters, the newline is kept if it fits
+                let max_len = (a1 as usize).saturating_sub(1);
+                let line = read_line()?;
+                let mut bytes = line.as_bytes()[..line.len().min(max_len)].to_vec();
+                bytes.push(0);
+                if a1 > 0 {
+                    cpu.write_bytes(a0, &bytes)?;
+                }
+            }
+            READ_CHAR => {
+                let mut byte = [0u8];
+                io::stdin()
+                    .read_exact(&mut byte)
+                    .map_err(|err| format!("could not read from stdin: {}", err))?;
+                cpu.set_register(R_A0, byte[0] as u32);
+            }
+            SBRK => {
+                let address = self.sbrk(cpu, a0)?;
+                cpu.set_register(R_A0, address);
+            }
+            EXIT => cpu.exit_code = Some(0),
+            EXIT2 => cpu.exit_code = Some(a0 as i32),
+            TIME => {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_millis() as u64);
+                cpu.set_register(R_A0, millis as u32);
+                cpu.set_register(R_A1, (millis >> 32) as u32);
+            }
+            SLEEP => thread::sleep(Duration::from_millis(a0 as u64)),
+            RAND_SEED => *self.generator(a0) = XorShift::new(a1 as u64),
+            RAND_INT => {
+                let value = self.generator(a0).next_u32();
+                cpu.set_register(R_A0, value);
+            }
+            RAND_INT_RANGE => {
+                if a1 == 0 {
+                    return Err("upper bound of random range is 0".to_string());
+                }
+                let value = self.generator(a0).next_u32() % a1;
+                cpu.set_register(R_A0, value);
+            }
+            _ => eprintln!(
+                "Unimplemented RARS service {} at pc 0x{:08X}",
+                service, cpu.program_counter
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+
+    fn machine() -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new().ram_size(0x1000));
+        cpu.reset();
+        cpu.image_end = 0x101;
+        cpu
+    }
+
+    fn call(services: &mut RarsServices, cpu: &mut RiscvCpu, service: u32, a0: u32, a1: u32) {
+        cpu.set_register(R_A7, service);
+        cpu.set_register(R_A0, a0);
+        cpu.set_register(R_A1, a1);
+        services.handle(cpu);
+    }
+
+    #[test]
+    fn sbrk_hands_out_word_aligned_blocks() {
+        let mut cpu = machine();
+        let mut services = RarsServices::new(&cpu);
+        call(&mut services, &mut cpu, SBRK, 5, 0);
+        assert_eq!(cpu.get_register(R_A0), 0x104);
+        call(&mut services, &mut cpu, SBRK, 4, 0);
+        assert_eq!(cpu.get_register(R_A0), 0x10c);
+        assert_eq!(cpu.exit_code, None);
+    }
+
+    #[test]
+    fn sbrk_past_memory_ends_the_program() {
+        let mut cpu = machine();
+        let mut services = RarsServices::new(&cpu);
+        call(&mut services, &mut cpu, SBRK, 0x7fff_fffe, 0);
+        assert_eq!(cpu.exit_code, Some(1));
+        assert!(services.sbrk(&mut cpu, 0x1000).is_err());
+        assert!(services.sbrk(&mut cpu, 0xffff_fffc).is_err());
+        // a failed sbrk leaves the heap where it was
+        assert_eq!(services.sbrk(&mut cpu, 0), Ok(0x104));
+    }
+
+    #[test]
+    fn heap_starts_at_the_rars_heap_base_when_memory_reaches_it() {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new().ram_size(0x1010_0000));
+        cpu.reset();
+        cpu.image_end = 0x40;
+        let mut services = RarsServices::new(&cpu);
+        assert_eq!(services.sbrk(&mut cpu, 0), Ok(RARS_HEAP_BASE));
+    }
+
+    #[test]
+    fn bad_string_pointer_ends_the_program() {
+        let mut cpu = machine();
+        let mut services = RarsServices::new(&cpu);
+        call(&mut services, &mut cpu, PRINT_STRING, 0x8000_0000, 0);
+        assert_eq!(cpu.exit_code, Some(1));
+    }
+
+    #[test]
+    fn random_range_of_zero_ends_the_program() {
+        let mut cpu = machine();
+        let mut services = RarsServices::new(&cpu);
+        call(&mut services, &mut cpu, RAND_SEED, 0, 7);
+        call(&mut services, &mut cpu, RAND_INT_RANGE, 0, 10);
+        assert!(cpu.get_register(R_A0) < 10);
+        assert_eq!(cpu.exit_code, None);
+        call(&mut services, &mut cpu, RAND_INT_RANGE, 0, 0);
+        assert_eq!(cpu.exit_code, Some(1));
+    }
+
+    #[test]
+    fn parses_integer_input() {
+        assert_eq!(parse_int(" -42\n"), Ok(-42));
+        assert!(parse_int("forty-two\n").is_err());
+        assert!(parse_int("\n").is_err());
+    }
+
+    #[test]
+    fn exit2_returns_the_code() {
+        let mut cpu = machine();
+        let mut services = RarsServices::new(&cpu);
+        call(&mut services, &mut cpu, EXIT2, 3, 0);
+        assert_eq!(cpu.exit_code, Some(3));
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// xorshift64*, good enough for register noise and guest random numbers
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+    pub fn seed_from_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        if self.state == 0 {
+            self.state = 0x9E37_79B9_7F4A_7C15;
+        }
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::{self, Read, Write};
+
+use cpu::RiscvCpu;
+use types::*;
+
+// legacy extensions return their value in a0 only
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+
+const EXT_BASE: u32 = 0x10;
+const EXT_TIME: u32 = 0x5449_4d45;
+const EXT_IPI: u32 = 0x0073_5049;
+const EXT_RFENCE: u32 = 0x5246_4e43;
+const EXT_HSM: u32 = 0x0048_534d;
+const EXT_SRST: u32 = 0x5352_5354;
+
+const SBI_SUCCESS: i32 = 0;
+const SBI_ERR_NOT_SUPPORTED: i32 = -2;
+const SBI_ERR_INVALID_PARAM: i32 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i32 = -6;
+
+// version 1.0
+const SBI_SPEC_VERSION: u32 = 1 << 24;
+// not a registered implementation id
+const SBI_IMPL_ID: u32 = 0xffff_ffff;
+const SBI_IMPL_VERSION: u32 = 1;
+
+const HSM_STARTED: u32 = 0;
+const HSM_SUSPEND_RETENTIVE: u32 = 0;
+
+const SRST_SHUTDOWN: u32 = 0;
+const SRST_COLD_REBOOT: u32 = 1;
+const SRST_WARM_REBOOT: u32 = 2;
+const SRST_NO_REASON: u32 = 0;
+
+// there is only hart 0
+const HART_ID: u32 = 0;
+
+pub struct SbiFirmware;
+
+impl SbiFirmware {
+    pub fn new() -> SbiFirmware {
+        SbiFirmware
+    }
+    // like OpenSBI, supervisor interrupts and the exceptions of user programs go to stvec
+    pub fn delegate_traps(&self, cpu: &mut RiscvCpu) {
+        cpu.csrs[CSR_MIDELEG] = MIP_SSIP | MIP_STIP | MIP_SEIP;
+        cpu.csrs[CSR_MEDELEG] = (1 << CAUSE_ILLEGAL_INSTRUCTION) | (1 << CAUSE_USER_ECALL);
+    }
+    // a7 holds the extension, a6 the function, the error goes to a0 and the value to a1
+    pub fn handle(&mut self, cpu: &mut RiscvCpu) {
+        let extension = cpu.get_register(R_A7);
+        let function = cpu.get_register(R_A6);
+        let a0 = cpu.get_register(R_A0);
+        let a1 = cpu.get_register(R_A1);
+        if extension <= 0x0f {
+            let value = self.legacy(cpu, extension, a0, a1);
+            cpu.set_register(R_A0, value as u32);
+            return;
+        }
+        let result = match extension {
+            EXT_BASE => self.base(function, a0),
+            EXT_TIME if function == 0 => {
+                set_timer(cpu, ((a1 as u64) << 32) | a0 as u64);
+                Ok(0)
+            }
+            EXT_IPI if function == 0 => {
+                // the mask starts at hart a1, or covers all harts when a1 is -1
+                let selected = a1 == u32::MAX || (a1 == HART_ID && a0 & 1 != 0);
+                if selected {
+                    cpu.csrs[CSR_MIP] |= MIP_SSIP;
+                }
+                Ok(0)
+            }
+            // no caches or address translation, fences have nothing to do
+            EXT_RFENCE if function <= 2 => Ok(0),
+            EXT_HSM => self.hsm(cpu, function, a0),
+            EXT_SRST if function == 0 => self.system_reset(cpu, a0, a1),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        };
+        let (error, value) = match result {
+            Ok(value) => (SBI_SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+        cpu.set_register(R_A0, error as u32);
+        cpu.set_register(R_A1, value);
+    }
+    fn legacy(&mut self, cpu: &mut RiscvCpu, extension: u32, a0: u32, a1: u32) -> i32 {
+        match extension {
+            LEGACY_SET_TIMER => {
+                set_timer(cpu, ((a1 as u64) << 32) | a0 as u64);
+                0
+            }
+            LEGACY_CONSOLE_PUTCHAR => {
+                let mut stdout = io::stdout();
+                stdout
+                    .write_all(&[a0 as u8])
+                    .and_then(|_| stdout.flush())
+                    .expect("Could not write to stdout");
+                0
+            }
+            LEGACY_CONSOLE_GETCHAR => {
+                let mut byte = [0u8];
+                match io::stdin().read(&mut byte) {
+                    Ok(1) => byte[0] as i32,
+                    _ => -1,
+                }
+            }
+            LEGACY_SHUTDOWN => {
+                cpu.exit_code = Some(0);
+                0
+            }
+            _ => SBI_ERR_NOT_SUPPORTED,
+        }
+    }
+    fn base(&self, function: u32, a0: u32) -> Result<u32, i32> {
+        match function {
+            0 => Ok(SBI_SPEC_VERSION),
+            1 => Ok(SBI_IMPL_ID),
+            2 => Ok(SBI_IMPL_VERSION),
+            3 => {
+                let supported = matches!(
+                    a0,
+                    LEGACY_SET_TIMER
+                        | LEGACY_CONSOLE_PUTCHAR
+                        | LEGACY_CONSOLE_GETCHAR
+                        | LEGACY_SHUTDOWN
+                        | EXT_BASE
+                        | EXT_TIME
+                        | EXT_IPI
+                        | EXT_RFENCE
+                        | EXT_HSM
+                        | EXT_SRST
+                );
+                Ok(supported as u32)
+            }
+            // mvendorid, marchid and mimpid
+            4..=6 => Ok(0),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+    fn hsm(&mut self, cpu: &mut RiscvCpu, function: u32, a0: u32) -> Result<u32, i32> {
+        match function {
+            // hart_start, the only hart is the one making the call
+            0 if a0 == HART_ID => Err(SBI_ERR_ALREADY_AVAILABLE),
+            0 => Err(SBI_ERR_INVALID_PARAM),
+            // hart_stop, stopping the last hart stops the machine
+            1 => {
+                cpu.exit_code = Some(0);
+                Ok(0)
+            }
+            2 if a0 == HART_ID => Ok(HSM_STARTED),
+            2 => Err(SBI_ERR_INVALID_PARAM),
+            // a retentive suspend returns right away like wfi
+            3 if a0 == HSM_SUSPEND_RETENTIVE => Ok(0),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+    fn system_reset(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        reset_type: u32,
+        reason: u32,
+    ) -> Result<u32, i32> {
+        match reset_type {
+            SRST_SHUTDOWN => println!("\nSystem shutdown"),
+            // the loaded program is gone after a reset, so a reboot also stops
+            SRST_COLD_REBOOT | SRST_WARM_REBOOT => println!("\nSystem reboot"),
+            _ => return Err(SBI_ERR_INVALID_PARAM),
+        }
+        cpu.exit_code = Some(if reason == SRST_NO_REASON { 0 } else { 1 });
+        Ok(0)
+    }
+}
+
+fn set_timer(cpu: &mut RiscvCpu, time: u64) {
+    cpu.timer_compare = Some(time);
+    cpu.csrs[CSR_MIP] &= !MIP_STIP;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+    use cpu::Privilege;
+    use syscalls::EcallMode;
+
+    const ECALL: u32 = 0x0000_0073;
+
+    fn supervisor() -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        cpu.reset();
+        let firmware = SbiFirmware::new();
+        firmware.delegate_traps(&mut cpu);
+        cpu.ecall = EcallMode::Sbi(firmware);
+        cpu.privilege = Privilege::Supervisor;
+        cpu.program_counter = 0x100;
+        cpu.csrs[CSR_MTVEC] = 0x200;
+        cpu.csrs[CSR_STVEC] = 0x300;
+        cpu
+    }
+
+    fn call(cpu: &mut RiscvCpu, extension: u32, function: u32, a0: u32) -> (u32, u32) {
+        cpu.set_register(R_A7, extension);
+        cpu.set_register(R_A6, function);
+        cpu.set_register(R_A0, a0);
+        cpu.execute_inst(ECALL);
+        (cpu.get_register(R_A0), cpu.get_register(R_A1))
+    }
+
+    #[test]
+    fn supervisor_calls_are_handled_by_the_firmware() {
+        let mut cpu = supervisor();
+        assert_eq!(call(&mut cpu, EXT_BASE, 0, 0), (0, SBI_SPEC_VERSION));
+        assert_eq!(cpu.program_counter, 0x104);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(call(&mut cpu, EXT_BASE, 3, EXT_SRST), (0, 1));
+        assert_eq!(call(&mut cpu, EXT_BASE, 3, 0x1234), (0, 0));
+        assert_eq!(
+            call(&mut cpu, 0x1234, 0, 0),
+            (SBI_ERR_NOT_SUPPORTED as u32, 0)
+        );
+    }
+
+    #[test]
+    fn user_ecalls_and_illegal_instructions_go_to_the_supervisor() {
+        let mut cpu = supervisor();
+        cpu.privilege = Privilege::User;
+        cpu.execute_inst(ECALL);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.program_counter, 0x300);
+        assert_eq!(cpu.csrs[CSR_SCAUSE], CAUSE_USER_ECALL as u64);
+
+        cpu.privilege = Privilege::User;
+        cpu.execute_inst(0xffff_ffff);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.csrs[CSR_SCAUSE], CAUSE_ILLEGAL_INSTRUCTION as u64);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], 0);
+    }
+
+    #[test]
+    fn set_timer_clears_the_pending_timer_interrupt() {
+        let mut cpu = supervisor();
+        cpu.csrs[CSR_MIP] = MIP_STIP;
+        cpu.set_register(R_A1, 0);
+        call(&mut cpu, EXT_TIME, 0, 1000);
+        assert_eq!(cpu.timer_compare, Some(1000));
+        assert_eq!(cpu.csrs[CSR_MIP] & MIP_STIP, 0);
+    }
+
+    #[test]
+    fn shutdown_ends_the_program() {
+        let mut cpu = supervisor();
+        cpu.set_register(R_A1, SRST_NO_REASON);
+        call(&mut cpu, EXT_SRST, 0, SRST_SHUTDOWN);
+        assert_eq!(cpu.exit_code, Some(0));
+    }
+}
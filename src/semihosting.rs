@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Instant;
+
+use cpu::RiscvCpu;
+use guest_fs::{GuestFile, OpenMode};
+use types::*;
+
+// slli x0, x0, 0x1f / ebreak / srai x0, x0, 7
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+const EBADF: u32 = 9;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const EFAULT: u32 = 14;
+
+pub struct Semihosting {
+    files: HashMap<u32, GuestFile>,
+    cmdline: String,
+    errno: u32,
+    start: Instant,
+}
+
+fn errno(err: &io::Error) -> u32 {
+    err.raw_os_error().map_or(EIO, |errno| errno as u32)
+}
+
+impl Semihosting {
+    pub fn new(cmdline: &str) -> Semihosting {
+        Semihosting {
+            files: HashMap::new(),
+            cmdline: cmdline.to_string(),
+            errno: 0,
+            start: Instant::now(),
+        }
+    }
+    fn argument(&self, cpu: &RiscvCpu, block: u32, index: u32) -> Result<u32, String> {
+        let address = block
+            .checked_add(index * 4)
+            .ok_or_else(|| format!("parameter block at 0x{:08X} wraps around", block))?;
+        let bytes = cpu.read_bytes(address, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    fn fail(&mut self, errno: u32) -> u32 {
+        self.errno = errno;
+        u32::MAX
+    }
+    // a0 holds the operation and a1 the parameter, usually a block of words
+    pub fn handle(&mut self, cpu: &mut RiscvCpu) {
+        let operation = cpu.get_register(R_A0);
+        let parameter = cpu.get_register(R_A1);
+        let result = match operation {
+            SYS_OPEN => self.open(cpu, parameter),
+            SYS_CLOSE => self.close(cpu, parameter),
+            SYS_WRITEC => cpu.read_bytes(parameter, 1).map(|byte| {
+                self.write_stdout(byte);
+                0
+            }),
+            SYS_WRITE0 => cpu.read_c_bytes(parameter).map(|text| {
+                self.write_stdout(text);
+                0
+            }),
+            SYS_WRITE => self.write(cpu, parameter),
+            SYS_READ => self.read(cpu, parameter),
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u32),
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => self.get_cmdline(cpu, parameter),
+            SYS_EXIT => {
+                // on RV32 the reason is passed directly instead of in a block
+                let code = if parameter == ADP_STOPPED_APPLICATION_EXIT {
+                    0
+                } else {
+                    1
+                };
+                cpu.exit_code = Some(code);
+                Ok(0)
+            }
+            SYS_EXIT_EXTENDED => self.argument(cpu, parameter, 0).and_then(|reason| {
+                let subcode = self.argument(cpu, parameter, 1)?;
+                cpu.exit_code = Some(if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    subcode as i32
+                } else {
+                    1
+                });
+                Ok(0)
+            }),
+            _ => {
+                eprintln!(
+                    "Unimplemented semihosting operation 0x{:X} at pc 0x{:08X}",
+                    operation, cpu.program_counter
+                );
+                Ok(u32::MAX)
+            }
+        };
+        let value = result.unwrap_or_else(|err| {
+            eprintln!("Invalid semihosting parameter: {}", err);
+            u32::MAX
+        });
+        cpu.set_register(R_A0, value);
+    }
+    fn write_stdout(&mut self, data: &[u8]) {
+        let mut stdout = io::stdout();
+        stdout
+            .write_all(data)
+            .and_then(|_| stdout.flush())
+            .expect("Could not write to stdout");
+    }
+    fn open(&mut self, cpu: &mut RiscvCpu, block: u32) -> Result<u32, String> {
+        let path = cpu.read_c_bytes(self.argument(cpu, block, 0)?)?;
+        let path = match String::from_utf8(path.to_vec()) {
+            Ok(path) => path,
+            Err(_) => return Ok(self.fail(EINVAL)),
+        };
+        // the fopen modes "r", "rb", "r+", "r+b", "w", ... "a+b" in order
+        let mode = self.argument(cpu, block, 1)?;
+        let file = if path == ":tt" {
+            match mode / 4 {
+                0 => GuestFile::Stdin,
+                1 => GuestFile::Stdout,
+                _ => GuestFile::Stderr,
+            }
+        } else {
+            let update = mode & 2 != 0;
+            let mode = match mode / 4 {
+                0 => OpenMode {
+                    read: true,
+                    write: update,
+                    ..OpenMode::default()
+                },
+                1 => OpenMode {
+                    read: update,
+                    write: true,
+                    create: true,
+                    truncate: true,
+                    ..OpenMode::default()
+                },
+                2 => OpenMode {
+                    read: update,
+                    write: true,
+                    create: true,
+                    append: true,
+                    ..OpenMode::default()
+                },
+                _ => return Ok(self.fail(EINVAL)),
+            };
+            match cpu.filesystem.open(&path, mode) {
+                Ok(file) => file,
+                Err(errno) => return Ok(self.fail(errno as u32)),
+            }
+        };
+        let handle = (1..)
+            .find(|handle| !self.files.contains_key(handle))
+            .unwrap();
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+    fn close(&mut self, cpu: &RiscvCpu, block: u32) -> Result<u32, String> {
+        let handle = self.argument(cpu, block, 0)?;
+        match self.files.remove(&handle) {
+            Some(_) => Ok(0),
+            None => Ok(self.fail(EBADF)),
+        }
+    }
+    // returns the number of bytes that were not written
+    fn write(&mut self, cpu: &RiscvCpu, block: u32) -> Result<u32, String> {
+        let handle = self.argument(cpu, block, 0)?;
+        let length = self.argument(cpu, block, 2)?;
+        let data = cpu.read_bytes(self.argument(cpu, block, 1)?, length as usize)?;
+        let written = match self.files.get_mut(&handle) {
+            Some(file) => file.write(data),
+            None => return Ok(self.fail(EBADF)),
+        };
+        match written {
+            Ok(()) => Ok(0),
+            Err(err) => {
+                self.fail(errno(&err));
+                Ok(length)
+            }
+        }
+    }
+    // returns the number of bytes that were not read
+    fn read(&mut self, cpu: &mut RiscvCpu, block: u32) -> Result<u32, String> {
+        let handle = self.argument(cpu, block, 0)?;
+        let buffer = self.argument(cpu, block, 1)?;
+        let length = self.argument(cpu, block, 2)?;
+        // the buffer has to fit in memory before anything is allocated for it
+        let mut data = match cpu.memory_after(buffer) {
+            Some(room) if length as usize <= room => vec![0u8; length as usize],
+            _ => {
+                self.fail(EFAULT);
+                return Ok(length);
+            }
+        };
+        let read = match self.files.get_mut(&handle) {
+            Some(file) => file.read(&mut data),
+            None => return Ok(self.fail(EBADF)),
+        };
+        match read {
+            Ok(read) => {
+                cpu.write_bytes(buffer, &data[..read])?;
+                Ok(length - read as u32)
+            }
+            Err(err) => {
+                self.fail(errno(&err));
+                Ok(length)
+            }
+        }
+    }
+    fn get_cmdline(&mut self, cpu: &mut RiscvCpu, block: u32) -> Result<u32, String> {
+        let buffer = self.argument(cpu, block, 0)?;
+        let length = self.argument(cpu, block, 1)?;
+        let mut bytes = self.cmdline.as_bytes().to_vec();
+        bytes.push(0);
+        if bytes.len() > length as usize {
+            return Ok(u32::MAX);
+        }
+        cpu.write_bytes(buffer, &bytes)?;
+        let length_address = block
+            .checked_add(4)
+            .ok_or_else(|| format!("parameter block at 0x{:08X} wraps around", block))?;
+        cpu.write_bytes(length_address, &(bytes.len() as u32 - 1).to_le_bytes())?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+    use cpu::Stop;
+
+    const BLOCK: u32 = 0x100;
+
+    fn machine() -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new().ram_size(0x1000));
+        cpu.reset();
+        cpu
+    }
+
+    fn call(cpu: &mut RiscvCpu, operation: u32, parameter: u32) -> u32 {
+        let mut semihosting = Semihosting::new("program");
+        cpu.set_register(R_A0, operation);
+        cpu.set_register(R_A1, parameter);
+        semihosting.handle(cpu);
+        cpu.get_register(R_A0)
+    }
+
+    fn block(cpu: &mut RiscvCpu, words: &[u32]) {
+        for (index, word) in words.iter().enumerate() {
+            cpu.write_bytes(BLOCK + 4 * index as u32, &word.to_le_bytes())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn write0_prints_up_to_the_terminator() {
+        let mut cpu = machine();
+        cpu.write_bytes(0x200, b"\0").unwrap();
+        assert_eq!(call(&mut cpu, SYS_WRITE0, 0x200), 0);
+        // no terminator before the end of memory
+        cpu.write_bytes(0xfff, b"x").unwrap();
+        assert_eq!(call(&mut cpu, SYS_WRITE0, 0xfff), u32::MAX);
+    }
+
+    #[test]
+    fn exit_reports_success_only_for_application_exit() {
+        let mut cpu = machine();
+        call(&mut cpu, SYS_EXIT, ADP_STOPPED_APPLICATION_EXIT);
+        assert_eq!(cpu.exit_code, Some(0));
+        let mut cpu = machine();
+        call(&mut cpu, SYS_EXIT, 0x20023);
+        assert_eq!(cpu.exit_code, Some(1));
+        let mut cpu = machine();
+        block(&mut cpu, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+        call(&mut cpu, SYS_EXIT_EXTENDED, BLOCK);
+        assert_eq!(cpu.exit_code, Some(3));
+    }
+
+    #[test]
+    fn read_past_memory_fails_without_allocating() {
+        let mut cpu = machine();
+        let mut semihosting = Semihosting::new("");
+        semihosting.files.insert(1, GuestFile::Stdin);
+        block(&mut cpu, &[1, 0x800, 0x900]);
+        cpu.set_register(R_A0, SYS_READ);
+        cpu.set_register(R_A1, BLOCK);
+        semihosting.handle(&mut cpu);
+        // nothing was read
+        assert_eq!(cpu.get_register(R_A0), 0x900);
+        assert_eq!(semihosting.errno, EFAULT);
+    }
+
+    #[test]
+    fn parameter_block_at_the_top_of_memory_is_rejected() {
+        let mut cpu = machine();
+        assert!(Semihosting::new("")
+            .argument(&cpu, u32::MAX - 3, 1)
+            .is_err());
+        assert_eq!(call(&mut cpu, SYS_EXIT_EXTENDED, u32::MAX - 3), u32::MAX);
+        assert_eq!(cpu.exit_code, None);
+    }
+
+    #[test]
+    fn get_cmdline_fills_the_buffer_and_length() {
+        let mut cpu = machine();
+        block(&mut cpu, &[0x200, 16]);
+        assert_eq!(call(&mut cpu, SYS_GET_CMDLINE, BLOCK), 0);
+        assert_eq!(cpu.read_c_bytes(0x200).unwrap(), b"program");
+        assert_eq!(cpu.read_bytes(BLOCK + 4, 4).unwrap(), &7u32.to_le_bytes());
+        block(&mut cpu, &[0x200, 4]);
+        assert_eq!(call(&mut cpu, SYS_GET_CMDLINE, BLOCK), u32::MAX);
+    }
+
+    // an ebreak only makes a call when wrapped in the entry and exit markers
+    fn trap_sequence(entry: u32) -> RiscvCpu {
+        let mut cpu = machine();
+        let ebreak = 0x0010_0073u32;
+        let program = [entry, ebreak, SEMIHOSTING_EXIT, 0xDEADC0DE];
+        for (index, word) in program.iter().enumerate() {
+            cpu.write_bytes(4 * index as u32, &word.to_le_bytes())
+                .unwrap();
+        }
+        cpu.semihosting = Some(Semihosting::new(""));
+        cpu.set_register(R_A0, SYS_EXIT);
+        cpu.set_register(R_A1, ADP_STOPPED_APPLICATION_EXIT);
+        cpu
+    }
+
+    #[test]
+    fn ebreak_between_the_markers_is_a_call() {
+        let mut cpu = trap_sequence(SEMIHOSTING_ENTRY);
+        assert_eq!(cpu.run(), Stop::Ended);
+        assert_eq!(cpu.exit_code, Some(0));
+        assert_eq!(cpu.program_counter, 8);
+    }
+
+    #[test]
+    fn ebreak_without_the_entry_marker_is_not_a_call() {
+        // addi x0, x0, 0 instead of slli x0, x0, 0x1f
+        let mut cpu = trap_sequence(0x13);
+        assert_eq!(cpu.run(), Stop::Ended);
+        assert_eq!(cpu.exit_code, None);
+    }
+}
@@ -0,0 +1,166 @@
+use cpu::RiscvCpu;
+use elf::ElfFile;
+use rng::XorShift;
+use types::*;
+
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_RANDOM: u32 = 25;
+
+const PAGE_SIZE: u32 = 0x1000;
+
+// the address size bytes below top, when there is room for them
+fn below(top: u32, size: usize) -> Result<u32, String> {
+    (top as usize)
+        .checked_sub(size)
+        .map(|address| address as u32)
+        .ok_or_else(|| "The arguments and environment do not fit on the stack".to_string())
+}
+
+impl RiscvCpu {
+    // the initial stack of a Linux process, from sp upwards:
+    // argc, argv[], NULL, envp[], NULL, auxv pairs, AT_NULL, then the strings
+    pub fn setup_process_stack(
+        &mut self,
+        elf: &ElfFile,
+        args: &[String],
+        env: &[String],
+    ) -> Result<(), String> {
+        let mut top = self.get_register(R_SP);
+
+        let mut rng = XorShift::new(
+            self.config
+                .random_registers
+                .unwrap_or_else(XorShift::seed_from_time),
+        );
+        let random = (0..4)
+            .flat_map(|_| rng.next_u32().to_le_bytes())
+            .collect::<Vec<u8>>();
+        top = below(top, random.len())?;
+        self.write_bytes(top, &random)?;
+        let random_address = top;
+
+        let mut push_strings = |cpu: &mut RiscvCpu, strings: &[String]| {
+            let mut addresses = Vec::new();
+            for string in strings {
+                let mut bytes = string.as_bytes().to_vec();
+                bytes.push(0);
+                top = below(top, bytes.len())?;
+                cpu.write_bytes(top, &bytes)?;
+                addresses.push(top);
+            }
+            Ok::<Vec<u32>, String>(addresses)
+        };
+        let env_addresses = push_strings(self, env)?;
+        let arg_addresses = push_strings(self, args)?;
+
+        let mut auxv = Vec::new();
+        if let Some(program_headers) = elf.program_headers {
+            auxv.extend([
+                (AT_PHDR, program_headers),
+                (AT_PHENT, elf.program_header_size),
+                (AT_PHNUM, elf.program_header_count),
+            ]);
+        }
+        auxv.extend([
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry),
+            (AT_RANDOM, random_address),
+            (AT_NULL, 0),
+        ]);
+
+        let mut words = vec![args.len() as u32];
+        words.extend(&arg_addresses);
+        words.push(0);
+        words.extend(&env_addresses);
+        words.push(0);
+        auxv.iter()
+            .for_each(|&(key, value)| words.extend([key, value]));
+
+        let sp = below(top, words.len() * 4)? & !0xf;
+        let bytes = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>();
+        self.write_bytes(sp, &bytes)?;
+        self.set_register(R_SP, sp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+
+    fn elf() -> ElfFile {
+        ElfFile {
+            entry: 0x1000,
+            program_headers: Some(0x34),
+            program_header_size: 32,
+            program_header_count: 2,
+            segments: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+
+    fn word(cpu: &RiscvCpu, address: u32) -> u32 {
+        let bytes = cpu.read_bytes(address, 4).unwrap();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn string(cpu: &RiscvCpu, address: u32) -> String {
+        String::from_utf8(cpu.read_c_bytes(address).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn lays_out_argv_envp_and_auxv() {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new().random_registers(1));
+        cpu.reset();
+        let top = cpu.get_register(R_SP);
+        let args = ["prog".to_string(), "-v".to_string()];
+        let env = ["HOME=/".to_string()];
+        cpu.setup_process_stack(&elf(), &args, &env).unwrap();
+
+        let sp = cpu.get_register(R_SP);
+        assert_eq!(sp % 16, 0);
+        assert!(sp < top);
+        assert_eq!(word(&cpu, sp), 2);
+        assert_eq!(string(&cpu, word(&cpu, sp + 4)), "prog");
+        assert_eq!(string(&cpu, word(&cpu, sp + 8)), "-v");
+        assert_eq!(word(&cpu, sp + 12), 0);
+        assert_eq!(string(&cpu, word(&cpu, sp + 16)), "HOME=/");
+        assert_eq!(word(&cpu, sp + 20), 0);
+
+        let auxv = (0..7)
+            .map(|i| (word(&cpu, sp + 24 + 8 * i), word(&cpu, sp + 28 + 8 * i)))
+            .collect::<Vec<(u32, u32)>>();
+        assert_eq!(
+            auxv[..5],
+            [
+                (AT_PHDR, 0x34),
+                (AT_PHENT, 32),
+                (AT_PHNUM, 2),
+                (AT_PAGESZ, PAGE_SIZE),
+                (AT_ENTRY, 0x1000)
+            ]
+        );
+        assert_eq!(auxv[5].0, AT_RANDOM);
+        // the 16 random bytes sit right below the old top, above the strings
+        assert_eq!(auxv[5].1, top - 16);
+        assert_eq!(auxv[6], (AT_NULL, 0));
+    }
+
+    #[test]
+    fn rejects_a_stack_that_does_not_fit() {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new().stack_pointer(0x20));
+        cpu.reset();
+        let args = ["a long program name".to_string()];
+        assert!(cpu.setup_process_stack(&elf(), &args, &[]).is_err());
+        assert_eq!(cpu.get_register(R_SP), 0x20);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, HashMap};
+
+pub struct SymbolMap {
+    by_address: BTreeMap<u32, (String, u32)>,
+    by_name: HashMap<String, u32>,
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap {
+            by_address: BTreeMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, name: &str, address: u32, size: u32) {
+        // keep the first name for aliases, functions usually come first
+        self.by_address
+            .entry(address)
+            .or_insert_with(|| (name.to_string(), size));
+        self.by_name.insert(name.to_string(), address);
+    }
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        let (start, (name, size)) = self.by_address.range(..=address).next_back()?;
+        let offset = address - start;
+        let inside = match *size {
+            // labels without a size reach up to the next symbol, the last one only covers itself
+            0 => offset == 0 || self.by_address.range(address..).next().is_some(),
+            size => offset < size,
+        };
+        if !inside {
+            return None;
+        }
+        Some((name, offset))
+    }
+    // "func+0x1c" or "func"
+    pub fn describe(&self, address: u32) -> Option<String> {
+        self.lookup(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+0x{:x}", name, offset),
+        })
+    }
+    // accepts "0x1000", "4096", "main" and "main+0x10"
+    pub fn resolve(&self, location: &str) -> Result<u32, String> {
+        if let Some(address) = parse_address(location) {
+            return Ok(address);
+        }
+        let (name, offset) = match location.split_once('+') {
+            Some((name, offset)) => (
+                name.trim(),
+                parse_address(offset.trim())
+                    .ok_or_else(|| format!("Invalid offset: {}", offset))?,
+            ),
+            None => (location.trim(), 0),
+        };
+        self.get(name)
+            .map(|address| address.wrapping_add(offset))
+            .ok_or_else(|| format!("Unknown symbol: {}", name))
+    }
+}
+
+fn parse_address(value: &str) -> Option<u32> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u32>().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> SymbolMap {
+        let mut symbols = SymbolMap::new();
+        symbols.insert("_start", 0x1000, 0);
+        symbols.insert("main", 0x1010, 0x20);
+        symbols.insert("main_alias", 0x1010, 0x20);
+        symbols.insert("helper", 0x1100, 0x8);
+        symbols.insert("_end", 0x2000, 0);
+        symbols
+    }
+
+    #[test]
+    fn describes_addresses_inside_symbols() {
+        let symbols = symbols();
+        assert_eq!(symbols.describe(0x1010), Some("main".to_string()));
+        assert_eq!(symbols.describe(0x102c), Some("main+0x1c".to_string()));
+        assert_eq!(symbols.describe(0x1030), None);
+        assert_eq!(symbols.describe(0x1104), Some("helper+0x4".to_string()));
+        assert_eq!(symbols.describe(0xfff), None);
+    }
+
+    #[test]
+    fn unsized_symbols_end_at_the_next_symbol() {
+        let symbols = symbols();
+        assert_eq!(symbols.lookup(0x100c), Some(("_start", 0xc)));
+        assert_eq!(symbols.lookup(0x2000), Some(("_end", 0)));
+        // nothing follows _end, so the heap after it is not attributed to it
+        assert_eq!(symbols.lookup(0x2004), None);
+    }
+
+    #[test]
+    fn resolves_names_offsets_and_numbers() {
+        let symbols = symbols();
+        assert_eq!(symbols.resolve("0x1234"), Ok(0x1234));
+        assert_eq!(symbols.resolve("4096"), Ok(4096));
+        assert_eq!(symbols.resolve("main"), Ok(0x1010));
+        assert_eq!(symbols.resolve("main_alias"), Ok(0x1010));
+        assert_eq!(symbols.resolve("main + 0x10"), Ok(0x1020));
+        assert!(symbols.resolve("missing").is_err());
+        assert!(symbols.resolve("main+x").is_err());
+    }
+}
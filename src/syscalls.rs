@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use cpu::RiscvCpu;
+use guest_fs::{FileStat, GuestFile, OpenMode};
+use rars::RarsServices;
+use rng::XorShift;
+use sbi::SbiFirmware;
+use types::*;
+
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_FSTAT: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_CLOCK_GETTIME: u32 = 113;
+const SYS_UNAME: u32 = 160;
+const SYS_GETTIMEOFDAY: u32 = 169;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP: u32 = 222;
+const SYS_GETRANDOM: u32 = 278;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EFAULT: i32 = 14;
+const ENODEV: i32 = 19;
+const ENOTDIR: i32 = 20;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+const EOVERFLOW: i32 = 75;
+const EIO: i32 = 5;
+
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const CLOCK_REALTIME: u32 = 0;
+
+// the fields filled in by statx, STATX_BASIC_STATS
+const STATX_BASIC_STATS: u32 = 0x7ff;
+
+// the most iovecs readv and writev accept
+const IOV_MAX: u32 = 1024;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const PAGE_SIZE: u32 = 0x1000;
+// space kept free below the initial stack pointer
+const STACK_SIZE: u32 = 0x10000;
+
+// newlib keeps the 32-bit syscalls, musl and glibc on rv32 only have the 64-bit time and
+// offset ones, and use the same number 62 for _llseek that newlib uses for lseek
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Libc {
+    Newlib,
+    Linux,
+}
+
+pub enum EcallMode {
+    None,
+    Linux(LinuxSyscalls),
+    Rars(RarsServices),
+    Sbi(SbiFirmware),
+}
+
+pub struct LinuxSyscalls {
+    libc: Libc,
+    files: HashMap<u32, GuestFile>,
+    // guest paths of the files opened by the program, for openat relative to a directory
+    paths: HashMap<u32, String>,
+    brk_start: u32,
+    brk: u32,
+    // anonymous mappings grow down from below the stack
+    mmap_bottom: u32,
+    start: Instant,
+    rng: XorShift,
+}
+
+fn page_align(value: u32) -> u32 {
+    value.wrapping_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn errno(err: &io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(EIO)
+}
+
+fn file_mode(stat: &FileStat) -> u32 {
+    if stat.is_char_device {
+        S_IFCHR | 0o620
+    } else if stat.is_dir {
+        S_IFDIR | 0o755
+    } else {
+        S_IFREG | 0o644
+    }
+}
+
+impl LinuxSyscalls {
+    pub fn new(cpu: &RiscvCpu, libc: Libc) -> LinuxSyscalls {
+        let mut files = HashMap::new();
+        files.insert(0, GuestFile::Stdin);
+        files.insert(1, GuestFile::Stdout);
+        files.insert(2, GuestFile::Stderr);
+        let brk_start = page_align(cpu.image_end);
+        let stack_bottom = cpu.get_register(R_SP).saturating_sub(STACK_SIZE) & !(PAGE_SIZE - 1);
+        LinuxSyscalls {
+            libc,
+            files,
+            paths: HashMap::new(),
+            brk_start,
+            brk: brk_start,
+            mmap_bottom: stack_bottom.max(brk_start),
+            start: Instant::now(),
+            rng: XorShift::new(XorShift::seed_from_time()),
+        }
+    }
+    pub fn open_file(&mut self, file: GuestFile) -> u32 {
+        let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, file);
+        fd
+    }
+    pub fn handle(&mut self, cpu: &mut RiscvCpu) {
+        let number = cpu.get_register(R_A7);
+        let args = [
+            cpu.get_register(R_A0),
+            cpu.get_register(R_A1),
+            cpu.get_register(R_A2),
+            cpu.get_register(R_A3),
+            cpu.get_register(R_A4),
+            cpu.get_register(R_A5),
+        ];
+        let result = match (number, self.libc) {
+            (SYS_LSEEK, Libc::Newlib) => self.lseek(args[0], args[1], args[2]),
+            (SYS_LSEEK, Libc::Linux) => {
+                self.llseek(cpu, args[0], args[1], args[2], args[3], args[4])
+            }
+            (SYS_FSTAT, Libc::Newlib) => self.fstat(cpu, args[0], args[1]),
+            (SYS_CLOCK_GETTIME, Libc::Newlib) => self.clock_gettime(cpu, args[0], args[1], false),
+            (SYS_GETTIMEOFDAY, Libc::Newlib) => self.gettimeofday(cpu, args[0], args[1]),
+            (number, _) => self.handle_common(cpu, number, args),
+        };
+        let value = match result {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u32,
+        };
+        if cpu.exit_code.is_none() {
+            cpu.set_register(R_A0, value);
+        }
+    }
+    // the syscalls both ABIs share
+    fn handle_common(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        number: u32,
+        args: [u32; 6],
+    ) -> Result<u32, i32> {
+        match number {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                cpu.exit_code = Some(args[0] as i32);
+                Ok(args[0])
+            }
+            SYS_READ => self.read(cpu, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(cpu, args[0], args[1], args[2]),
+            SYS_READV => self.vectored(cpu, args[0], args[1], args[2], false),
+            SYS_WRITEV => self.vectored(cpu, args[0], args[1], args[2], true),
+            SYS_OPENAT => self.openat(cpu, args[0] as i32, args[1], args[2]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_STATX => self.statx(cpu, args[0] as i32, args[1], args[2], args[4]),
+            SYS_BRK => self.brk(cpu, args[0]),
+            SYS_MMAP => self.mmap(cpu, args[0], args[1], args[3]),
+            SYS_MUNMAP => self.munmap(args[0], args[1]),
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(cpu, args[0], args[1], true),
+            SYS_GETRANDOM => self.getrandom(cpu, args[0], args[1]),
+            SYS_UNAME => self.uname(cpu, args[0]),
+            // single threaded, the tid is always 1
+            SYS_SET_TID_ADDRESS => Ok(1),
+            _ => {
+                eprintln!(
+                    "Unimplemented syscall {} at pc 0x{:08X}",
+                    number, cpu.program_counter
+                );
+                Err(ENOSYS)
+            }
+        }
+    }
+    // a guest buffer of count bytes, checked before anything is allocated for it
+    fn buffer(cpu: &RiscvCpu, buf: u32, count: u32) -> Result<Vec<u8>, i32> {
+        match cpu.memory_after(buf) {
+            Some(available) if count as usize <= available => Ok(vec![0u8; count as usize]),
+            _ => Err(EFAULT),
+        }
+    }
+    fn read(&mut self, cpu: &mut RiscvCpu, fd: u32, buf: u32, count: u32) -> Result<u32, i32> {
+        let mut data = LinuxSyscalls::buffer(cpu, buf, count)?;
+        let read = self
+            .files
+            .get_mut(&fd)
+            .ok_or(EBADF)?
+            .read(&mut data)
+            .map_err(|err| errno(&err))?;
+        cpu.write_bytes(buf, &data[..read]).map_err(|_| EFAULT)?;
+        Ok(read as u32)
+    }
+    fn write(&mut self, cpu: &mut RiscvCpu, fd: u32, buf: u32, count: u32) -> Result<u32, i32> {
+        let data = cpu.read_bytes(buf, count as usize).map_err(|_| EFAULT)?;
+        self.files
+            .get_mut(&fd)
+            .ok_or(EBADF)?
+            .write(data)
+            .map_err(|err| errno(&err))?;
+        Ok(count)
+    }
+    fn vectored(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        fd: u32,
+        iov: u32,
+        iovcnt: u32,
+        write: bool,
+    ) -> Result<u32, i32> {
+        if iovcnt > IOV_MAX {
+            return Err(EINVAL);
+        }
+        let mut total: u32 = 0;
+        for i in 0..iovcnt {
+            let entry = iov.checked_add(i * 8).ok_or(EFAULT)?;
+            let entry = cpu.read_bytes(entry, 8).map_err(|_| EFAULT)?;
+            let base = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let done = if write {
+                self.write(cpu, fd, base, len)?
+            } else {
+                self.read(cpu, fd, base, len)?
+            };
+            // the total has to fit in the returned ssize_t
+            total = total
+                .checked_add(done)
+                .filter(|&total| total <= i32::MAX as u32)
+                .ok_or(EINVAL)?;
+            if done < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+    fn openat(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        dirfd: i32,
+        path: u32,
+        flags: u32,
+    ) -> Result<u32, i32> {
+        let path = cpu.read_c_bytes(path).map_err(|_| EFAULT)?;
+        let path = String::from_utf8(path.to_vec()).map_err(|_| EINVAL)?;
+        let path = self.at_path(dirfd, path)?;
+        let access = flags & O_ACCMODE;
+        let mode = OpenMode {
+            read: access != O_WRONLY,
+            write: access == O_WRONLY || access == O_RDWR,
+            create: flags & O_CREAT != 0,
+            exclusive: flags & O_EXCL != 0,
+            truncate: flags & O_TRUNC != 0,
+            append: flags & O_APPEND != 0,
+        };
+        let file = cpu.filesystem.open(&path, mode)?;
+        let fd = self.open_file(file);
+        self.paths.insert(fd, path);
+        Ok(fd)
+    }
+    // a path relative to dirfd as a path for the guest filesystem
+    fn at_path(&self, dirfd: i32, path: String) -> Result<String, i32> {
+        if dirfd == AT_FDCWD || path.starts_with('/') {
+            return Ok(path);
+        }
+        let directory = self.files.get(&(dirfd as u32)).ok_or(EBADF)?;
+        let is_dir = directory.stat().is_ok_and(|stat| stat.is_dir);
+        match self.paths.get(&(dirfd as u32)) {
+            Some(directory_path) if is_dir => Ok(format!("{}/{}", directory_path, path)),
+            _ => Err(ENOTDIR),
+        }
+    }
+    fn close(&mut self, fd: u32) -> Result<u32, i32> {
+        self.paths.remove(&fd);
+        match self.files.remove(&fd) {
+            Some(_) => Ok(0),
+            None => Err(EBADF),
+        }
+    }
+    // newlib passes a 32 bit offset and gets the new position back in a0
+    fn lseek(&mut self, fd: u32, offset: u32, whence: u32) -> Result<u32, i32> {
+        let position = self.seek(fd, offset as i32 as i64, whence)?;
+        // larger positions would read as an error
+        match position {
+            position if position <= i32::MAX as u64 => Ok(position as u32),
+            _ => Err(EOVERFLOW),
+        }
+    }
+    // musl and glibc split the offset in two and get the 64 bit position back through result
+    fn llseek(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        fd: u32,
+        offset_high: u32,
+        offset_low: u32,
+        result: u32,
+        whence: u32,
+    ) -> Result<u32, i32> {
+        let offset = ((offset_high as u64) << 32 | offset_low as u64) as i64;
+        let position = self.seek(fd, offset, whence)?;
+        cpu.write_bytes(result, &position.to_le_bytes())
+            .map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+    fn seek(&mut self, fd: u32, offset: i64, whence: u32) -> Result<u64, i32> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        self.files
+            .get_mut(&fd)
+            .ok_or(EBADF)?
+            .seek(position)
+            .map_err(|err| errno(&err))
+    }
+    fn file_stat(&self, fd: u32) -> Result<FileStat, i32> {
+        self.files
+            .get(&fd)
+            .ok_or(EBADF)?
+            .stat()
+            .map_err(|err| errno(&err))
+    }
+    // struct kernel_stat as used by newlib
+    fn fstat(&mut self, cpu: &mut RiscvCpu, fd: u32, statbuf: u32) -> Result<u32, i32> {
+        let file_stat = self.file_stat(fd)?;
+        let mode = file_mode(&file_stat);
+        let mut stat = vec![0u8; 128];
+        stat[16..20].copy_from_slice(&mode.to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&file_stat.size.to_le_bytes());
+        stat[56..60].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+        stat[64..72].copy_from_slice(&file_stat.size.div_ceil(512).to_le_bytes());
+        for time in [72, 88, 104] {
+            stat[time..time + 8].copy_from_slice(&file_stat.modified.to_le_bytes());
+        }
+        cpu.write_bytes(statbuf, &stat).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+    // musl builds fstat and stat on statx, an empty path with AT_EMPTY_PATH means dirfd itself
+    fn statx(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        dirfd: i32,
+        path: u32,
+        flags: u32,
+        statxbuf: u32,
+    ) -> Result<u32, i32> {
+        let path = cpu.read_c_bytes(path).map_err(|_| EFAULT)?;
+        let path = String::from_utf8(path.to_vec()).map_err(|_| EINVAL)?;
+        let file_stat = if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+            self.file_stat(dirfd as u32)?
+        } else {
+            let path = self.at_path(dirfd, path)?;
+            let mode = OpenMode {
+                read: true,
+                ..OpenMode::default()
+            };
+            cpu.filesystem
+                .open(&path, mode)?
+                .stat()
+                .map_err(|err| errno(&err))?
+        };
+        let mut statx = vec![0u8; 256];
+        statx[0..4].copy_from_slice(&STATX_BASIC_STATS.to_le_bytes());
+        statx[4..8].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+        statx[16..20].copy_from_slice(&1u32.to_le_bytes());
+        statx[28..30].copy_from_slice(&(file_mode(&file_stat) as u16).to_le_bytes());
+        statx[40..48].copy_from_slice(&file_stat.size.to_le_bytes());
+        statx[48..56].copy_from_slice(&file_stat.size.div_ceil(512).to_le_bytes());
+        // atime, btime, ctime and mtime, each a 64 bit tv_sec and 32 bit tv_nsec
+        for time in [64, 80, 96, 112] {
+            statx[time..time + 8].copy_from_slice(&file_stat.modified.to_le_bytes());
+        }
+        cpu.write_bytes(statxbuf, &statx).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+    fn brk(&mut self, cpu: &mut RiscvCpu, address: u32) -> Result<u32, i32> {
+        if address < self.brk_start || address > self.mmap_bottom {
+            return Ok(self.brk);
+        }
+        if address > self.brk {
+            let grown = vec![0u8; (address - self.brk) as usize];
+            if cpu.write_bytes(self.brk, &grown).is_err() {
+                return Ok(self.brk);
+            }
+        }
+        self.brk = address;
+        Ok(self.brk)
+    }
+    // only anonymous mappings, memory is not protected
+    fn mmap(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        address: u32,
+        length: u32,
+        flags: u32,
+    ) -> Result<u32, i32> {
+        if flags & MAP_ANONYMOUS == 0 {
+            return Err(ENODEV);
+        }
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = page_align(length);
+        let address = if flags & MAP_FIXED != 0 {
+            address
+        } else {
+            let address = self.mmap_bottom.checked_sub(length).ok_or(ENOMEM)?;
+            if address < self.brk {
+                return Err(ENOMEM);
+            }
+            self.mmap_bottom = address;
+            address
+        };
+        match cpu.memory_after(address) {
+            Some(available) if length as usize <= available => {}
+            _ => return Err(ENOMEM),
+        }
+        cpu.write_bytes(address, &vec![0u8; length as usize])
+            .map_err(|_| ENOMEM)?;
+        Ok(address)
+    }
+    fn munmap(&mut self, address: u32, length: u32) -> Result<u32, i32> {
+        // only the most recent mapping can be given back
+        if address == self.mmap_bottom {
+            self.mmap_bottom = self.mmap_bottom.saturating_add(page_align(length));
+        }
+        Ok(0)
+    }
+    fn clock_gettime(
+        &mut self,
+        cpu: &mut RiscvCpu,
+        clock: u32,
+        tp: u32,
+        time64: bool,
+    ) -> Result<u32, i32> {
+        let time = if clock == CLOCK_REALTIME {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        } else {
+            self.start.elapsed()
+        };
+        let mut timespec = Vec::new();
+        if time64 {
+            timespec.extend_from_slice(&time.as_secs().to_le_bytes());
+            timespec.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        } else {
+            timespec.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+            timespec.extend_from_slice(&time.subsec_nanos().to_le_bytes());
+        }
+        cpu.write_bytes(tp, &timespec).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+    fn getrandom(&mut self, cpu: &mut RiscvCpu, buf: u32, count: u32) -> Result<u32, i32> {
+        let mut data = LinuxSyscalls::buffer(cpu, buf, count)?;
+        data.iter_mut()
+            .for_each(|byte| *byte = self.rng.next_u32() as u8);
+        cpu.write_bytes(buf, &data).map_err(|_| EFAULT)?;
+        Ok(count)
+    }
+    // newlib's struct timeval has a 64 bit tv_sec and a 32 bit tv_usec, padded to 16 bytes
+    fn gettimeofday(&mut self, cpu: &mut RiscvCpu, tv: u32, tz: u32) -> Result<u32, i32> {
+        if tv != 0 {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let mut timeval = vec![0u8; 16];
+            timeval[0..8].copy_from_slice(&time.as_secs().to_le_bytes());
+            timeval[8..12].copy_from_slice(&time.subsec_micros().to_le_bytes());
+            cpu.write_bytes(tv, &timeval).map_err(|_| EFAULT)?;
+        }
+        // the obsolete timezone is always UTC
+        if tz != 0 {
+            cpu.write_bytes(tz, &[0u8; 8]).map_err(|_| EFAULT)?;
+        }
+        Ok(0)
+    }
+    fn uname(&mut self, cpu: &mut RiscvCpu, buf: u32) -> Result<u32, i32> {
+        let fields = [
+            "Linux",
+            "risc-v",
+            "6.1.0",
+            "risc-v_emulator-rust",
+            "riscv32",
+            "",
+        ];
+        let mut utsname = vec![0u8; 65 * fields.len()];
+        fields.iter().enumerate().for_each(|(i, field)| {
+            utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+        });
+        cpu.write_bytes(buf, &utsname).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+    use guest_fs::GuestFs;
+
+    const PATH: u32 = 0x100;
+    const DATA: u32 = 0x200;
+    const BUFFER: u32 = 0x300;
+    const RESULT: u32 = 0x400;
+
+    // the file lives in the overlay, the host is never written
+    fn setup(libc: Libc) -> (RiscvCpu, LinuxSyscalls) {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        cpu.reset();
+        cpu.filesystem = GuestFs::new().overlay();
+        cpu.write_bytes(PATH, b"syscalls_test_file\0").unwrap();
+        cpu.write_bytes(DATA, b"hello").unwrap();
+        let syscalls = LinuxSyscalls::new(&cpu, libc);
+        (cpu, syscalls)
+    }
+
+    fn syscall(cpu: &mut RiscvCpu, syscalls: &mut LinuxSyscalls, number: u32, args: &[u32]) -> i32 {
+        cpu.set_register(R_A7, number);
+        for (i, &arg) in args.iter().enumerate() {
+            cpu.set_register(R_A0 + i as u32, arg);
+        }
+        syscalls.handle(cpu);
+        cpu.get_register(R_A0) as i32
+    }
+
+    // a new file holding hello
+    fn create(cpu: &mut RiscvCpu, syscalls: &mut LinuxSyscalls) -> u32 {
+        let fd = syscall(
+            cpu,
+            syscalls,
+            SYS_OPENAT,
+            &[AT_FDCWD as u32, PATH, O_RDWR | O_CREAT],
+        );
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(cpu, syscalls, SYS_WRITE, &[3, DATA, 5]), 5);
+        fd as u32
+    }
+
+    #[test]
+    fn writes_and_reads_back_a_file() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let fd = create(&mut cpu, &mut syscalls);
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_LSEEK, &[fd, 0, 0]), 0);
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_READ, &[fd, BUFFER, 16]),
+            5
+        );
+        assert_eq!(cpu.read_bytes(BUFFER, 5), Ok(&b"hello"[..]));
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_READ, &[fd, BUFFER, 16]),
+            0
+        );
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_READ, &[fd, BUFFER, 16]),
+            -EBADF
+        );
+    }
+
+    #[test]
+    fn rejects_buffers_outside_memory() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let fd = create(&mut cpu, &mut syscalls);
+        let end = cpu.memory.len() as u32;
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_READ, &[fd, end - 2, 16]),
+            -EFAULT
+        );
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_WRITE, &[fd, end - 2, 16]),
+            -EFAULT
+        );
+    }
+
+    #[test]
+    fn lseek_follows_the_libc_abi() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let fd = create(&mut cpu, &mut syscalls);
+        // lseek(fd, -1, SEEK_END) returns the position
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_LSEEK, &[fd, -1i32 as u32, 2]),
+            4
+        );
+
+        let (mut cpu, mut syscalls) = setup(Libc::Linux);
+        let fd = create(&mut cpu, &mut syscalls);
+        // _llseek(fd, 0, 2, &result, SEEK_SET) returns 0 and the position through result
+        let args = [fd, 0, 2, RESULT, 0];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_LSEEK, &args), 0);
+        assert_eq!(cpu.read_bytes(RESULT, 8), Ok(&2u64.to_le_bytes()[..]));
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_READ, &[fd, BUFFER, 16]),
+            3
+        );
+    }
+
+    #[test]
+    fn linux_abi_has_only_the_64_bit_calls() {
+        let (mut cpu, mut syscalls) = setup(Libc::Linux);
+        let fd = create(&mut cpu, &mut syscalls);
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_FSTAT, &[fd, BUFFER]),
+            -ENOSYS
+        );
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_CLOCK_GETTIME, &[0, BUFFER]),
+            -ENOSYS
+        );
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_CLOCK_GETTIME64, &[0, BUFFER]),
+            0
+        );
+
+        // statx(fd, "", AT_EMPTY_PATH, STATX_BASIC_STATS, &statx), like musl's fstat
+        cpu.write_bytes(RESULT, &[0]).unwrap();
+        let args = [fd, RESULT, AT_EMPTY_PATH, STATX_BASIC_STATS, BUFFER];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_STATX, &args), 0);
+        assert_eq!(cpu.read_bytes(BUFFER + 40, 8), Ok(&5u64.to_le_bytes()[..]));
+        let mode = cpu.read_bytes(BUFFER + 28, 2).unwrap();
+        assert_eq!(
+            u16::from_le_bytes([mode[0], mode[1]]) as u32,
+            S_IFREG | 0o644
+        );
+    }
+
+    #[test]
+    fn brk_grows_and_keeps_its_bounds() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let start = syscall(&mut cpu, &mut syscalls, SYS_BRK, &[0]) as u32;
+        let grown = start + 0x1000;
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_BRK, &[grown]) as u32,
+            grown
+        );
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_BRK, &[u32::MAX]) as u32,
+            grown
+        );
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_BRK, &[start]) as u32,
+            start
+        );
+    }
+
+    #[test]
+    fn vectored_io_checks_its_arguments() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let fd = create(&mut cpu, &mut syscalls);
+        let args = [fd, u32::MAX - 4, 2];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_WRITEV, &args), -EFAULT);
+        let args = [fd, BUFFER, IOV_MAX + 1];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_WRITEV, &args), -EINVAL);
+
+        // two iovecs of hello and hel
+        for (i, value) in [DATA, 5, DATA, 3].iter().enumerate() {
+            cpu.write_bytes(BUFFER + 4 * i as u32, &value.to_le_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_WRITEV, &[fd, BUFFER, 2]),
+            8
+        );
+    }
+
+    #[test]
+    fn openat_needs_a_directory_fd_for_relative_paths() {
+        let (mut cpu, mut syscalls) = setup(Libc::Newlib);
+        let fd = create(&mut cpu, &mut syscalls);
+        let args = [fd, PATH, 0];
+        assert_eq!(
+            syscall(&mut cpu, &mut syscalls, SYS_OPENAT, &args),
+            -ENOTDIR
+        );
+        let args = [42, PATH, 0];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_OPENAT, &args), -EBADF);
+
+        let directory = std::env::temp_dir().display().to_string() + "\0";
+        cpu.write_bytes(BUFFER, directory.as_bytes()).unwrap();
+        let args = [AT_FDCWD as u32, BUFFER, 0];
+        let dirfd = syscall(&mut cpu, &mut syscalls, SYS_OPENAT, &args);
+        assert_eq!(dirfd, 4);
+        let args = [4, PATH, O_RDWR | O_CREAT];
+        assert_eq!(syscall(&mut cpu, &mut syscalls, SYS_OPENAT, &args), 5);
+    }
+}
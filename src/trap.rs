@@ -0,0 +1,331 @@
+use cpu::{Privilege, RiscvCpu};
+use types::*;
+
+// machine external, software and timer, then the same for the supervisor
+const INTERRUPT_PRIORITY: [u32; 6] = [11, 3, 7, 9, 1, 5];
+
+// the encoding of a privilege in mstatus.MPP
+fn level(privilege: Privilege) -> u64 {
+    match privilege {
+        Privilege::User => 0,
+        Privilege::Supervisor => 1,
+        Privilege::Machine => 3,
+    }
+}
+
+impl RiscvCpu {
+    // sstatus, sie and sip are views of the machine registers
+    pub fn read_csr(&self, csr: usize) -> u64 {
+        match csr {
+            CSR_SSTATUS => self.csrs[CSR_MSTATUS] & SSTATUS_MASK,
+            CSR_SIE => self.csrs[CSR_MIE] & self.csrs[CSR_MIDELEG],
+            CSR_SIP => self.csrs[CSR_MIP] & self.csrs[CSR_MIDELEG],
+            _ => self.csrs[csr],
+        }
+    }
+    pub fn write_csr(&mut self, csr: usize, value: u64) {
+        let (csr, mask) = match csr {
+            CSR_SSTATUS => (CSR_MSTATUS, SSTATUS_MASK),
+            CSR_SIE => (CSR_MIE, self.csrs[CSR_MIDELEG]),
+            // the supervisor can only raise and clear its software interrupt
+            CSR_SIP => (CSR_MIP, self.csrs[CSR_MIDELEG] & MIP_SSIP),
+            _ => (csr, u64::MAX),
+        };
+        self.csrs[csr] = (self.csrs[csr] & !mask) | (value & mask);
+    }
+    // the pending interrupt to take next, interrupts for a higher privilege are always enabled
+    fn pending_interrupt(&self) -> Option<u32> {
+        let pending = self.csrs[CSR_MIP] & self.csrs[CSR_MIE];
+        let delegated = self.csrs[CSR_MIDELEG];
+        let status = self.csrs[CSR_MSTATUS];
+        let machine = self.privilege != Privilege::Machine || status & MSTATUS_MIE != 0;
+        let supervisor = self.privilege == Privilege::User
+            || (self.privilege == Privilege::Supervisor && status & MSTATUS_SIE != 0);
+        INTERRUPT_PRIORITY.iter().copied().find(|&code| {
+            let bit = 1 << code;
+            let enabled = if delegated & bit != 0 {
+                supervisor
+            } else {
+                machine
+            };
+            pending & bit != 0 && enabled
+        })
+    }
+    pub fn take_interrupt(&mut self) {
+        if let Some(code) = self.pending_interrupt() {
+            self.trap(CAUSE_INTERRUPT | code, 0);
+        }
+    }
+    // enters the trap handler of the machine, or of the supervisor when the cause is delegated
+    pub fn trap(&mut self, cause: u32, value: u32) {
+        let interrupt = cause & CAUSE_INTERRUPT != 0;
+        let code = cause & !CAUSE_INTERRUPT;
+        let delegated = if interrupt {
+            self.csrs[CSR_MIDELEG]
+        } else {
+            self.csrs[CSR_MEDELEG]
+        };
+        let status = self.csrs[CSR_MSTATUS];
+        let (tvec, epc, cause_csr, tval) =
+            if self.privilege != Privilege::Machine && delegated & (1 << code) != 0 {
+                let previous = if self.privilege == Privilege::Supervisor {
+                    MSTATUS_SPP
+                } else {
+                    0
+                };
+                let enabled = if status & MSTATUS_SIE != 0 {
+                    MSTATUS_SPIE
+                } else {
+                    0
+                };
+                self.csrs[CSR_MSTATUS] = (status & !SSTATUS_MASK) | enabled | previous;
+                self.privilege = Privilege::Supervisor;
+                (CSR_STVEC, CSR_SEPC, CSR_SCAUSE, CSR_STVAL)
+            } else {
+                let previous = level(self.privilege) << 11;
+                let enabled = if status & MSTATUS_MIE != 0 {
+                    MSTATUS_MPIE
+                } else {
+                    0
+                };
+                self.csrs[CSR_MSTATUS] =
+                    (status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | enabled | previous;
+                self.privilege = Privilege::Machine;
+                (CSR_MTVEC, CSR_MEPC, CSR_MCAUSE, CSR_MTVAL)
+            };
+        self.csrs[epc] = self.program_counter as u64;
+        self.csrs[cause_csr] = cause as u64;
+        self.csrs[tval] = value as u64;
+        let tvec = self.csrs[tvec] as u32;
+        // in vectored mode interrupts go to base + 4 * cause
+        self.program_counter = if tvec & 3 == 1 && interrupt {
+            (tvec & !3).wrapping_add(4 * code)
+        } else {
+            tvec & !3
+        };
+    }
+    // instructions that are not implemented raise the same exception as invalid ones,
+    // without a handler to take it there is nothing left to run and the program ends
+    pub fn illegal_instruction(&mut self, bits: u32) {
+        let delegated = self.privilege != Privilege::Machine
+            && self.csrs[CSR_MEDELEG] & (1 << CAUSE_ILLEGAL_INSTRUCTION) != 0;
+        let tvec = if delegated { CSR_STVEC } else { CSR_MTVEC };
+        if self.csrs[tvec] == 0 {
+            eprintln!("Illegal instruction 0x{:08X} at {}", bits, self.location());
+            self.exit_code = Some(1);
+            return;
+        }
+        self.trap(CAUSE_ILLEGAL_INSTRUCTION, bits);
+    }
+    pub fn sret(&mut self, bits: u32) {
+        if self.privilege == Privilege::User {
+            self.illegal_instruction(bits);
+            return;
+        }
+        let status = self.csrs[CSR_MSTATUS];
+        self.privilege = if status & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        let enabled = if status & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+        self.csrs[CSR_MSTATUS] = (status & !SSTATUS_MASK) | enabled | MSTATUS_SPIE;
+        self.program_counter = self.csrs[CSR_SEPC] as u32;
+    }
+    pub fn mret(&mut self, bits: u32) {
+        if self.privilege != Privilege::Machine {
+            self.illegal_instruction(bits);
+            return;
+        }
+        let status = self.csrs[CSR_MSTATUS];
+        self.privilege = match (status & MSTATUS_MPP) >> 11 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        };
+        let enabled = if status & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        self.csrs[CSR_MSTATUS] = (status & !(MSTATUS_MIE | MSTATUS_MPP)) | enabled | MSTATUS_MPIE;
+        self.program_counter = self.csrs[CSR_MEPC] as u32;
+    }
+    // the cause of an ecall made at the current privilege
+    pub fn ecall_cause(&self) -> u32 {
+        match self.privilege {
+            Privilege::User => CAUSE_USER_ECALL,
+            Privilege::Supervisor => CAUSE_SUPERVISOR_ECALL,
+            Privilege::Machine => CAUSE_MACHINE_ECALL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+
+    const ECALL: u32 = 0x0000_0073;
+    const MRET: u32 = 0x3020_0073;
+    const SRET: u32 = 0x1020_0073;
+
+    fn machine(privilege: Privilege) -> RiscvCpu {
+        let mut cpu = RiscvCpu::with_config(MachineConfig::new());
+        cpu.reset();
+        cpu.privilege = privilege;
+        cpu.program_counter = 0x100;
+        cpu.csrs[CSR_MTVEC] = 0x200;
+        cpu.csrs[CSR_STVEC] = 0x300;
+        cpu
+    }
+
+    #[test]
+    fn unknown_opcodes_raise_illegal_instruction() {
+        let mut cpu = machine(Privilege::Machine);
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_MIE;
+        cpu.execute_inst(0xffff_ffff);
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], CAUSE_ILLEGAL_INSTRUCTION as u64);
+        assert_eq!(cpu.csrs[CSR_MEPC], 0x100);
+        assert_eq!(cpu.csrs[CSR_MTVAL], 0xffff_ffff);
+        // interrupts are off in the handler and come back on with mret
+        assert_eq!(cpu.csrs[CSR_MSTATUS], MSTATUS_MPIE | MSTATUS_MPP);
+    }
+
+    #[test]
+    fn unknown_funct3_raises_illegal_instruction() {
+        let mut cpu = machine(Privilege::Machine);
+        // a branch with funct3 010
+        cpu.execute_inst(0x0000_2063);
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], CAUSE_ILLEGAL_INSTRUCTION as u64);
+    }
+
+    #[test]
+    fn illegal_instructions_without_a_handler_end_the_program() {
+        let mut cpu = machine(Privilege::User);
+        cpu.csrs[CSR_MTVEC] = 0;
+        cpu.execute_inst(0xffff_ffff);
+        assert_eq!(cpu.exit_code, Some(1));
+        assert_eq!(cpu.program_counter, 0x100);
+        assert_eq!(cpu.privilege, Privilege::User);
+        // a delegated one needs a supervisor handler
+        let mut cpu = machine(Privilege::User);
+        cpu.csrs[CSR_MEDELEG] = 1 << CAUSE_ILLEGAL_INSTRUCTION;
+        cpu.csrs[CSR_STVEC] = 0;
+        cpu.execute_inst(SRET);
+        assert_eq!(cpu.exit_code, Some(1));
+    }
+
+    #[test]
+    fn mret_restores_privilege_and_interrupt_enable() {
+        let mut cpu = machine(Privilege::Machine);
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_MPIE | (1 << 11);
+        cpu.csrs[CSR_MEPC] = 0x400;
+        cpu.execute_inst(MRET);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.program_counter, 0x400);
+        assert_eq!(cpu.csrs[CSR_MSTATUS], MSTATUS_MIE | MSTATUS_MPIE);
+    }
+
+    #[test]
+    fn trap_and_mret_round_trip_from_user_mode() {
+        let mut cpu = machine(Privilege::User);
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_MIE;
+        cpu.execute_inst(ECALL);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], CAUSE_USER_ECALL as u64);
+        assert_eq!(cpu.csrs[CSR_MSTATUS] & MSTATUS_MPP, 0);
+        cpu.csrs[CSR_MEPC] += 4;
+        cpu.execute_inst(MRET);
+        assert_eq!(cpu.privilege, Privilege::User);
+        assert_eq!(cpu.program_counter, 0x104);
+        assert_ne!(cpu.csrs[CSR_MSTATUS] & MSTATUS_MIE, 0);
+    }
+
+    #[test]
+    fn returns_from_a_lower_privilege_are_illegal() {
+        let mut cpu = machine(Privilege::Supervisor);
+        cpu.execute_inst(MRET);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], CAUSE_ILLEGAL_INSTRUCTION as u64);
+        assert_eq!(cpu.csrs[CSR_MTVAL], MRET as u64);
+        assert_eq!(cpu.csrs[CSR_MSTATUS] & MSTATUS_MPP, 1 << 11);
+
+        let mut cpu = machine(Privilege::User);
+        cpu.execute_inst(SRET);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.csrs[CSR_MTVAL], SRET as u64);
+    }
+
+    #[test]
+    fn sret_restores_user_mode() {
+        let mut cpu = machine(Privilege::Supervisor);
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_SPIE;
+        cpu.csrs[CSR_SEPC] = 0x500;
+        cpu.execute_inst(SRET);
+        assert_eq!(cpu.privilege, Privilege::User);
+        assert_eq!(cpu.program_counter, 0x500);
+        assert_eq!(cpu.csrs[CSR_MSTATUS], MSTATUS_SIE | MSTATUS_SPIE);
+    }
+
+    #[test]
+    fn delegated_exceptions_go_to_stvec() {
+        let mut cpu = machine(Privilege::User);
+        cpu.csrs[CSR_MEDELEG] = 1 << CAUSE_USER_ECALL;
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_SIE;
+        cpu.execute_inst(ECALL);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.program_counter, 0x300);
+        assert_eq!(cpu.csrs[CSR_SCAUSE], CAUSE_USER_ECALL as u64);
+        assert_eq!(cpu.csrs[CSR_SEPC], 0x100);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], 0);
+        // from user mode, with interrupts saved in SPIE
+        assert_eq!(cpu.csrs[CSR_MSTATUS], MSTATUS_SPIE);
+    }
+
+    #[test]
+    fn delegation_does_not_apply_in_machine_mode() {
+        let mut cpu = machine(Privilege::Machine);
+        cpu.csrs[CSR_MEDELEG] = 1 << CAUSE_ILLEGAL_INSTRUCTION;
+        cpu.execute_inst(0xffff_ffff);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn delegated_interrupts_use_the_vectored_stvec() {
+        let mut cpu = machine(Privilege::Supervisor);
+        cpu.csrs[CSR_STVEC] = 0x301;
+        cpu.csrs[CSR_MIDELEG] = MIP_STIP;
+        cpu.csrs[CSR_MIE] = MIP_STIP;
+        cpu.csrs[CSR_MIP] = MIP_STIP;
+        // masked while the supervisor has SIE clear
+        cpu.take_interrupt();
+        assert_eq!(cpu.program_counter, 0x100);
+        cpu.csrs[CSR_MSTATUS] = MSTATUS_SIE;
+        cpu.take_interrupt();
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.program_counter, 0x300 + 4 * 5);
+        assert_eq!(cpu.csrs[CSR_SCAUSE], (CAUSE_INTERRUPT | 5) as u64);
+        assert_eq!(cpu.csrs[CSR_MSTATUS], MSTATUS_SPIE | MSTATUS_SPP);
+    }
+
+    #[test]
+    fn machine_interrupts_preempt_the_supervisor() {
+        let mut cpu = machine(Privilege::Supervisor);
+        cpu.csrs[CSR_MIE] = MIP_MTIP;
+        cpu.csrs[CSR_MIP] = MIP_MTIP;
+        // MIE only masks interrupts while in machine mode
+        cpu.take_interrupt();
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.csrs[CSR_MCAUSE], (CAUSE_INTERRUPT | 7) as u64);
+    }
+}
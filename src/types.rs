@@ -60,6 +60,11 @@ pub const IMM11_0_000000000000: u32 = 0b000000000000;
 // ebreak
 pub const IMM11_0_000000000001: u32 = 0b000000000001;
 
+// sret mret wfi
+pub const IMM11_0_000100000010: u32 = 0b000100000010;
+pub const IMM11_0_001100000010: u32 = 0b001100000010;
+pub const IMM11_0_000100000101: u32 = 0b000100000101;
+
 // hard-wired zero
 pub const R_ZERO: u32 = 0b00000;
 
@@ -116,3 +121,61 @@ pub const R_T3: u32 = 0b11100;
 pub const R_T4: u32 = 0b11101;
 pub const R_T5: u32 = 0b11110;
 pub const R_T6: u32 = 0b11111;
+
+// supervisor trap setup and handling
+pub const CSR_SSTATUS: usize = 0x100;
+pub const CSR_SIE: usize = 0x104;
+pub const CSR_STVEC: usize = 0x105;
+pub const CSR_SEPC: usize = 0x141;
+pub const CSR_SCAUSE: usize = 0x142;
+pub const CSR_STVAL: usize = 0x143;
+pub const CSR_SIP: usize = 0x144;
+
+// machine trap setup and handling
+pub const CSR_MSTATUS: usize = 0x300;
+pub const CSR_MEDELEG: usize = 0x302;
+pub const CSR_MIDELEG: usize = 0x303;
+pub const CSR_MIE: usize = 0x304;
+pub const CSR_MTVEC: usize = 0x305;
+pub const CSR_MEPC: usize = 0x341;
+pub const CSR_MCAUSE: usize = 0x342;
+pub const CSR_MTVAL: usize = 0x343;
+
+// machine interrupt pending
+pub const CSR_MIP: usize = 0x344;
+
+// mstatus interrupt enables, their values before the trap and the privilege it came from
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 3 << 11;
+
+// the mstatus bits sstatus shows
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP;
+
+// machine external interrupt pending
+pub const MIP_MEIP: u64 = 1 << 11;
+
+// supervisor software, timer and external interrupt pending
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_SEIP: u64 = 1 << 9;
+
+// machine software and timer interrupt pending
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_MTIP: u64 = 1 << 7;
+
+// the top bit of mcause and scause marks interrupts
+pub const CAUSE_INTERRUPT: u32 = 1 << 31;
+
+// exception causes
+pub const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const CAUSE_USER_ECALL: u32 = 8;
+pub const CAUSE_SUPERVISOR_ECALL: u32 = 9;
+pub const CAUSE_MACHINE_ECALL: u32 = 11;
+
+// timer, counts retired instructions
+pub const CSR_TIME: usize = 0xc01;
+pub const CSR_TIMEH: usize = 0xc81;